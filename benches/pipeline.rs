@@ -0,0 +1,182 @@
+// Drives a real runtime with note on/off messages, the way the MIDI input callback does,
+// and compares it with the pipeline it replaced.
+// Run with: cargo bench --bench pipeline --no-default-features --features chamsys
+//
+// mpsc        a Vec per message through an mpsc channel, format! for the command and to_vec to send it (the old design)
+// simulation  the runtime in simulation mode, so only the path up to working out the command is measured
+// network     the runtime sending to a desk socket bound on localhost, so encoding and sending commands is measured too
+//
+// Every pipeline sends its commands over UDP on localhost apart from simulation.
+// The runtimes are warmed up first, so the event list and desk state have grown to what they need.
+// Allocations are then counted across the whole process while the measured messages are handled,
+// and the bench fails if either runtime made any.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use midilx::midi_utils::MidiMessage;
+use midilx::stats::{format_latency, LatencyHistogram};
+use midilx::{DeskTarget, MidiRuntime, OutputMode};
+
+/// Messages sent before counting, to let the runtime settle
+const WARM_UP_COUNT: u64 = 10_000;
+
+/// Messages counted
+const MESSAGE_COUNT: u64 = 100_000;
+
+/// How long the producer waits between messages, so latency is measured without the queue backing up
+const MESSAGE_INTERVAL: Duration = Duration::from_micros(20);
+
+/// How long the event loop gets to handle what's left on the queue before counting stops
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// How long the desk monitor needs to report the desk as Unknown, after which its health stops changing.
+/// A change works out the active desks again, which allocates, so it has to happen before counting.
+const DESK_SETTLE_TIME: Duration = Duration::from_millis(2500);
+
+/// The port MagicQ listens on, where the runtime sends its commands
+const CHAMSYS_PORT: u16 = 6553;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+struct PipelineResult {
+    elapsed: Duration,
+    allocations: u64,
+    latency: LatencyHistogram,
+}
+
+fn main() {
+    // Nothing reads the packets, they only need somewhere to go.
+    // The runtime always sends to the MagicQ port, so the desk has to be bound there.
+    let desk = UdpSocket::bind((Ipv4Addr::LOCALHOST, CHAMSYS_PORT)).expect("failed to bind the desk socket, is MagicQ running?");
+    let target = desk.local_addr().expect("desk socket has no address");
+    let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).expect("failed to bind the sending socket");
+
+    println!("{} messages after {} to warm up, one every {:?}\n", MESSAGE_COUNT, WARM_UP_COUNT, MESSAGE_INTERVAL);
+
+    let results = [
+        ("mpsc", run_mpsc(&sender, target)),
+        ("simulation", run_runtime(Vec::new(), Ipv4Addr::UNSPECIFIED, OutputMode::Simulation)),
+        ("network", run_runtime(vec![DeskTarget::new(Ipv4Addr::LOCALHOST)], Ipv4Addr::LOCALHOST, OutputMode::Network)),
+    ];
+
+    println!("{:<12} {:>10} {:>12} {:>12} {:>12} {:>12}", "", "total", "allocs/msg", "mean", "p99", "max");
+
+    for (name, result) in &results {
+        println!(
+            "{:<12} {:>10} {:>12.2} {:>12} {:>12} {:>12}",
+            name,
+            format!("{:.0} ms", result.elapsed.as_secs_f64() * 1000.0),
+            result.allocations as f64 / MESSAGE_COUNT as f64,
+            format_latency(result.latency.mean()),
+            format_latency(result.latency.percentile(0.99)),
+            format_latency(result.latency.max()),
+        );
+    }
+
+    for (name, result) in &results[1..] {
+        assert_eq!(result.allocations, 0, "{} handled {} messages and allocated {} times", name, MESSAGE_COUNT, result.allocations);
+    }
+}
+
+// Alternates note on and note off over a couple of octaves
+fn message(index: u64) -> [u8; 3] {
+    let note = 48 + (index / 2 % 24) as u8;
+
+    if index.is_multiple_of(2) { [0x90, note, 100] } else { [0x80, note, 0] }
+}
+
+fn produce<F: FnMut(&[u8])>(messages: std::ops::Range<u64>, mut send: F) {
+    for index in messages {
+        send(&message(index));
+
+        let sent = Instant::now();
+        while sent.elapsed() < MESSAGE_INTERVAL {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+fn run_mpsc(socket: &UdpSocket, target: SocketAddr) -> PipelineResult {
+    let (tx, rx) = mpsc::channel::<(Vec<u8>, Instant)>();
+    let mut latency = LatencyHistogram::new();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let producer = std::thread::spawn(move || {
+        produce(0..MESSAGE_COUNT, |message| {
+            let _ = tx.send((message.to_vec(), Instant::now()));
+        });
+    });
+
+    for (message, received) in rx {
+        let command = match MidiMessage::parse(&message) {
+            Ok(MidiMessage::NoteOn { note, .. }) => format!("{}A", note - 47),
+            Ok(MidiMessage::NoteOff { note, .. }) => format!("{}R", note - 47),
+            _ => continue,
+        };
+
+        // Copied like send_magicq_command used to
+        #[allow(clippy::unnecessary_to_owned)]
+        let _ = socket.send_to(&command.as_bytes().to_vec(), target);
+        latency.record(received.elapsed());
+    }
+
+    let _ = producer.join();
+
+    PipelineResult {
+        elapsed: start.elapsed(),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        latency,
+    }
+}
+
+fn run_runtime(desk_targets: Vec<DeskTarget>, app_ip: Ipv4Addr, output_mode: OutputMode) -> PipelineResult {
+    let network = output_mode == OutputMode::Network;
+    let runtime = MidiRuntime::create_without_input(desk_targets, app_ip, output_mode).expect("failed to start the runtime");
+
+    produce(0..WARM_UP_COUNT, |message| runtime.send_midi(message));
+    std::thread::sleep(if network { DESK_SETTLE_TIME } else { SETTLE_TIME });
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    produce(WARM_UP_COUNT..WARM_UP_COUNT + MESSAGE_COUNT, |message| runtime.send_midi(message));
+    std::thread::sleep(SETTLE_TIME);
+
+    let elapsed = start.elapsed() - SETTLE_TIME;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    // Asking for the stats allocates, so it's only done once counting has stopped
+    let stats = runtime.stats().expect("the runtime stopped");
+    runtime.stop();
+
+    assert_eq!(stats.messages_in, WARM_UP_COUNT + MESSAGE_COUNT, "the runtime didn't handle every message in time");
+
+    // The runtime's latency includes the warm-up messages
+    PipelineResult { elapsed, allocations, latency: stats.total_latency }
+}
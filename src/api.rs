@@ -0,0 +1,353 @@
+// Local HTTP control API for a running MidiRuntime,
+// so something else on the production network (e.g. a tablet) can change mappings and watch what's happening.
+// Requests are handled one at a time on the server thread, each WebSocket gets its own thread.
+//
+// GET  /api/state      what the desk should be doing: playbacks, recent commands
+// PUT  /api/mappings   {"C3": "activate", "61": "intensity"}, notes as names or numbers, replaces the active bank's mappings
+// PUT  /api/desk-ip    {"ip": "2.0.0.35"}, sends everything to a single desk
+// PUT  /api/desks      [{"address": "2.0.0.35", "backup": false, "enabled": true}]
+// POST /api/bank       {"bank": "Song 2"}, switches to the bank with that name
+// POST /api/song       {"song": "Opener"}, starts the song with that name
+// POST /api/song/next  starts the next song in the setlist
+// POST /api/song/previous
+// POST /api/blackout   releases every active playback
+// POST /api/stop       stops the runtime
+// GET  /api/stats      message and command counts, latencies in microseconds
+// GET  /api/events     WebSocket, every runtime event as JSON, in RuntimeEvent's serde format, e.g.
+//                      {"event": "desk_unreachable", "data": ["2.0.0.35", "..."]}
+//                      {"event": "command_sent", "data": "3A"}
+//                      stats are sent every few seconds as {"event": "stats", "data": {...}}
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use crate::desk_state::DeskState;
+use crate::errors::ProgramError;
+use crate::midi_utils::{parse_note, MiddleC};
+use crate::stats::{LatencyHistogram, RuntimeStats};
+use crate::{return_err, DeskRole, DeskTarget, LxCommand, RuntimeHandle};
+
+/// Request bodies bigger than this are refused
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+/// The control API server. Stops when dropped.
+pub struct ControlApi {
+    server: Arc<Server>,
+    address: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlApi {
+    /// Starts serving on the address (e.g. 127.0.0.1:8080, or 0.0.0.0:8080 for the whole network).
+    /// Note names in mappings are read with middle_c, and on_stop is called when a stop is requested.
+    pub fn start<S>(address: SocketAddr, runtime: RuntimeHandle, middle_c: MiddleC, on_stop: S) -> Result<ControlApi, ProgramError>
+    where
+        S: Fn() + Send + 'static,
+    {
+        let server = match Server::http(address) {
+            Ok(server) => Arc::new(server),
+            Err(e) => return_err!(NetworkBind, format!("failed to start the control API on {address}: {e}"), e),
+        };
+
+        // The port the server got, if it was asked for any (port 0)
+        let address = server.server_addr().to_ip().unwrap_or(address);
+        info!("Control API listening on http://{}", address);
+
+        let thread_server = Arc::clone(&server);
+        let thread = std::thread::spawn(move || {
+            // Ends when the server is unblocked on drop
+            for request in thread_server.incoming_requests() {
+                handle_request(request, &runtime, middle_c, &on_stop);
+            }
+        });
+
+        Ok(ControlApi {
+            server,
+            address,
+            thread: Some(thread),
+        })
+    }
+
+    /// The address the API is being served on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for ControlApi {
+    fn drop(&mut self) {
+        self.server.unblock();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle_request<S: Fn()>(mut request: Request, runtime: &RuntimeHandle, middle_c: MiddleC, on_stop: &S) {
+    debug!("Control API: {} {}", request.method(), request.url());
+
+    if request.url() == "/api/events" && *request.method() == Method::Get {
+        accept_event_stream(request, runtime);
+        return
+    }
+
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().take(MAX_BODY_SIZE).read_to_string(&mut body) {
+        respond(request, 400, Some(json!({ "error": format!("failed to read the request body: {e}") })));
+        return
+    }
+
+    let result = match (request.method(), request.url()) {
+        (Method::Get, "/api/state") => match runtime.desk_state() {
+            Some(desk_state) => Ok(Some(desk_state_json(&desk_state))),
+            None => Err((503, String::from("the runtime has stopped"))),
+        },
+
+        (Method::Get, "/api/stats") => match runtime.stats() {
+            Some(stats) => Ok(Some(stats_json(&stats))),
+            None => Err((503, String::from("the runtime has stopped"))),
+        },
+
+        (Method::Put, "/api/mappings") => parse_mappings(&body, middle_c).map(|mappings| {
+            runtime.update_mappings(mappings);
+            None
+        }),
+
+        (Method::Put, "/api/desk-ip") => parse_desk_ip(&body).map(|ip| {
+            runtime.set_desk_ip(ip);
+            None
+        }),
+
+        (Method::Put, "/api/desks") => parse_desk_targets(&body).map(|desk_targets| {
+            runtime.set_desk_targets(desk_targets);
+            None
+        }),
+
+        (Method::Post, "/api/bank") => parse_bank_name(&body).map(|name| {
+            runtime.select_bank(&name);
+            None
+        }),
+
+        (Method::Post, "/api/song") => parse_song_name(&body).map(|name| {
+            runtime.select_song(&name);
+            None
+        }),
+
+        (Method::Post, "/api/song/next") => {
+            runtime.next_song();
+            Ok(None)
+        }
+
+        (Method::Post, "/api/song/previous") => {
+            runtime.previous_song();
+            Ok(None)
+        }
+
+        (Method::Post, "/api/blackout") => {
+            runtime.blackout();
+            Ok(None)
+        }
+
+        (Method::Post, "/api/stop") => {
+            warn!("Stop requested through the control API");
+            on_stop();
+            Ok(None)
+        }
+
+        _ => Err((404, format!("no endpoint for {} {}", request.method(), request.url()))),
+    };
+
+    match result {
+        Ok(Some(value)) => respond(request, 200, Some(value)),
+        Ok(None) => respond(request, 204, None),
+        Err((status, message)) => respond(request, status, Some(json!({ "error": message }))),
+    }
+}
+
+fn respond(request: Request, status: u16, body: Option<Value>) {
+    let response = match body {
+        Some(body) => Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(header("Content-Type", "application/json")),
+        None => Response::new(status.into(), Vec::new(), Cursor::new(Vec::new()), Some(0), None),
+    };
+
+    if let Err(e) = request.respond(response) {
+        warn!("Control API failed to respond: {}", e);
+    }
+}
+
+// Upgrades the request to a WebSocket and sends it every runtime event until either side goes away
+fn accept_event_stream(request: Request, runtime: &RuntimeHandle) {
+    let key = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+        .map(|header| derive_accept_key(header.value.as_bytes()));
+
+    let Some(accept_key) = key else {
+        respond(request, 400, Some(json!({ "error": "/api/events is a WebSocket" })));
+        return
+    };
+
+    let response = Response::empty(101).with_header(header("Sec-WebSocket-Accept", &accept_key));
+    let stream = request.upgrade("websocket", response);
+    let events = runtime.subscribe();
+
+    std::thread::spawn(move || {
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+        debug!("Control API event stream opened");
+
+        // The subscription ends when the runtime stops
+        for event in events {
+            let text = match serde_json::to_string(&event) {
+                Ok(text) => text,
+                Err(e) => {
+                    warn!("Control API failed to serialize {:?}: {}", event, e);
+                    continue
+                }
+            };
+
+            if socket.send(Message::text(text)).is_err() {
+                break
+            }
+        }
+
+        let _ = socket.close(None);
+        debug!("Control API event stream closed");
+    });
+}
+
+fn header(field: &str, value: &str) -> Header {
+    // Only called with fixed field names and ASCII values
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn parse_json(body: &str) -> Result<Value, (u16, String)> {
+    serde_json::from_str(body).map_err(|e| (400, format!("invalid JSON: {e}")))
+}
+
+fn parse_mappings(body: &str, middle_c: MiddleC) -> Result<HashMap<usize, LxCommand>, (u16, String)> {
+    let Value::Object(entries) = parse_json(body)? else {
+        return Err((400, String::from("mappings should be an object of note to command")))
+    };
+
+    let mut mappings = HashMap::new();
+
+    for (note, command) in entries {
+        let note = parse_note(&note, middle_c).map_err(|e| (400, e.to_string()))?;
+
+        let command = match command.as_str() {
+            Some(command) => LxCommand::parse(command).map_err(|e| (400, e.to_string()))?,
+            None => return Err((400, format!("{command} is not a command, should be \"activate\", \"deactivate\" or \"intensity\""))),
+        };
+
+        mappings.insert(note as usize, command);
+    }
+
+    Ok(mappings)
+}
+
+fn parse_desk_ip(body: &str) -> Result<Ipv4Addr, (u16, String)> {
+    match parse_json(body)?.get("ip") {
+        Some(Value::String(ip)) => ip.parse().map_err(|_| (400, format!("'{ip}' is not a valid desk IP"))),
+        _ => Err((400, String::from("expected {\"ip\": \"<desk IP>\"}"))),
+    }
+}
+
+fn parse_bank_name(body: &str) -> Result<String, (u16, String)> {
+    match parse_json(body)?.get("bank") {
+        Some(Value::String(name)) => Ok(name.to_owned()),
+        _ => Err((400, String::from("expected {\"bank\": \"<bank name>\"}"))),
+    }
+}
+
+fn parse_song_name(body: &str) -> Result<String, (u16, String)> {
+    match parse_json(body)?.get("song") {
+        Some(Value::String(name)) => Ok(name.to_owned()),
+        _ => Err((400, String::from("expected {\"song\": \"<song name>\"}"))),
+    }
+}
+
+fn parse_desk_targets(body: &str) -> Result<Vec<DeskTarget>, (u16, String)> {
+    let Value::Array(desks) = parse_json(body)? else {
+        return Err((400, String::from("desks should be a list")))
+    };
+
+    let mut desk_targets = Vec::new();
+
+    for desk in desks {
+        let mut target = match desk.get("address").and_then(Value::as_str).map(str::parse::<Ipv4Addr>) {
+            Some(Ok(address)) => DeskTarget::new(address),
+            _ => return Err((400, String::from("every desk needs a valid \"address\""))),
+        };
+
+        if desk.get("backup").and_then(Value::as_bool).unwrap_or(false) {
+            target.role = DeskRole::Backup;
+        }
+
+        target.enabled = desk.get("enabled").and_then(Value::as_bool).unwrap_or(true);
+        desk_targets.push(target);
+    }
+
+    Ok(desk_targets)
+}
+
+fn desk_state_json(desk_state: &DeskState) -> Value {
+    let playbacks: Vec<Value> = desk_state
+        .playbacks()
+        .map(|(playback, state)| json!({ "playback": playback, "active": state.active, "level": state.level }))
+        .collect();
+
+    json!({
+        "playbacks": playbacks,
+        "recent_commands": desk_state.recent_commands().map(|command| command.to_string()).collect::<Vec<_>>(),
+        "total_commands": desk_state.total_commands(),
+    })
+}
+
+fn stats_json(stats: &RuntimeStats) -> Value {
+    json!({
+        "running_for_secs": stats.running_for.as_secs_f64(),
+        "messages_in": stats.messages_in,
+        "messages_ignored": stats.messages_ignored,
+        "messages_dropped": stats.messages_dropped,
+        "commands_out": stats.commands_out,
+        "send_errors": stats.send_errors,
+        "latency": {
+            "queue": histogram_json(&stats.queue_latency),
+            "translate": histogram_json(&stats.translate_latency),
+            "send": histogram_json(&stats.send_latency),
+            "total": histogram_json(&stats.total_latency),
+        },
+    })
+}
+
+// Latencies in microseconds, buckets are {"le": <upper bound or null for the last one>, "count": n}
+fn histogram_json(histogram: &LatencyHistogram) -> Value {
+    let micros = |latency: Option<Duration>| latency.map(|latency| latency.as_micros() as u64);
+
+    let buckets: Vec<Value> = histogram
+        .buckets()
+        .map(|(bound, count)| json!({ "le": micros(bound), "count": count }))
+        .collect();
+
+    json!({
+        "count": histogram.count(),
+        "mean_us": micros(histogram.mean()),
+        "p50_us": micros(histogram.percentile(0.5)),
+        "p99_us": micros(histogram.percentile(0.99)),
+        "max_us": micros(histogram.max()),
+        "buckets": buckets,
+    })
+}
@@ -1,196 +1,1142 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
-use color_print::{ceprintln, cprintln};
+use color_print::cprintln;
+use log::{debug, error, info, log_enabled, trace, warn, Level};
 use crate::errors::ProgramError;
-use crate::midi_utils::{is_off_status, is_on_status};
-use crate::{return_err, LxCommand, MidiRuntime};
-use std::sync::mpsc;
-use midir::{MidiInput, MidiInputPort, MidiOutputConnection};
+use crate::magicq::{EncodedCommand, MagicqCommand};
+use crate::midi_queue::{MidiQueue, MIDI_QUEUE_CAPACITY};
+use crate::midi_utils::{MidiMessage, MidiParser};
+use crate::{return_err, BankSwitching, DeskRole, DeskTarget, LxCommand, MappingBank, MidiRuntime, OutputMode, RuntimeEvent, Setlist, Song, DEFAULT_BANK_NAME};
+use std::sync::{mpsc, Arc};
+#[cfg(feature = "midir-io")]
+use midir::{MidiInput, MidiOutputConnection};
+#[cfg(feature = "midir-io")]
+use crate::midi_io::{connect_midi_input, midi_input_source_name};
+#[cfg(feature = "midir-io")]
+use crate::supervisor::{handler_callback, MidiHandler, PortSupervisor};
+#[cfg(feature = "midir-io")]
+use crate::recorder::RecorderHandle;
+#[cfg(feature = "midir-io")]
+use crate::{MidiInputSource, RuntimeHandle};
+use crate::desk_state::DeskState;
+use crate::desk_monitor::{DeskHealth, DeskMonitor};
+use std::time::{Duration, Instant};
+use crate::stats::RuntimeStats;
 
 /// Default port MagicQ listens on for remote control UDP
 const CHAMSYS_PORT: u16 = 6553;
 
+/// How often subscribers are sent the stats
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// MIDI note number for the note that controls PB1 on the desk, unless the show file says otherwise (C3)
+pub const DEFAULT_FIRST_PLAYBACK_NOTE: u8 = 48;
+
 pub struct AppState {
-    desk_ip: Ipv4Addr,
+    // Every desk commands can go to, with reachability checks when sending over the network
+    desks: Vec<Desk>,
 
     // Should not change while running the runtime (hopefully)
     app_ip: Ipv4Addr,
 
-    mappings: HashMap<usize, LxCommand>,
+    // Always at least one bank
+    banks: Vec<MappingBank>,
+    active_bank: usize,
+    bank_switching: BankSwitching,
+
+    // Playbacks activated since switching to the active bank, to release when switching away
+    bank_playbacks: BTreeSet<u8>,
+
+    // None until the first song is started
+    setlist: Setlist,
+    active_song: Option<usize>,
+
     previous_playback: u8,
+
+    // Messages fed in with send_midi can use running status
+    midi_parser: MidiParser,
+
+    output_mode: OutputMode,
+
+    // What the desk should be doing after every command sent so far
+    desk_state: DeskState,
+
+    stats: RuntimeStats,
+    started: Instant,
+}
+
+struct Desk {
+    target: DeskTarget,
+    monitor: Option<DeskMonitor>,
+}
+
+impl Desk {
+    fn is_down(&self) -> bool {
+        self.monitor.as_ref().is_some_and(DeskMonitor::is_down)
+    }
 }
 
 impl AppState {
-    pub fn new(desk_ip: Ipv4Addr, app_ip: Ipv4Addr) -> Self {
-        Self {
-            desk_ip,
+    pub fn new(desk_targets: Vec<DeskTarget>, app_ip: Ipv4Addr, output_mode: OutputMode) -> Self {
+        let mut state = Self {
+            desks: Vec::new(),
             app_ip,
-            mappings: HashMap::new(),
+            banks: vec![MappingBank::new(DEFAULT_BANK_NAME)],
+            active_bank: 0,
+            bank_switching: BankSwitching::default(),
+            bank_playbacks: BTreeSet::new(),
+            setlist: Setlist::default(),
+            active_song: None,
             previous_playback: 0,
+            midi_parser: MidiParser::new(),
+            output_mode,
+            desk_state: DeskState::new(),
+            stats: RuntimeStats::new(),
+            started: Instant::now(),
+        };
+
+        state.set_desk_targets(desk_targets);
+        state
+    }
+
+    /// Replaces the banks and starts from the first one. A default bank is used if there are none.
+    pub fn set_banks(&mut self, banks: Vec<MappingBank>, switching: BankSwitching) {
+        self.banks = if banks.is_empty() { vec![MappingBank::new(DEFAULT_BANK_NAME)] } else { banks };
+        self.active_bank = 0;
+        self.bank_switching = switching;
+        self.bank_playbacks.clear();
+    }
+
+    pub fn active_bank(&self) -> &MappingBank {
+        &self.banks[self.active_bank]
+    }
+
+    /// The bank this message switches to, if it's one of the bank switching messages
+    pub fn bank_switch_for(&self, message: &MidiMessage) -> Option<usize> {
+        let switching = &self.bank_switching;
+
+        if let Some(channel) = switching.channel
+            && message.channel() != Some(channel)
+        {
+            return None
+        }
+
+        let last_bank = self.banks.len() - 1;
+
+        match message {
+            MidiMessage::ProgramChange { program, .. } => self.banks.iter().position(|bank| bank.program == Some(*program)),
+            MidiMessage::NoteOn { note, .. } if switching.next_note == Some(*note) => Some((self.active_bank + 1).min(last_bank)),
+            MidiMessage::NoteOn { note, .. } if switching.previous_note == Some(*note) => Some(self.active_bank.saturating_sub(1)),
+            MidiMessage::ControlChange { controller, value, .. } if switching.select_cc == Some(*controller) => Some((*value as usize).min(last_bank)),
+
+            // The note off for a switching note shouldn't release a playback either, so it switches to the same bank
+            MidiMessage::NoteOff { note, .. } if switching.next_note == Some(*note) || switching.previous_note == Some(*note) => Some(self.active_bank),
+
+            _ => None,
+        }
+    }
+
+    pub fn bank_index(&self, name: &str) -> Option<usize> {
+        self.banks.iter().position(|bank| bank.name == name)
+    }
+
+    /// Switches bank, returning the commands to release the old bank's playbacks if that's turned on
+    pub fn switch_bank(&mut self, bank: usize) -> Vec<MagicqCommand> {
+        if bank == self.active_bank || bank >= self.banks.len() {
+            return Vec::new()
+        }
+
+        self.active_bank = bank;
+        let old_playbacks = std::mem::take(&mut self.bank_playbacks);
+
+        if !self.bank_switching.release_on_switch {
+            return Vec::new()
+        }
+
+        old_playbacks
+            .into_iter()
+            .filter(|playback| self.desk_state.playback(*playback).is_some_and(|state| state.active))
+            .map(MagicqCommand::Release)
+            .collect()
+    }
+
+    /// Counters and latencies since the state was created
+    pub fn stats(&self) -> RuntimeStats {
+        RuntimeStats {
+            running_for: self.started.elapsed(),
+            ..self.stats.clone()
         }
     }
+
+    /// Replaces the setlist, staying on the current song if there's still a song with its name
+    pub fn set_setlist(&mut self, setlist: Setlist) {
+        let current_name = self.active_song.map(|song| self.setlist.songs[song].name.clone());
+        self.active_song = current_name.and_then(|name| setlist.songs.iter().position(|song| song.name == name));
+        self.setlist = setlist;
+    }
+
+    /// How far through the setlist this message steps (1 for next, -1 for previous), if it's a setlist message.
+    /// Releasing the next or previous control is 0, so it isn't translated into a command either.
+    pub fn song_step_for(&self, message: &MidiMessage) -> Option<isize> {
+        let setlist = &self.setlist;
+
+        if let Some(channel) = setlist.channel
+            && message.channel() != Some(channel)
+        {
+            return None
+        }
+
+        match message {
+            MidiMessage::NoteOn { note, .. } if setlist.next_note == Some(*note) => Some(1),
+            MidiMessage::NoteOn { note, .. } if setlist.previous_note == Some(*note) => Some(-1),
+            MidiMessage::NoteOff { note, .. } if setlist.next_note == Some(*note) || setlist.previous_note == Some(*note) => Some(0),
+            MidiMessage::ControlChange { controller, value, .. } if setlist.next_cc == Some(*controller) => Some(if *value >= 64 { 1 } else { 0 }),
+            _ => None,
+        }
+    }
+
+    /// The song a step from the current song lands on, stopping at either end of the setlist.
+    /// None if it doesn't move.
+    pub fn song_after_step(&self, step: isize) -> Option<usize> {
+        let last_song = self.setlist.songs.len().checked_sub(1)?;
+
+        let song = match self.active_song {
+            Some(current) => current.saturating_add_signed(step).min(last_song),
+            // Before the first song, only going forwards starts it
+            None if step > 0 => (step as usize - 1).min(last_song),
+            None => return None,
+        };
+
+        (self.active_song != Some(song)).then_some(song)
+    }
+
+    /// Makes this the current song, without switching bank or sending anything
+    pub fn enter_song(&mut self, song: usize) -> &Song {
+        self.active_song = Some(song);
+        &self.setlist.songs[song]
+    }
+
+    pub fn song_index(&self, name: &str) -> Option<usize> {
+        self.setlist.songs.iter().position(|song| song.name == name)
+    }
+
+    fn set_desk_targets(&mut self, desk_targets: Vec<DeskTarget>) {
+        self.desks = desk_targets
+            .into_iter()
+            .map(|target| Desk {
+                // Reachability is only checked when actually sending to the desk
+                monitor: match self.output_mode {
+                    OutputMode::Network => Some(DeskMonitor::new(self.app_ip, target.address, CHAMSYS_PORT)),
+                    OutputMode::Simulation => None,
+                },
+                target,
+            })
+            .collect();
+    }
+
+    /// The desks commands are currently sent to:
+    /// every main desk that can be reached, or the first backup that can be reached if none of them can.
+    /// If nothing can be reached, commands still go to the main desks in case only the checks are failing.
+    fn active_desks(&self) -> Vec<Ipv4Addr> {
+        let enabled = || self.desks.iter().filter(|desk| desk.target.enabled);
+        let main_desks = || enabled().filter(|desk| desk.target.role == DeskRole::Main);
+
+        let reachable: Vec<Ipv4Addr> = main_desks()
+            .filter(|desk| !desk.is_down())
+            .map(|desk| desk.target.address)
+            .collect();
+
+        if !reachable.is_empty() {
+            return reachable
+        }
+
+        match enabled().find(|desk| desk.target.role == DeskRole::Backup && !desk.is_down()) {
+            Some(backup) => vec![backup.target.address],
+            None => main_desks().map(|desk| desk.target.address).collect(),
+        }
+    }
+
+    // How long until a desk is due to be checked, if any are being checked
+    fn time_until_check(&self) -> Option<Duration> {
+        self.desks
+            .iter()
+            .filter_map(|desk| desk.monitor.as_ref())
+            .map(DeskMonitor::time_until_check)
+            .min()
+    }
 }
 
 pub enum AppEvent {
-    Midi(Vec<u8>),
+    // With when it arrived, to time how long it takes to get to the desk
+    Midi(Vec<u8>, Instant),
     UpdateMappings(HashMap<usize, LxCommand>),
-    SetDeskIp(Ipv4Addr),
+    SetBanks(Vec<MappingBank>, BankSwitching),
+    SelectBank(String),
+    SetSetlist(Setlist),
+    StepSong(isize),
+    SelectSong(String),
+    SetDeskTargets(Vec<DeskTarget>),
+    SetDeskTargetEnabled(Ipv4Addr, bool),
+    GetDeskState(mpsc::Sender<DeskState>),
+    GetStats(mpsc::Sender<RuntimeStats>),
+    Blackout,
+
+    // Front ends listening for runtime events
+    Subscribe(mpsc::Sender<RuntimeEvent>),
+
+    // Something happened that subscribers should hear about, e.g. the input port was unplugged
+    #[cfg(feature = "midir-io")]
+    Notify(RuntimeEvent),
+
     Stop,
 }
 
-pub fn start_midi_to_chamsys_runtime(state: AppState, midi_input: MidiInput, selected_midi_port: MidiInputPort, midi_through: Option<MidiOutputConnection>) -> Result<MidiRuntime, ProgramError> {
+#[cfg(feature = "midir-io")]
+pub fn start_midi_to_chamsys_runtime(state: AppState, midi_input: MidiInput, input_source: MidiInputSource, midi_through: Option<MidiOutputConnection>, recorder: Option<RecorderHandle>) -> Result<MidiRuntime, ProgramError> {
     cprintln!("\n<green>RUNNING CHAMSYS MIDI CONTROL</>");
     let (tx, rx) = mpsc::channel::<AppEvent>();
+    let queue = Arc::new(MidiQueue::new());
 
     // MIDI prep
-    let tx_midi = tx.clone();
-
-    println!("Local IP for sending: {}", state.app_ip);
+    let midi_handle = RuntimeHandle {
+        tx: tx.clone(),
+        queue: Arc::clone(&queue),
+    };
 
-    // Try pinging the desk
-    if let Ok(_) = std::process::Command::new("ping")
-        .arg(state.desk_ip.to_string())
-        .output() {
-        println!("Ping to 2.0.0.35 succeeded");
+    if state.output_mode == OutputMode::Simulation {
+        cprintln!("<yellow>SIMULATION MODE - nothing will be sent to the desk</>");
     } else {
-        println!("Ping failed — network config may be wrong");
+        info!("Local IP for sending: {}", state.app_ip);
     }
 
+    let socket = open_output_socket(&state)?;
+
+    let port_name = midi_input_source_name(&midi_input, &input_source);
+    let recorder_port_name = port_name.clone();
+
     // MIDI INPUTS MESSAGE PASSING
-    let _conn_in = match midi_input.connect(
-        &selected_midi_port,
-        "midir-read-input",
-        move |_stamp, message, _| {
-            let _ = tx_midi.send(AppEvent::Midi(message.to_vec()));
-        },
-        (),
-    ) {
-        Ok(connection) => connection,
-        Err(e) => return_err!(&format!("failed to connect: {}", e))
+    // Shared so the same handler carries on if the port has to be reconnected
+    let mut handler = MidiHandler::new(move |stamp: u64, message: &[u8]| {
+        // The desk is waiting on this, so it goes first
+        midi_handle.send_midi(message);
+
+        if let Some(recorder) = &recorder {
+            recorder.record(stamp, &recorder_port_name, message);
+        }
+    });
+
+    // Everything received is passed straight through, untranslated
+    if let Some(mut midi_through) = midi_through {
+        handler = handler.with_exclusive(move |_, message| {
+            let _ = midi_through.send(message);
+        });
+    }
+
+    // Virtual ports belong to this program so they can't be unplugged, only hardware ports are watched
+    let is_virtual = matches!(input_source, MidiInputSource::Virtual(_));
+    let midi_connection = connect_midi_input(midi_input, input_source, handler_callback(&handler))?;
+
+    let (midi_connection, port_supervisor) = if is_virtual {
+        (Some(midi_connection), None)
+    } else {
+        let status_handle = RuntimeHandle {
+            tx: tx.clone(),
+            queue: Arc::clone(&queue),
+        };
+
+        let supervisor = PortSupervisor::start(port_name, midi_connection, handler, move |event| {
+            status_handle.send(AppEvent::Notify(event));
+        });
+
+        (None, Some(supervisor))
     };
 
+    let output_mode = state.output_mode;
+    let loop_queue = Arc::clone(&queue);
+
     // Spawn the event loop
     std::thread::spawn(move || {
-        run_event_loop(state, rx);
+        run_event_loop(state, socket, loop_queue, rx);
+    });
+
+    Ok(MidiRuntime {
+        tx,
+        queue,
+        output_mode,
+        _midi_connection: midi_connection,
+        _port_supervisor: port_supervisor,
+    })
+}
+
+pub fn start_chamsys_runtime_without_input(state: AppState) -> Result<MidiRuntime, ProgramError> {
+    cprintln!("\n<green>RUNNING CHAMSYS MIDI CONTROL</>");
+    let (tx, rx) = mpsc::channel::<AppEvent>();
+    let queue = Arc::new(MidiQueue::new());
+
+    if state.output_mode == OutputMode::Simulation {
+        cprintln!("<yellow>SIMULATION MODE - nothing will be sent to the desk</>");
+    } else {
+        info!("Local IP for sending: {}", state.app_ip);
+    }
+
+    let socket = open_output_socket(&state)?;
+    let output_mode = state.output_mode;
+    let loop_queue = Arc::clone(&queue);
+
+    std::thread::spawn(move || {
+        run_event_loop(state, socket, loop_queue, rx);
     });
 
-    Ok(MidiRuntime { tx })
+    Ok(MidiRuntime {
+        tx,
+        queue,
+        output_mode,
+        #[cfg(feature = "midir-io")]
+        _midi_connection: None,
+        #[cfg(feature = "midir-io")]
+        _port_supervisor: None,
+    })
 }
 
+// Opened before the event loop starts, so a runtime that couldn't send anything is never started.
+// No socket is needed in simulation mode, so it works without being on the desk's network.
+fn open_output_socket(state: &AppState) -> Result<Option<UdpSocket>, ProgramError> {
+    match state.output_mode {
+        OutputMode::Network => Ok(Some(open_command_socket(state.app_ip)?)),
+        OutputMode::Simulation => Ok(None),
+    }
+}
+
+// Sleeps until something is queued or sent, or a desk check or the stats are due.
+// MIDI from the queue is handled before any other events, it's what the desk is waiting on.
 fn run_event_loop(
     mut state: AppState,
+    socket: Option<UdpSocket>,
+    queue: Arc<MidiQueue>,
     rx: mpsc::Receiver<AppEvent>,
 ) {
-    let socket = match UdpSocket::bind((state.app_ip, 0)) {
-        Ok(s) => s,
-        Err(e) => {
-            ceprintln!("<red>Failed to Bind Socket: {}</>", e);
-            return
-        }
-    };
+    queue.set_event_loop();
+
+    let mut subscribers: Vec<mpsc::Sender<RuntimeEvent>> = Vec::new();
+    let mut active_desks = state.active_desks();
+    info!("Sending commands to: {}", format_desks(&active_desks));
+    let mut next_stats = Instant::now() + STATS_INTERVAL;
+
+    // Events for subscribers, kept between messages so handling one doesn't allocate.
+    // A note sends one command, so a full queue of them fits without growing it.
+    let mut events: Vec<RuntimeEvent> = Vec::with_capacity(MIDI_QUEUE_CAPACITY);
+
+    // The active desks are only worked out again when they could have changed
+    let mut desks_changed = false;
 
     loop {
-        match rx.recv() {
-            Ok(AppEvent::Midi(message)) => {
-                if let Ok(Some(cmd)) =
-                    translate_midi_to_chamsys_command(
-                        &message,
-                        &mut state,
-                    )
-                {
-                    match send_magicq_command(&socket, &cmd, &state) {
-                        Ok(details) => println!("{}", details),
-                        Err(e) => println!("{}", e)
+        if Instant::now() >= next_stats {
+            next_stats = Instant::now() + STATS_INTERVAL;
+
+            if !subscribers.is_empty() {
+                events.push(RuntimeEvent::Stats(Box::new(state.stats())));
+            }
+        }
+
+        for desk in &mut state.desks {
+            let address = desk.target.address;
+
+            if let Some(monitor) = &mut desk.monitor
+                && let Some(health) = monitor.check_if_due()
+            {
+                desks_changed = true;
+
+                events.push(match health {
+                    DeskHealth::Up => {
+                        info!("Desk {} is reachable", address);
+                        RuntimeEvent::DeskReachable(address)
+                    }
+                    DeskHealth::Unknown => {
+                        info!("Desk {} isn't answering, commands are still sent but it may not be there", address);
+                        RuntimeEvent::DeskReachabilityUnknown(address)
                     }
+                    DeskHealth::Down(reason) => {
+                        warn!("Desk {} is unreachable: {}", address, reason);
+                        RuntimeEvent::DeskUnreachable(address, reason)
+                    }
+                });
+            }
+        }
+
+        // Fails over to a backup (or back again) as soon as the checks say so
+        if desks_changed {
+            desks_changed = false;
+
+            let new_active_desks = state.active_desks();
+            if new_active_desks != active_desks {
+                info!("Sending commands to: {}", format_desks(&new_active_desks));
+                active_desks = new_active_desks;
+                events.push(RuntimeEvent::ActiveDesksChanged(active_desks.clone()));
+            }
+        }
+
+        let dropped = queue.take_dropped();
+        if dropped > 0 {
+            warn!("Dropped {} MIDI messages, the queue was full", dropped);
+            state.stats.messages_in += dropped;
+            state.stats.messages_dropped += dropped;
+        }
+
+        while let Some(packet) = queue.pop() {
+            handle_midi(&mut state, socket.as_ref(), &active_desks, packet.as_bytes(), packet.received, &mut events);
+        }
+
+        notify(&mut subscribers, &mut events);
+
+        let event = match rx.try_recv() {
+            Ok(event) => event,
+            Err(mpsc::TryRecvError::Empty) => {
+                // Anything queued or sent since the queue was emptied unparks straight away
+                let until_stats = next_stats.saturating_duration_since(Instant::now());
+                let timeout = state.time_until_check().map_or(until_stats, |until_check| until_check.min(until_stats));
+                std::thread::park_timeout(timeout);
+                continue
+            }
+            Err(mpsc::TryRecvError::Disconnected) => break,
+        };
+
+        match event {
+            // Messages too long for the queue
+            AppEvent::Midi(message, received) => {
+                handle_midi(&mut state, socket.as_ref(), &active_desks, &message, received, &mut events);
+            }
+
+            AppEvent::SelectBank(name) => match state.bank_index(&name) {
+                Some(bank) => change_bank(&mut state, socket.as_ref(), &active_desks, bank, &mut events),
+                None => warn!("Can't switch to bank '{}', there's no bank with that name", name),
+            },
+
+            AppEvent::StepSong(step) => match state.song_after_step(step) {
+                Some(song) => start_song(&mut state, socket.as_ref(), &active_desks, song, &mut events),
+                None => debug!("No song to step to"),
+            },
+
+            AppEvent::SelectSong(name) => match state.song_index(&name) {
+                Some(song) => start_song(&mut state, socket.as_ref(), &active_desks, song, &mut events),
+                None => warn!("Can't start song '{}', it isn't in the setlist", name),
+            },
+
+            AppEvent::Blackout => {
+                warn!("Blackout, releasing every active playback");
+
+                for playback in state.desk_state.active_playbacks() {
+                    send_command(&mut state, socket.as_ref(), &active_desks, MagicqCommand::Release(playback), &mut events);
                 }
             }
 
-            Ok(AppEvent::UpdateMappings(new_mappings)) => {
-                state.mappings = new_mappings;
+            AppEvent::GetDeskState(reply) => {
+                let _ = reply.send(state.desk_state.clone());
             }
 
-            Ok(AppEvent::SetDeskIp(ip)) => {
-                state.desk_ip = ip;
+            AppEvent::GetStats(reply) => {
+                let _ = reply.send(state.stats());
             }
 
-            Ok(AppEvent::Stop) | Err(_) => {
-                break;
+            AppEvent::Subscribe(subscriber) => {
+                subscribers.push(subscriber);
             }
+
+            #[cfg(feature = "midir-io")]
+            AppEvent::Notify(event) => {
+                events.push(event);
+            }
+
+            AppEvent::UpdateMappings(new_mappings) => {
+                let bank = state.active_bank;
+                state.banks[bank].mappings = new_mappings;
+            }
+
+            AppEvent::SetBanks(banks, switching) => {
+                state.set_banks(banks, switching);
+                info!("Using bank '{}'", state.active_bank().name);
+            }
+
+            AppEvent::SetSetlist(setlist) => {
+                state.set_setlist(setlist);
+            }
+
+            AppEvent::SetDeskTargets(desk_targets) => {
+                state.set_desk_targets(desk_targets);
+                desks_changed = true;
+            }
+
+            AppEvent::SetDeskTargetEnabled(address, enabled) => {
+                let mut found = false;
+
+                for desk in state.desks.iter_mut().filter(|desk| desk.target.address == address) {
+                    desk.target.enabled = enabled;
+                    found = true;
+                }
+
+                if !found {
+                    warn!("Can't {} desk {}, it isn't a desk target", if enabled { "enable" } else { "disable" }, address);
+                }
+
+                desks_changed = true;
+            }
+
+            AppEvent::Stop => break,
         }
+
+        notify(&mut subscribers, &mut events);
+    }
+}
+
+// Sends the events to every subscriber, dropping subscribers that have gone away
+fn notify(subscribers: &mut Vec<mpsc::Sender<RuntimeEvent>>, events: &mut Vec<RuntimeEvent>) {
+    for event in events.drain(..) {
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
     }
 }
 
-/// Formats a command formatted for Chamsys desks in rx (no header) mode
-pub fn translate_midi_to_chamsys_command(message: &[u8], state: &mut AppState) -> Result<Option<String>, ProgramError> {
-    // MIDI note number for the first note that will control PB1 on the desk
-    let first_playback_note = 48;
+// Switches song or bank if the message is set to, otherwise translates it and sends the command
+fn handle_midi(state: &mut AppState, socket: Option<&UdpSocket>, active_desks: &[Ipv4Addr], message: &[u8], received: Instant, events: &mut Vec<RuntimeEvent>) {
+    let dequeued = Instant::now();
+    state.stats.messages_in += 1;
+    state.stats.queue_latency.record(dequeued.duration_since(received));
 
-    // Get the note value of the message
-    let note = match message.get(1) {
-        Some(n) => *n,
-        None => return_err!("message does not contain a note value")
+    let message = match state.midi_parser.parse(message) {
+        Ok(message) => message,
+        Err(e) => {
+            debug!("Ignoring MIDI input: {}", e);
+            state.stats.messages_dropped += 1;
+            return
+        }
     };
 
-    let status = match message.get(0) {
-        Some(s) => *s,
-        None => return_err!("message does not contain a status byte")
-    };
+    if let Some(step) = state.song_step_for(&message) {
+        if let Some(song) = state.song_after_step(step) {
+            start_song(state, socket, active_desks, song, events);
+        }
+    } else if let Some(bank) = state.bank_switch_for(&message) {
+        change_bank(state, socket, active_desks, bank, events);
+    } else {
+        let cmd = translate_midi_to_chamsys_command(&message, state);
+        state.stats.translate_latency.record(dequeued.elapsed());
 
-    let velocity = match message.get(2) {
-        Some(v) => *v,
-        None => return_err!("message does not contain a velocity value")
-    };
+        match cmd {
+            Some(cmd) => {
+                send_command(state, socket, active_desks, cmd, events);
+                state.stats.total_latency.record(received.elapsed());
+            }
+            None => state.stats.messages_ignored += 1,
+        }
+    }
+}
 
-    // Important Status messages
-    println!("MIDI input: {:?}", message);
+// Sends a command to every active desk, or just traces it in simulation mode.
+// Adds the events for subscribers, including one for every desk the command couldn't be sent to.
+fn send_command(state: &mut AppState, socket: Option<&UdpSocket>, active_desks: &[Ipv4Addr], cmd: MagicqCommand, events: &mut Vec<RuntimeEvent>) {
+    state.desk_state.apply_command(cmd);
 
-    let command_letter: &str;
+    match socket {
+        Some(socket) => {
+            let encoded = cmd.encode();
+            let sending = Instant::now();
 
-    // Note on for all channels
-    if is_on_status(status)  {
-        command_letter = "A";
+            for desk_ip in active_desks {
+                match send_magicq_command(socket, &encoded, *desk_ip) {
+                    Ok(target) => debug!("Command '{}' sent to {}", cmd, target),
+                    Err(e) => {
+                        error!("{}", e);
+                        state.stats.send_errors += 1;
+                        events.push(RuntimeEvent::CommandFailed(*desk_ip, format!("'{}': {}", cmd, e)));
+                    }
+                }
+            }
 
-    // Note off for all channels
-    } else if is_off_status(status) {
-        command_letter = "R";
+            state.stats.send_latency.record(sending.elapsed());
+        }
 
-    // MOD WHEEL (LOL)
-    } else if status == 176 {
-        return Ok(Some(format!("{},{}L", state.previous_playback, velocity)))
-    } else {
-        // If this status isn't set as a command yet
-        println!("Status {} not set as a command", status);
-        return Ok(None);
+        // The description and summary are built just for this line, so only when it's shown
+        None => if log_enabled!(Level::Info) {
+            info!("[SIM] {:<10} {:<24} {}", cmd, cmd.description(), state.desk_state.summary());
+        },
+    }
+
+    state.stats.commands_out += 1;
+    events.push(RuntimeEvent::CommandSent(cmd));
+}
+
+// Switches bank, releasing the old bank's playbacks if that's turned on.
+// Switching to the bank that's already active does nothing.
+fn change_bank(state: &mut AppState, socket: Option<&UdpSocket>, active_desks: &[Ipv4Addr], bank: usize, events: &mut Vec<RuntimeEvent>) {
+    if bank == state.active_bank {
+        return
+    }
+
+    for cmd in state.switch_bank(bank) {
+        send_command(state, socket, active_desks, cmd, events);
+    }
+
+    let name = state.active_bank().name.clone();
+    info!("Switched to bank '{}'", name);
+    events.push(RuntimeEvent::BankChanged(name));
+}
+
+// Starts a song: switches to its bank, then sends its commands
+fn start_song(state: &mut AppState, socket: Option<&UdpSocket>, active_desks: &[Ipv4Addr], song: usize, events: &mut Vec<RuntimeEvent>) {
+    let song_info = state.enter_song(song).clone();
+
+    match &song_info.tempo {
+        Some(tempo) => info!("Song {}/{}: '{}' at {} BPM", song + 1, state.setlist.songs.len(), song_info.name, tempo),
+        None => info!("Song {}/{}: '{}'", song + 1, state.setlist.songs.len(), song_info.name),
     }
 
+    if let Some(bank_name) = &song_info.bank {
+        match state.bank_index(bank_name) {
+            Some(bank) => change_bank(state, socket, active_desks, bank, events),
+            None => warn!("Song '{}' uses bank '{}', but there's no bank with that name", song_info.name, bank_name),
+        }
+    }
+
+    for cmd in &song_info.commands {
+        send_command(state, socket, active_desks, *cmd, events);
+    }
+
+    events.push(RuntimeEvent::SongChanged(song, song_info));
+}
+
+fn open_command_socket(app_ip: Ipv4Addr) -> Result<UdpSocket, ProgramError> {
+    let socket = match UdpSocket::bind((app_ip, 0)) {
+        Ok(s) => s,
+        Err(e) => return_err!(NetworkBind, format!("Failed to Bind Socket: {}", e), e),
+    };
+
+    // Desk targets can be subnet broadcast addresses
+    if let Err(e) = socket.set_broadcast(true) {
+        return_err!(NetworkBind, format!("Failed to enable broadcast on socket: {}", e), e)
+    }
+
+    Ok(socket)
+}
+
+fn format_desks(desks: &[Ipv4Addr]) -> String {
+    if desks.is_empty() {
+        return String::from("no desks")
+    }
+
+    desks.iter().map(Ipv4Addr::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Works out the command for a message, for Chamsys desks in rx (no header) mode
+pub fn translate_midi_to_chamsys_command(message: &MidiMessage, state: &mut AppState) -> Option<MagicqCommand> {
+    trace!("MIDI input: {:?}", message);
+
+    let (note, velocity) = match message {
+        MidiMessage::NoteOn { note, velocity, .. } => (*note, Some(*velocity)),
+        MidiMessage::NoteOff { note, .. } => (*note, None),
+
+        // MOD WHEEL (LOL)
+        MidiMessage::ControlChange { channel: 1, value, .. } => {
+            return Some(MagicqCommand::Level(state.previous_playback, *value))
+        }
+
+        // If this message isn't set as a command yet
+        other => {
+            debug!("Message {:?} not set as a command", other);
+            return None
+        }
+    };
+
     // If is just regular note status, fill in playback info and command
+    let bank = state.active_bank();
 
     // To make sure it doesn't try to use negative playback numbers (u8 overflow panic)
-    if note < first_playback_note { return Ok(None) }
+    if note < bank.first_playback_note { return None }
 
     // Convert the note value to a Chamsys playback
-    let playback_number = (note - first_playback_note + 1);
+    let playback_number = (note - bank.first_playback_note).checked_add(bank.first_playback)?;
+
+    // Mapped notes do what their mapping says to their playback,
+    // anything else activates it while the note is held
+    let cmd = match (bank.mappings.get(&(note as usize)), velocity) {
+        (Some(LxCommand::Activate) | None, Some(_)) => MagicqCommand::Activate(playback_number),
+        (None, None) => MagicqCommand::Release(playback_number),
+        (Some(LxCommand::Deactivate), Some(_)) => MagicqCommand::Release(playback_number),
+        (Some(LxCommand::Intensity), Some(velocity)) => MagicqCommand::Level(playback_number, velocity),
+        (Some(LxCommand::Intensity), None) => MagicqCommand::Level(playback_number, 0),
+
+        // Activate and deactivate latch, so letting go of the note does nothing
+        (Some(LxCommand::Activate | LxCommand::Deactivate), None) => return None,
+    };
 
     // Update which playback is currently playing
     state.previous_playback = playback_number;
 
-    // Send back the formatted command
-    Ok(Some(format!("{}{}", playback_number, command_letter)))
+    // Remember what this bank has activated so it can be released when switching away
+    match cmd {
+        MagicqCommand::Activate(_) | MagicqCommand::Level(_, 1..) => state.bank_playbacks.insert(playback_number),
+        _ => state.bank_playbacks.remove(&playback_number),
+    };
+
+    Some(cmd)
 }
 
+// Returns where the command was sent
 fn send_magicq_command(
     socket: &UdpSocket,
-    command: &str,
-    state: &AppState,
-) -> Result<String, ProgramError> {
-    let target = SocketAddrV4::new(state.desk_ip, CHAMSYS_PORT);
+    command: &EncodedCommand,
+    desk_ip: Ipv4Addr,
+) -> Result<SocketAddrV4, ProgramError> {
+    let target = SocketAddrV4::new(desk_ip, CHAMSYS_PORT);
 
-    match socket.send_to(&command.as_bytes().to_vec(), target) {
+    match socket.send_to(command.as_bytes(), target) {
         Ok(_) => (),
-        Err(e) => return_err!(format!("Failed to send: {}", e))
+        Err(e) => return_err!(NetworkSend, format!("Failed to send '{}' to {}: {}", command.as_str(), target, e), e)
     }
 
-    Ok(format!("Command '{}' sent to {}", command, target))
+    Ok(target)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_with_banks() -> AppState {
+        let mut state = AppState::new(Vec::new(), Ipv4Addr::UNSPECIFIED, OutputMode::Simulation);
+        let song = MappingBank {
+            program: Some(5),
+            first_playback: 11,
+            mappings: HashMap::from([(62, LxCommand::Deactivate), (64, LxCommand::Intensity), (65, LxCommand::Activate)]),
+            ..MappingBank::new("Song 1")
+        };
+        state.set_banks(vec![MappingBank::new(DEFAULT_BANK_NAME), song], BankSwitching::default());
+        state
+    }
+
+    fn note_on(note: u8, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn { channel: 1, note, velocity }
+    }
+
+    fn note_off(note: u8) -> MidiMessage {
+        MidiMessage::NoteOff { channel: 1, note, velocity: 0 }
+    }
+
+    #[test]
+    fn program_change_switches_to_the_mapped_bank() {
+        let mut state = state_with_banks();
+        let first = state.active_bank().first_playback_note;
+
+        // The default bank only uses the offset
+        assert_eq!(translate_midi_to_chamsys_command(&note_on(first + 4, 100), &mut state), Some(MagicqCommand::Activate(5)));
+
+        let bank = state.bank_switch_for(&MidiMessage::ProgramChange { channel: 1, program: 5 }).unwrap();
+        state.switch_bank(bank);
+        assert_eq!(state.active_bank().name, "Song 1");
+
+        // Intensity sets the level from the velocity, and back to 0 when the note is let go
+        assert_eq!(translate_midi_to_chamsys_command(&note_on(64, 100), &mut state), Some(MagicqCommand::Level(11 + 64 - first, 100)));
+        assert_eq!(translate_midi_to_chamsys_command(&note_off(64), &mut state), Some(MagicqCommand::Level(11 + 64 - first, 0)));
+
+        // Activate and deactivate latch
+        assert_eq!(translate_midi_to_chamsys_command(&note_on(65, 100), &mut state), Some(MagicqCommand::Activate(11 + 65 - first)));
+        assert_eq!(translate_midi_to_chamsys_command(&note_off(65), &mut state), None);
+        assert_eq!(translate_midi_to_chamsys_command(&note_on(62, 100), &mut state), Some(MagicqCommand::Release(11 + 62 - first)));
+        assert_eq!(translate_midi_to_chamsys_command(&note_off(62), &mut state), None);
+
+        // Notes without a mapping still use the offset
+        assert_eq!(translate_midi_to_chamsys_command(&note_on(60, 100), &mut state), Some(MagicqCommand::Activate(11 + 60 - first)));
+        assert_eq!(translate_midi_to_chamsys_command(&note_off(60), &mut state), Some(MagicqCommand::Release(11 + 60 - first)));
+    }
+
+    #[test]
+    fn mappings_from_the_api_are_used() {
+        let mut state = state_with_banks();
+        let first = state.active_bank().first_playback_note;
+        let active = state.active_bank;
+
+        state.banks[active].mappings = HashMap::from([(first as usize, LxCommand::Intensity)]);
+
+        assert_eq!(translate_midi_to_chamsys_command(&note_on(first, 64), &mut state), Some(MagicqCommand::Level(1, 64)));
+    }
+
+    // Three banks, switched with two notes that would otherwise control playbacks 11 and 12
+    fn state_with_bank_switching(channel: Option<u8>) -> (AppState, u8, u8) {
+        let mut state = state_with_banks();
+        let next = state.active_bank().first_playback_note + 10;
+        let previous = next + 1;
 
+        let mut banks = state.banks.clone();
+        banks.push(MappingBank { program: Some(7), ..MappingBank::new("Song 2") });
+        state.set_banks(banks, BankSwitching {
+            channel,
+            next_note: Some(next),
+            previous_note: Some(previous),
+            select_cc: Some(20),
+            release_on_switch: false,
+        });
+
+        (state, next, previous)
+    }
+
+    #[test]
+    fn next_and_previous_notes_step_through_the_banks() {
+        let (mut state, next, previous) = state_with_bank_switching(None);
+
+        assert_eq!(state.bank_switch_for(&note_on(previous, 100)), Some(0));
+
+        for bank in [1, 2, 2] {
+            let switch = state.bank_switch_for(&note_on(next, 100)).unwrap();
+            assert_eq!(switch, bank);
+            state.switch_bank(switch);
+        }
+
+        for bank in [1, 0, 0] {
+            let switch = state.bank_switch_for(&note_on(previous, 100)).unwrap();
+            assert_eq!(switch, bank);
+            state.switch_bank(switch);
+        }
+
+        assert_eq!(state.bank_switch_for(&note_on(next + 2, 100)), None);
+        assert_eq!(state.bank_switch_for(&MidiMessage::ProgramChange { channel: 1, program: 7 }), Some(2));
+        assert_eq!(state.bank_switch_for(&MidiMessage::ProgramChange { channel: 1, program: 6 }), None);
+    }
+
+    #[test]
+    fn select_cc_picks_the_bank_by_position() {
+        let (state, _, _) = state_with_bank_switching(None);
+        let select = |controller, value| state.bank_switch_for(&MidiMessage::ControlChange { channel: 1, controller, value });
+
+        assert_eq!(select(20, 0), Some(0));
+        assert_eq!(select(20, 1), Some(1));
+        assert_eq!(select(20, 2), Some(2));
+        assert_eq!(select(20, 127), Some(2));
+        assert_eq!(select(21, 1), None);
+    }
+
+    #[test]
+    fn bank_switching_can_be_limited_to_a_channel() {
+        let (state, next, _) = state_with_bank_switching(Some(16));
+
+        assert_eq!(state.bank_switch_for(&note_on(next, 100)), None);
+        assert_eq!(state.bank_switch_for(&MidiMessage::NoteOn { channel: 16, note: next, velocity: 100 }), Some(1));
+        assert_eq!(state.bank_switch_for(&MidiMessage::ControlChange { channel: 1, controller: 20, value: 1 }), None);
+    }
+
+    #[test]
+    fn note_offs_for_switching_notes_are_swallowed() {
+        let (mut state, next, previous) = state_with_bank_switching(None);
+        let mut events = Vec::new();
+
+        // Stays on the bank it's on, so nothing is switched or sent
+        assert_eq!(state.bank_switch_for(&note_off(next)), Some(0));
+        assert_eq!(state.bank_switch_for(&note_off(previous)), Some(0));
+
+        for message in [[0x90, next, 100], [0x80, next, 0], [0x90, previous, 100], [0x80, previous, 0]] {
+            handle_midi(&mut state, None, &[], &message, Instant::now(), &mut events);
+        }
+
+        assert_eq!(events, vec![
+            RuntimeEvent::BankChanged(String::from("Song 1")),
+            RuntimeEvent::BankChanged(String::from(DEFAULT_BANK_NAME)),
+        ]);
+        assert_eq!(state.stats.commands_out, 0);
+        assert_eq!(state.stats.messages_ignored, 0);
+    }
+
+    // Three songs after the banks, stepped through with two notes below the first playback note
+    fn state_with_setlist(channel: Option<u8>) -> AppState {
+        let mut state = state_with_banks();
+
+        let opener = Song {
+            bank: Some(String::from("Song 1")),
+            commands: vec![MagicqCommand::Go(1), MagicqCommand::Level(2, 100)],
+            ..Song::new("Opener")
+        };
+        let encore = Song {
+            bank: Some(String::from(DEFAULT_BANK_NAME)),
+            commands: vec![MagicqCommand::Stop(1)],
+            ..Song::new("Encore")
+        };
+
+        state.set_setlist(Setlist {
+            songs: vec![opener, Song::new("Ballad"), encore],
+            channel,
+            next_note: Some(36),
+            previous_note: Some(37),
+            next_cc: Some(64),
+        });
+
+        state
+    }
+
+    #[test]
+    fn setlist_notes_and_footswitch_step_through_the_songs() {
+        let state = state_with_setlist(None);
+        let footswitch = |value| MidiMessage::ControlChange { channel: 1, controller: 64, value };
+
+        assert_eq!(state.song_step_for(&note_on(36, 100)), Some(1));
+        assert_eq!(state.song_step_for(&note_on(37, 100)), Some(-1));
+        assert_eq!(state.song_step_for(&footswitch(127)), Some(1));
+
+        // Letting go doesn't move, but isn't sent on to the desk either
+        assert_eq!(state.song_step_for(&note_off(36)), Some(0));
+        assert_eq!(state.song_step_for(&footswitch(0)), Some(0));
+
+        assert_eq!(state.song_step_for(&note_on(38, 100)), None);
+
+        let state = state_with_setlist(Some(16));
+        assert_eq!(state.song_step_for(&note_on(36, 100)), None);
+        assert_eq!(state.song_step_for(&MidiMessage::NoteOn { channel: 16, note: 36, velocity: 100 }), Some(1));
+    }
+
+    #[test]
+    fn stepping_stops_at_either_end_of_the_setlist() {
+        let mut state = state_with_setlist(None);
+
+        // Before the first song only going forwards starts one
+        assert_eq!(state.song_after_step(-1), None);
+        assert_eq!(state.song_after_step(0), None);
+        assert_eq!(state.song_after_step(1), Some(0));
+        assert_eq!(state.song_after_step(5), Some(2));
+
+        state.enter_song(0);
+        assert_eq!(state.song_after_step(-1), None);
+        assert_eq!(state.song_after_step(1), Some(1));
+
+        state.enter_song(2);
+        assert_eq!(state.song_after_step(1), None);
+        assert_eq!(state.song_after_step(-1), Some(1));
+        assert_eq!(state.song_after_step(-5), Some(0));
+
+        state.set_setlist(Setlist::default());
+        assert_eq!(state.song_after_step(1), None);
+    }
+
+    #[test]
+    fn entering_a_song_switches_its_bank_and_sends_its_commands() {
+        let mut state = state_with_setlist(None);
+        let mut events = Vec::new();
+        let mut step = |state: &mut AppState, note| {
+            events.clear();
+            handle_midi(state, None, &[], &[0x90, note, 100], Instant::now(), &mut events);
+            handle_midi(state, None, &[], &[0x80, note, 0], Instant::now(), &mut events);
+            events.clone()
+        };
+
+        let opener = state.setlist.songs[0].clone();
+        assert_eq!(step(&mut state, 36), vec![
+            RuntimeEvent::BankChanged(String::from("Song 1")),
+            RuntimeEvent::CommandSent(MagicqCommand::Go(1)),
+            RuntimeEvent::CommandSent(MagicqCommand::Level(2, 100)),
+            RuntimeEvent::SongChanged(0, opener),
+        ]);
+        assert_eq!(state.active_bank().name, "Song 1");
+
+        // A song without a bank stays on the one it's on
+        let ballad = state.setlist.songs[1].clone();
+        assert_eq!(step(&mut state, 36), vec![RuntimeEvent::SongChanged(1, ballad.clone())]);
+        assert_eq!(state.active_bank().name, "Song 1");
+
+        let encore = state.setlist.songs[2].clone();
+        assert_eq!(step(&mut state, 36), vec![
+            RuntimeEvent::BankChanged(String::from(DEFAULT_BANK_NAME)),
+            RuntimeEvent::CommandSent(MagicqCommand::Stop(1)),
+            RuntimeEvent::SongChanged(2, encore),
+        ]);
+
+        // Past the end nothing happens
+        assert_eq!(step(&mut state, 36), vec![]);
+        assert_eq!(state.active_song, Some(2));
+
+        assert_eq!(step(&mut state, 37), vec![RuntimeEvent::SongChanged(1, ballad)]);
+        assert_eq!(state.active_bank().name, DEFAULT_BANK_NAME);
+        assert_eq!(state.stats.commands_out, 3);
+    }
+
+    const MAIN_1: Ipv4Addr = Ipv4Addr::new(2, 0, 0, 35);
+    const MAIN_2: Ipv4Addr = Ipv4Addr::new(2, 0, 0, 36);
+    const BACKUP_1: Ipv4Addr = Ipv4Addr::new(2, 0, 0, 37);
+    const BACKUP_2: Ipv4Addr = Ipv4Addr::new(2, 0, 0, 38);
+
+    fn desk(address: Ipv4Addr, role: DeskRole, enabled: bool) -> DeskTarget {
+        DeskTarget { address, enabled, role }
+    }
+
+    // Two mains and two backups, with the health each one's checks found (None if not checked yet)
+    fn state_with_desks(health: [Option<DeskHealth>; 4], enabled: [bool; 4]) -> AppState {
+        let targets = vec![
+            desk(MAIN_1, DeskRole::Main, enabled[0]),
+            desk(MAIN_2, DeskRole::Main, enabled[1]),
+            desk(BACKUP_1, DeskRole::Backup, enabled[2]),
+            desk(BACKUP_2, DeskRole::Backup, enabled[3]),
+        ];
+        let mut state = AppState::new(targets, Ipv4Addr::LOCALHOST, OutputMode::Network);
+
+        for (desk, health) in state.desks.iter_mut().zip(health) {
+            if let (Some(monitor), Some(health)) = (&mut desk.monitor, health) {
+                monitor.set_health(health);
+            }
+        }
+
+        state
+    }
+
+    fn down() -> Option<DeskHealth> {
+        Some(DeskHealth::Down(String::from("host unreachable")))
+    }
+
+    const UP: Option<DeskHealth> = Some(DeskHealth::Up);
+    const UNKNOWN: Option<DeskHealth> = Some(DeskHealth::Unknown);
+    const ALL_ENABLED: [bool; 4] = [true; 4];
+
+    #[test]
+    fn main_desks_are_used_while_they_can_be_reached() {
+        assert_eq!(state_with_desks([UP, UP, UP, UP], ALL_ENABLED).active_desks(), vec![MAIN_1, MAIN_2]);
+
+        // Only Down counts as unreachable, a desk that isn't answering or hasn't been checked is still sent to
+        assert_eq!(state_with_desks([UNKNOWN, None, UP, UP], ALL_ENABLED).active_desks(), vec![MAIN_1, MAIN_2]);
+
+        assert_eq!(state_with_desks([down(), UP, UP, UP], ALL_ENABLED).active_desks(), vec![MAIN_2]);
+    }
+
+    #[test]
+    fn the_first_reachable_backup_is_used_when_every_main_is_down() {
+        assert_eq!(state_with_desks([down(), down(), UP, UP], ALL_ENABLED).active_desks(), vec![BACKUP_1]);
+        assert_eq!(state_with_desks([down(), down(), down(), UNKNOWN], ALL_ENABLED).active_desks(), vec![BACKUP_2]);
+    }
+
+    #[test]
+    fn the_mains_are_used_when_nothing_can_be_reached() {
+        // In case it's only the checks that are failing
+        assert_eq!(state_with_desks([down(), down(), down(), down()], ALL_ENABLED).active_desks(), vec![MAIN_1, MAIN_2]);
+    }
+
+    #[test]
+    fn disabled_desks_are_never_used() {
+        assert_eq!(state_with_desks([UP, UP, UP, UP], [false, true, true, true]).active_desks(), vec![MAIN_2]);
+
+        // A disabled main doesn't count as reachable, and a disabled backup isn't failed over to
+        assert_eq!(state_with_desks([UP, down(), UP, UP], [false, true, false, true]).active_desks(), vec![BACKUP_2]);
+
+        // Or fallen back to
+        assert_eq!(state_with_desks([down(), down(), down(), down()], [false, true, true, true]).active_desks(), vec![MAIN_2]);
+        assert!(state_with_desks([UP, UP, UP, UP], [false; 4]).active_desks().is_empty());
+    }
+
+    #[test]
+    fn desks_are_not_checked_in_simulation_mode() {
+        let targets = vec![desk(MAIN_1, DeskRole::Main, true), desk(BACKUP_1, DeskRole::Backup, true)];
+        let state = AppState::new(targets, Ipv4Addr::UNSPECIFIED, OutputMode::Simulation);
+
+        assert_eq!(state.active_desks(), vec![MAIN_1]);
+        assert_eq!(state.time_until_check(), None);
+    }
+
+    #[test]
+    fn the_runtime_doesnt_start_without_a_socket_to_send_from() {
+        // A documentation address, which no interface here has
+        let state = AppState::new(vec![DeskTarget::new(MAIN_1)], Ipv4Addr::new(192, 0, 2, 1), OutputMode::Network);
+
+        match start_chamsys_runtime_without_input(state) {
+            Ok(_) => panic!("the runtime started without a socket"),
+            Err(e) => assert_eq!(e.kind(), crate::errors::ErrorKind::NetworkBind),
+        }
+    }
+}
@@ -1,125 +1,635 @@
-use std::env;
-use std::net::Ipv4Addr;
-use color_print::{ceprintln, cprintln};
-use crate::midi_io::{get_midi_input, get_midi_input_port};
-use crate::test::{dummy_midi_out};
-use crate::MidiRuntime;
+use std::io::stdin;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc;
+use std::time::Duration;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use color_print::ceprintln;
+use log::{error, LevelFilter};
+use regex::Regex;
+use crate::midi_io::{check_midi_backend, create_virtual_output, get_midi_input, get_midi_input_source, midi_input_source_name, print_midi_ports, set_client_name, MidiBackend};
+use crate::{print_runtime_stats, print_simulation_summary, return_err, BankSwitching, DeskTarget, MappingBank, MidiPortOptions, MidiRuntime, OutputMode, Setlist, DEFAULT_BANK_NAME};
+use crate::port_selector::PortSelector;
+use crate::show::load_show_file;
+use crate::logging::{init_logging, LogConfig};
+use crate::learn::learn_mappings;
+use crate::recorder::{export_recording, record_midi_input, Recorder, RecorderHandle};
+use crate::player::{play_midi_file, PlaybackOptions, PlaybackTarget, MAX_SPEED, MIN_SPEED};
 use crate::organ::organ_midi::play_organ;
+use crate::errors::{ErrorKind, ProgramError};
+use crate::network::{print_interfaces, resolve_app_ip};
+use crate::midi_utils::MiddleC;
+use crate::tui::{run_dashboard, ShowReload};
+use crate::api::ControlApi;
+use crate::monitor::{monitor_midi_input, MessageKind, MonitorFilter};
 
+/// MIDI control for Chamsys lighting desks and the organ
+#[derive(Parser)]
+#[command(name = "midi_lx", version, arg_required_else_help = true)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Options that can go anywhere on the command line.
+// Anything given here takes priority over the show file.
+#[derive(Args, Clone)]
+struct GlobalArgs {
+    /// Load settings from a show file
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Desk to send commands to, can be given more than once.
+    /// Needed to send to desks unless the show file lists them.
+    #[arg(long = "desk-ip", global = true, value_name = "IP")]
+    desk_ips: Vec<Ipv4Addr>,
+
+    /// Local IP to send from (picked from the interface on the desk's network if not set)
+    #[arg(long, global = true, value_name = "IP")]
+    app_ip: Option<Ipv4Addr>,
+
+    /// MIDI input port by index, name, part of the name or /regex/
+    #[arg(long, global = true, value_name = "PORT", value_parser = parse_port_selector)]
+    in_port: Option<PortSelector>,
+
+    /// MIDI output port by index, name, part of the name or /regex/
+    #[arg(long, global = true, value_name = "PORT", value_parser = parse_port_selector)]
+    out_port: Option<PortSelector>,
+
+    /// Fail unless midi_lx was built for this MIDI system.
+    /// The system can't be switched when running, JACK is used when built with the jack feature.
+    #[arg(long, global = true, value_enum, value_name = "BACKEND")]
+    require_midi_backend: Option<MidiBackend>,
+
+    /// Name of the MIDI client ports are opened from, e.g. "midi_lx:in" in JACK (default midi_lx)
+    #[arg(long, global = true, value_name = "NAME")]
+    client_name: Option<String>,
+
+    /// What note 60 is called in note names shown (C3 or C4)
+    #[arg(long, global = true, value_name = "NOTE", value_parser = parse_middle_c)]
+    middle_c: Option<MiddleC>,
+
+    /// Log more detail (-v for debug, -vv for trace)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(Subcommand)]
 enum Command {
-    MIDITest,
-    ChamsysMIDI,
-    OrganStopControl,
-    OrganKeyboardControl,
-    Help,
+    /// Run the Chamsys MIDI control program
+    Lx {
+        /// Send nothing to the desk, trace commands to a simulated desk instead
+        #[arg(long)]
+        simulate: bool,
+
+        /// Serve the HTTP control API on this address, e.g. 127.0.0.1:8080
+        #[arg(long, value_name = "ADDRESS")]
+        api: Option<SocketAddr>,
+
+        /// Record MIDI input to this file while running, like the record command
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+
+        #[command(flatten)]
+        virtual_ports: VirtualPortArgs,
+    },
+
+    /// Run the Chamsys MIDI control program with a live dashboard
+    #[command(alias = "tui")]
+    Dashboard {
+        /// Send nothing to the desk, trace commands to a simulated desk instead
+        #[arg(long)]
+        simulate: bool,
+
+        /// Serve the HTTP control API on this address, e.g. 127.0.0.1:8080
+        #[arg(long, value_name = "ADDRESS")]
+        api: Option<SocketAddr>,
+
+        /// Record MIDI input to this file while running, like the record command
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+
+        #[command(flatten)]
+        virtual_ports: VirtualPortArgs,
+    },
+
+    /// Run the organ MIDI control program
+    Organ {
+        /// Record MIDI input to this file while running, like the record command
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+
+        #[command(flatten)]
+        virtual_ports: VirtualPortArgs,
+    },
+
+    /// Run the organ MIDI control program, controlling stops
+    Stops {
+        /// Record MIDI input to this file while running, like the record command
+        #[arg(long, value_name = "FILE")]
+        record: Option<PathBuf>,
+
+        #[command(flatten)]
+        virtual_ports: VirtualPortArgs,
+    },
+
+    /// List MIDI input and output ports
+    Ports,
+
+    /// Show incoming MIDI messages decoded
+    #[command(alias = "test")]
+    Monitor {
+        /// Only show this type of message, can be given more than once
+        #[arg(long = "type", value_enum, value_name = "TYPE")]
+        kinds: Vec<MonitorType>,
+
+        /// Only show messages on this channel (1-16), can be given more than once
+        #[arg(long = "channel", value_name = "CHANNEL", value_parser = clap::value_parser!(u8).range(1..=16))]
+        channels: Vec<u8>,
+
+        #[command(flatten)]
+        virtual_ports: VirtualPortArgs,
+    },
+
+    /// Show which desk command each key or control sends, without sending anything
+    Learn {
+        #[command(flatten)]
+        virtual_ports: VirtualPortArgs,
+    },
+
+    /// Play a MIDI file through a program
+    Play {
+        file: PathBuf,
+
+        #[arg(value_enum, default_value = "lx")]
+        target: PlayTarget,
+
+        /// Start again from the beginning when the file ends
+        #[arg(long = "loop")]
+        looping: bool,
+
+        /// Playback speed multiplier, from 0.01 to 100
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f64,
+
+        /// Seconds into the file to start from
+        #[arg(long, default_value = "0", value_name = "SECONDS", value_parser = parse_start)]
+        start: Duration,
+
+        /// Send nothing to the desk (lx only)
+        #[arg(long)]
+        simulate: bool,
+    },
+
+    /// Record MIDI input with timestamps to a file
+    Record {
+        file: PathBuf,
+    },
+
+    /// Export a recording as a Standard MIDI File
+    Export {
+        recording: PathBuf,
+        midi_file: PathBuf,
+
+        #[arg(long, default_value_t = 120.0, value_name = "BPM", value_parser = parse_tempo)]
+        tempo: f64,
+    },
+
+    /// List network interfaces and which one is on the desk's network
+    Interfaces,
+}
+
+#[derive(Args)]
+struct VirtualPortArgs {
+    /// Create a virtual input port with this name instead of opening an existing port
+    #[arg(long, value_name = "NAME")]
+    virtual_in: Option<String>,
+
+    /// Create a virtual output port with this name instead of opening an existing port
+    #[arg(long, value_name = "NAME")]
+    virtual_out: Option<String>,
+
+    /// Connect the virtual output to every port whose name matches this regex, e.g. "fluidsynth:.*" (JACK only)
+    #[arg(long, value_name = "PATTERN", requires = "virtual_out", value_parser = parse_port_pattern)]
+    connect_out: Option<Regex>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MonitorType {
+    Note,
+    PolyAftertouch,
+    Cc,
+    Program,
+    Aftertouch,
+    PitchBend,
+    Sysex,
+    Common,
+    Realtime,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PlayTarget {
+    Lx,
+    Organ,
+    Stops,
+}
+
+// Everything from the command line and show file a command might need
+struct Settings {
+    ports: MidiPortOptions,
+    client_name: Option<String>,
+    desk_targets: Vec<DeskTarget>,
+    app_ip: Option<Ipv4Addr>,
+    middle_c: MiddleC,
+
+    // Empty to use a single default bank
+    banks: Vec<MappingBank>,
+    bank_switching: BankSwitching,
+
+    // Empty if there's no setlist
+    setlist: Setlist,
 }
 
 // This function will handle the CLI commands.
 // It will eventually be replaced with a GUI but still be used for testing and development.
-pub fn run_cli() {
-    // Get user command from CLI and run that program
-    // Display errors to the user if there is a problem,
-    // then either relaunch the program or prompt the user if they want to run a different program
-    let args: Vec<String> = env::args().collect();
-
-    let command = match get_command(&args[1..]) {
-        Ok(command) => command,
+// Exits with a failure code if the command fails, so scripts can tell.
+pub fn run_cli() -> ExitCode {
+    // Usage errors are reported by clap, which exits with its own error code
+    let cli = Cli::parse();
+
+    let mut log_config = LogConfig::from_env();
+    match cli.global.verbose {
+        0 => (),
+        1 => log_config.level = LevelFilter::Debug,
+        _ => log_config.level = LevelFilter::Trace,
+    }
+
+    // The dashboard draws over the whole terminal, so logs only go to the session file
+    if matches!(cli.command, Command::Dashboard { .. }) {
+        log_config.console = false;
+    }
+
+    if let Err(e) = init_logging(log_config) {
+        ceprintln!("<red>{}</>", e);
+    }
+
+    match run_command(cli) {
+        Ok(_) => ExitCode::SUCCESS,
         Err(e) => {
-            ceprintln!("<red>{}</>\n", e);
-            print_possible_commands();
-            return;
+            error!("{}", e);
+
+            if let Some(suggestion) = suggestion(e.kind()) {
+                ceprintln!("<yellow>hint:</> {}", suggestion);
+            }
+
+            ExitCode::FAILURE
         }
-    };
+    }
+}
 
-    // Match the prompt and run the appropriate program
-    match command {
-        Command::Help => print_possible_commands(),
-
-        Command::MIDITest => {
-            println!("Running MIDI test...");
-            // Currently just running the dummy program for testing
-            match dummy_midi_out() {
-                Ok(_) => (),
-                Err(e) => {
-                    ceprintln!("<red>{}</>", e)
-                },
+/// Something to try for each kind of error
+fn suggestion(kind: ErrorKind) -> Option<&'static str> {
+    match kind {
+        ErrorKind::MidiInit => Some("check the MIDI system is available (ALSA on Linux, CoreMIDI on macOS, WinMM on Windows)"),
+        ErrorKind::PortSelection => Some("run 'midi_lx ports' to list the ports, then pick one with --in-port or --out-port"),
+        ErrorKind::Connection => Some("check the port isn't in use by another program, or create one with --virtual-in or --virtual-out"),
+        ErrorKind::NetworkBind => Some("run 'midi_lx interfaces' to see which interface is on the desk's network, or set it with --app-ip"),
+        ErrorKind::NetworkSend => Some("check the desk's address with --desk-ip and that this machine is on the desk's network"),
+        ErrorKind::Config => Some("check the show file given with --config and the command line options"),
+        ErrorKind::Parse | ErrorKind::Io => None,
+    }
+}
+
+fn run_command(cli: Cli) -> Result<(), ProgramError> {
+    let global = cli.global;
+    let mut settings = load_settings(global.clone())?;
+
+    if let Some(backend) = global.require_midi_backend {
+        check_midi_backend(backend)?;
+    }
+
+    if let Some(client_name) = &settings.client_name {
+        set_client_name(client_name);
+    }
+
+    // Match the command and run the appropriate program
+    match cli.command {
+        Command::Monitor { kinds, channels, virtual_ports } => {
+            virtual_ports.apply(&mut settings.ports);
+
+            let filter = MonitorFilter {
+                kinds: kinds.into_iter().map(MonitorType::kind).collect(),
+                channels,
             };
+
+            monitor_midi_input(&settings.ports, filter, settings.middle_c)
         },
 
-        // Currently just running the dummy program for testing
-        Command::ChamsysMIDI => {
-            let midi_input = match get_midi_input() {
-                Ok(i) => i,
-                Err(e) => {
-                    ceprintln!("<red>{}</>", e);
-                    return
-                },
+        Command::Lx { simulate, api, record, virtual_ports } => {
+            virtual_ports.apply(&mut settings.ports);
+            let output_mode = if simulate { OutputMode::Simulation } else { OutputMode::Network };
+            let recorder = start_recorder(record.as_deref())?;
+            run_chamsys(settings, output_mode, api, recorder.as_ref().map(Recorder::handle))
+        }
+
+        Command::Dashboard { simulate, api, record, virtual_ports } => {
+            virtual_ports.apply(&mut settings.ports);
+            let recorder = start_recorder(record.as_deref())?;
+            let output_mode = if simulate { OutputMode::Simulation } else { OutputMode::Network };
+            let desk_targets = settings.desk_targets.clone();
+            let middle_c = settings.middle_c;
+            let bank = settings.banks.first().map(|bank| bank.name.clone()).unwrap_or_else(|| String::from(DEFAULT_BANK_NAME));
+            let song_count = settings.setlist.songs.len();
+            let (runtime, input_port) = start_chamsys(settings, output_mode, recorder.as_ref().map(Recorder::handle))?;
+
+            // Stopping the runtime from the API closes the dashboard
+            let _api = match api {
+                Some(address) => {
+                    let handle = runtime.handle();
+                    Some(ControlApi::start(address, runtime.handle(), middle_c, move || handle.stop())?)
+                }
+                None => None,
             };
 
-            let selected_midi_port = match get_midi_input_port(&midi_input) {
-                Ok(p) => p,
-                Err(e) => {
-                    ceprintln!("<red>{}</>", e);
-                    return
-                },
+            run_dashboard(runtime, input_port, desk_targets, bank, song_count, output_mode, move || {
+                if global.config.is_none() {
+                    return_err!(Config, "there's no show file to reload, start with --config to use one")
+                }
+
+                let settings = load_settings(global.clone())?;
+                if output_mode == OutputMode::Network {
+                    require_desks(&settings.desk_targets)?;
+                }
+
+                Ok(ShowReload {
+                    desk_targets: settings.desk_targets,
+                    banks: settings.banks,
+                    bank_switching: settings.bank_switching,
+                    setlist: settings.setlist,
+                })
+            })
+        }
+
+        Command::Organ { record, virtual_ports } => {
+            virtual_ports.apply(&mut settings.ports);
+            let recorder = start_recorder(record.as_deref())?;
+            play_organ(false, &settings.ports, recorder.as_ref().map(Recorder::handle))
+        },
+
+        Command::Stops { record, virtual_ports } => {
+            virtual_ports.apply(&mut settings.ports);
+            let recorder = start_recorder(record.as_deref())?;
+            play_organ(true, &settings.ports, recorder.as_ref().map(Recorder::handle))
+        },
+
+        Command::Ports => print_midi_ports(),
+
+        Command::Learn { virtual_ports } => {
+            virtual_ports.apply(&mut settings.ports);
+            learn_mappings(&settings.ports, settings.banks, settings.bank_switching, settings.setlist, settings.middle_c)
+        },
+
+        Command::Record { file } => record_midi_input(&file, &settings.ports),
+
+        Command::Play { file, target, looping, speed, start, simulate } => {
+            let target = match target {
+                PlayTarget::Lx => {
+                    let output_mode = if simulate { OutputMode::Simulation } else { OutputMode::Network };
+
+                    PlaybackTarget::Chamsys {
+                        app_ip: get_app_ip(output_mode, settings.app_ip, &settings.desk_targets)?,
+                        desk_targets: settings.desk_targets,
+                        output_mode,
+                        banks: settings.banks,
+                        bank_switching: settings.bank_switching,
+                        setlist: settings.setlist,
+                    }
+                }
+                PlayTarget::Organ | PlayTarget::Stops if simulate => {
+                    return_err!(Config, "--simulate only works when playing to lx")
+                }
+                PlayTarget::Organ => PlaybackTarget::Organ { control_stops: false },
+                PlayTarget::Stops => PlaybackTarget::Organ { control_stops: true },
             };
 
-            match MidiRuntime::create(
-                // TEMP DEFAULTS FOR TESTING
-                Ipv4Addr::new(2, 0, 0, 35),
-                Ipv4Addr::new(2, 0, 0, 1),
-                midi_input,
-                selected_midi_port,
-                None,
-            ) {
-                Ok(_) => (),
-                Err(e) => {
-                    ceprintln!("<red>{}</>", e)
-                },
+            let options = PlaybackOptions {
+                start_offset: start,
+                looping,
+                speed,
             };
-        }
 
-        Command::OrganStopControl => {
-            match play_organ(true) {
-                Ok(_) => (),
-                Err(e) => {
-                    ceprintln!("<red>{}</>", e)
-                },
-            }
+            play_midi_file(&file, target, options, &settings.ports)
         },
 
-        Command::OrganKeyboardControl => {
-            match play_organ(false) {
-                Ok(_) => (),
-                Err(e) => {
-                    ceprintln!("<red>{}</>", e)
-                },
-            }
+        Command::Export { recording, midi_file, tempo } => export_recording(&recording, &midi_file, tempo),
+
+        Command::Interfaces => print_interfaces(&settings.desk_targets),
+    }
+}
+
+fn run_chamsys(settings: Settings, output_mode: OutputMode, api: Option<SocketAddr>, recorder: Option<RecorderHandle>) -> Result<(), ProgramError> {
+    let middle_c = settings.middle_c;
+    let (runtime, _) = start_chamsys(settings, output_mode, recorder)?;
+
+    // Runs until enter is pressed or a stop is requested through the API
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+    let _api = match api {
+        Some(address) => {
+            let stop_tx = stop_tx.clone();
+            Some(ControlApi::start(address, runtime.handle(), middle_c, move || {
+                let _ = stop_tx.send(());
+            })?)
+        }
+        None => None,
+    };
+
+    println!("Running, press enter to stop ...");
+
+    std::thread::spawn(move || {
+        let mut input = String::new();
+        if let Err(e) = stdin().read_line(&mut input) {
+            error!("failed to read line from stdin: {e}");
+        }
+
+        let _ = stop_tx.send(());
+    });
+
+    let _ = stop_rx.recv();
+
+    print_simulation_summary(&runtime);
+    print_runtime_stats(&runtime);
+    runtime.stop();
+
+    Ok(())
+}
+
+// Starts the runtime on the selected input, returning it with the name of the input
+fn start_chamsys(settings: Settings, output_mode: OutputMode, recorder: Option<RecorderHandle>) -> Result<(MidiRuntime, String), ProgramError> {
+    let app_ip = get_app_ip(output_mode, settings.app_ip, &settings.desk_targets)?;
+
+    let midi_input = get_midi_input()?;
+    let input_source = get_midi_input_source(&midi_input, &settings.ports)?;
+    let input_port = midi_input_source_name(&midi_input, &input_source);
+
+    // Only a virtual output is used for MIDI through for now
+    let midi_through = match &settings.ports.virtual_output {
+        Some(name) => Some(create_virtual_output(name, settings.ports.connect_output.as_ref())?),
+        None => None,
+    };
+
+    let runtime = MidiRuntime::create(
+        settings.desk_targets,
+        app_ip,
+        output_mode,
+        midi_input,
+        input_source,
+        midi_through,
+        recorder,
+    )?;
+
+    runtime.set_banks(settings.banks, settings.bank_switching);
+    runtime.set_setlist(settings.setlist);
+
+    Ok((runtime, input_port))
+}
+
+// The recorder is kept until the program finishes, so it has to outlive whatever records to it
+fn start_recorder(path: Option<&Path>) -> Result<Option<Recorder>, ProgramError> {
+    let Some(path) = path else {
+        return Ok(None)
+    };
+
+    Ok(Some(Recorder::start(path)?))
+}
+
+// Command line options take priority over the show file
+fn load_settings(global: GlobalArgs) -> Result<Settings, ProgramError> {
+    let mut settings = Settings {
+        ports: MidiPortOptions {
+            input: global.in_port,
+            output: global.out_port,
+            ..MidiPortOptions::default()
         },
+        client_name: global.client_name,
+        desk_targets: global.desk_ips.into_iter().map(DeskTarget::new).collect(),
+        app_ip: global.app_ip,
+        middle_c: global.middle_c.unwrap_or_default(),
+        banks: Vec::new(),
+        bank_switching: BankSwitching::default(),
+        setlist: Setlist::default(),
+    };
+
+    if let Some(show_path) = &global.config {
+        let show = load_show_file(show_path)?;
+
+        settings.ports.input = settings.ports.input.or(show.in_port);
+        settings.ports.output = settings.ports.output.or(show.out_port);
+        settings.ports.connect_output = show.connect_output;
+        settings.client_name = settings.client_name.or(show.client_name);
+        settings.app_ip = settings.app_ip.or(show.app_ip);
+        settings.middle_c = global.middle_c.or(show.middle_c).unwrap_or_default();
+        settings.bank_switching = show.bank_switching;
+        settings.setlist = show.setlist;
+
+        // Without banks, the show file's first playback note goes in the default bank
+        settings.banks = show.banks;
+        if settings.banks.is_empty()
+            && let Some(note) = show.first_playback_note
+        {
+            settings.banks.push(MappingBank {
+                first_playback_note: note,
+                ..MappingBank::new(DEFAULT_BANK_NAME)
+            });
+        }
+
+        if settings.desk_targets.is_empty() {
+            settings.desk_targets = show.desks;
+        }
+    }
+
+    Ok(settings)
+}
+
+// Nothing is sent in simulation mode, so there's no need to be on the desk's network or to have a desk at all
+fn get_app_ip(output_mode: OutputMode, configured: Option<Ipv4Addr>, desk_targets: &[DeskTarget]) -> Result<Ipv4Addr, ProgramError> {
+    match output_mode {
+        OutputMode::Network => {
+            require_desks(desk_targets)?;
+            resolve_app_ip(configured, desk_targets)
+        }
+        OutputMode::Simulation => Ok(configured.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+    }
+}
+
+fn require_desks(desk_targets: &[DeskTarget]) -> Result<(), ProgramError> {
+    if desk_targets.is_empty() {
+        return_err!(Config, "no desk to send to, give one with --desk-ip or a [[desk]] in the show file, or use --simulate")
     }
+
+    Ok(())
 }
 
-fn get_command(args: &[String]) -> Result<Command, String> {
-    let command = args.first().map(String::as_str);
+impl VirtualPortArgs {
+    fn apply(self, ports: &mut MidiPortOptions) {
+        ports.virtual_input = self.virtual_in;
+        ports.virtual_output = self.virtual_out;
+
+        if let Some(pattern) = self.connect_out {
+            ports.connect_output = Some(pattern);
+        }
+    }
+}
 
-    match command {
-        Some("lx") => Ok(Command::ChamsysMIDI),
-        Some("test") => Ok(Command::MIDITest),
-        Some("organ") => Ok(Command::OrganKeyboardControl),
-        Some("stops") => Ok(Command::OrganStopControl),
+impl MonitorType {
+    fn kind(self) -> MessageKind {
+        match self {
+            MonitorType::Note => MessageKind::Note,
+            MonitorType::PolyAftertouch => MessageKind::PolyAftertouch,
+            MonitorType::Cc => MessageKind::ControlChange,
+            MonitorType::Program => MessageKind::ProgramChange,
+            MonitorType::Aftertouch => MessageKind::ChannelAftertouch,
+            MonitorType::PitchBend => MessageKind::PitchBend,
+            MonitorType::Sysex => MessageKind::SysEx,
+            MonitorType::Common => MessageKind::Common,
+            MonitorType::Realtime => MessageKind::Realtime,
+        }
+    }
+}
 
-        // Nothing entered into the command line
-        None => Ok(Command::Help),
+fn parse_port_selector(selector: &str) -> Result<PortSelector, String> {
+    PortSelector::parse(selector).map_err(|e| e.to_string())
+}
 
-        // Some unknown command
-        _ => Err(format!("Invalid command: '{}'", command.unwrap())),
+fn parse_port_pattern(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+fn parse_middle_c(middle_c: &str) -> Result<MiddleC, String> {
+    MiddleC::parse(middle_c).map_err(|e| e.to_string())
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(value) if (MIN_SPEED..=MAX_SPEED).contains(&value) => Ok(value),
+        _ => Err(format!("needs a number from {MIN_SPEED} to {MAX_SPEED}")),
     }
 }
 
-fn print_possible_commands() {
-    cprintln!("\n<yellow, bold>Possible commands</>");
-    cprintln!("<bold>test</> - Run the MIDI test program");
-    cprintln!("<bold>lx</> - Run the Chamsys MIDI through program");
-    cprintln!("<bold>organ</> - Run the organ MIDI control program");
-    cprintln!("<bold>stops</> - Run the organ MIDI control program");
-}
\ No newline at end of file
+fn parse_tempo(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(String::from("needs a number greater than 0")),
+    }
+}
+
+fn parse_start(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>().map(Duration::try_from_secs_f64) {
+        Ok(Ok(start)) => Ok(start),
+        _ => Err(String::from("needs a number of seconds")),
+    }
+}
@@ -0,0 +1,218 @@
+// Checks that the desk can be reached, without relying on any external programs like ping.
+//
+// Each check makes sure there is a route from the app IP to the desk (connecting a UDP socket does this),
+// then sends an empty probe datagram to the MagicQ port, which MagicQ ignores.
+// The socket is non-blocking so the check never holds up the event loop:
+// if the desk isn't there, the OS reports it on the socket by the next check
+// (connection refused when MagicQ isn't listening, host unreachable when nothing answers on the network).
+// Anything the desk sends back to the socket counts as a heartbeat.
+//
+// MagicQ doesn't answer the probe, so no errors only means nothing has failed yet, not that the desk is there.
+// A desk is only Up after it has sent something back within REPLY_TIMEOUT, otherwise it's Unknown.
+// Broadcast targets never get errors or replies (the socket only hears from the address it's connected to),
+// so they stay Unknown unless the route to them goes away.
+// Commands are still sent to desks that are Unknown, only Down desks are failed over from.
+
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How often the desk is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long after the last thing the desk sent it still counts as Up
+const REPLY_TIMEOUT: Duration = Duration::from_secs(6);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeskHealth {
+    // The desk sent something back recently
+    Up,
+
+    // Probes are going out without errors but nothing has come back
+    Unknown,
+
+    // Why the desk can't be reached
+    Down(String),
+}
+
+pub struct DeskMonitor {
+    app_ip: Ipv4Addr,
+    desk_ip: Ipv4Addr,
+    desk_port: u16,
+    socket: Option<UdpSocket>,
+    probe_sent: bool,
+    last_reply: Option<Instant>,
+    health: Option<DeskHealth>,
+    next_check: Instant,
+}
+
+impl DeskMonitor {
+    pub fn new(app_ip: Ipv4Addr, desk_ip: Ipv4Addr, desk_port: u16) -> Self {
+        Self {
+            app_ip,
+            desk_ip,
+            desk_port,
+            socket: None,
+            probe_sent: false,
+            last_reply: None,
+            health: None,
+            next_check: Instant::now(),
+        }
+    }
+
+    /// True once a check has found the desk can't be reached
+    pub fn is_down(&self) -> bool {
+        matches!(self.health, Some(DeskHealth::Down(_)))
+    }
+
+    /// Sets the health as if a check had found it, so failover can be tested without a network
+    #[cfg(test)]
+    pub(crate) fn set_health(&mut self, health: DeskHealth) {
+        self.health = Some(health);
+    }
+
+    pub fn time_until_check(&self) -> Duration {
+        self.next_check.saturating_duration_since(Instant::now())
+    }
+
+    /// Checks the desk if a check is due.
+    /// Returns the new health if it changed since the last check.
+    pub fn check_if_due(&mut self) -> Option<DeskHealth> {
+        if Instant::now() < self.next_check {
+            return None
+        }
+
+        self.next_check = Instant::now() + CHECK_INTERVAL;
+
+        // A new socket hasn't sent anything to hear back about yet
+        let health = self.probe()?;
+
+        if self.health.as_ref() == Some(&health) {
+            return None
+        }
+
+        self.health = Some(health.clone());
+        Some(health)
+    }
+
+    // Works out the health from the probe sent last time, then sends the next one
+    fn probe(&mut self) -> Option<DeskHealth> {
+        let socket = match &self.socket {
+            Some(socket) => socket,
+            None => match open_probe_socket(self.app_ip, SocketAddrV4::new(self.desk_ip, self.desk_port)) {
+                Ok(socket) => {
+                    self.probe_sent = false;
+                    self.socket.insert(socket)
+                }
+                Err(reason) => return Some(DeskHealth::Down(reason)),
+            },
+        };
+
+        // Errors from the last probe are reported here, and anything the desk sent is a heartbeat
+        let mut error = None;
+        let mut buffer = [0u8; 512];
+        loop {
+            match socket.recv(&mut buffer) {
+                Ok(_) => self.last_reply = Some(Instant::now()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error = Some(describe_error(&e));
+                    break
+                }
+            }
+        }
+
+        let mut health = match error {
+            Some(reason) => Some(DeskHealth::Down(reason)),
+            None if self.last_reply.is_some_and(|last_reply| last_reply.elapsed() < REPLY_TIMEOUT) => Some(DeskHealth::Up),
+            None if self.probe_sent => Some(DeskHealth::Unknown),
+            None => None,
+        };
+
+        match socket.send(&[]) {
+            Ok(_) => self.probe_sent = true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => {
+                // Start from a fresh socket next time, the route may have changed
+                self.socket = None;
+                health = Some(DeskHealth::Down(describe_error(&e)));
+            }
+        }
+
+        health
+    }
+}
+
+fn open_probe_socket(app_ip: Ipv4Addr, desk: SocketAddrV4) -> Result<UdpSocket, String> {
+    let socket = match UdpSocket::bind((app_ip, 0)) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("can't use app IP {app_ip}: {e}")),
+    };
+
+    // Broadcast targets can't be sent to without this
+    if let Err(e) = socket.set_broadcast(true) {
+        return Err(format!("failed to set up desk probe socket: {e}"))
+    }
+
+    // Fails straight away if there is no route to the desk
+    if let Err(e) = socket.connect(desk) {
+        return Err(format!("no route to {desk} from {app_ip}: {e}"))
+    }
+
+    if let Err(e) = socket.set_nonblocking(true) {
+        return Err(format!("failed to set up desk probe socket: {e}"))
+    }
+
+    Ok(socket)
+}
+
+fn describe_error(e: &std::io::Error) -> String {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => String::from("desk is on the network but MagicQ is not listening"),
+        ErrorKind::HostUnreachable => String::from("desk is not answering on the network"),
+        ErrorKind::NetworkUnreachable => String::from("no network route to the desk"),
+        _ => e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A monitor for something listening (or not) on a local port, after its first probe has gone out
+    fn probed_monitor(port: u16) -> DeskMonitor {
+        let mut monitor = DeskMonitor::new(Ipv4Addr::LOCALHOST, Ipv4Addr::LOCALHOST, port);
+        assert_eq!(monitor.probe(), None);
+        monitor
+    }
+
+    #[test]
+    fn desk_that_answers_is_up() {
+        let desk = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut monitor = probed_monitor(desk.local_addr().unwrap().port());
+
+        let mut buffer = [0u8; 16];
+        let (_, monitor_address) = desk.recv_from(&mut buffer).unwrap();
+        desk.send_to(b"hi", monitor_address).unwrap();
+
+        assert_eq!(monitor.probe(), Some(DeskHealth::Up));
+    }
+
+    #[test]
+    fn desk_that_never_answers_is_unknown() {
+        let desk = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut monitor = probed_monitor(desk.local_addr().unwrap().port());
+
+        assert_eq!(monitor.probe(), Some(DeskHealth::Unknown));
+        assert!(!monitor.is_down());
+    }
+
+    #[test]
+    fn nothing_listening_is_down() {
+        // Bound then closed, so nothing is listening on the port
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port();
+        let mut monitor = probed_monitor(port);
+
+        assert!(matches!(monitor.probe(), Some(DeskHealth::Down(_))));
+    }
+}
@@ -0,0 +1,90 @@
+// Keeps track of what the desk should be doing, based on the MagicQ commands that have been sent.
+// Used to show the state of the playbacks, and by simulation mode in place of a real desk.
+
+use std::collections::{BTreeMap, VecDeque};
+use crate::magicq::MagicqCommand;
+
+/// How many of the most recent commands are kept
+const RECENT_COMMAND_COUNT: usize = 32;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlaybackState {
+    pub active: bool,
+
+    // Last level sent to the playback, None if no level has been sent yet
+    pub level: Option<u8>,
+}
+
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeskState {
+    playbacks: BTreeMap<u8, PlaybackState>,
+    recent_commands: VecDeque<MagicqCommand>,
+    total_commands: usize,
+}
+
+impl DeskState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the state from a command sent to the desk
+    pub fn apply_command(&mut self, command: MagicqCommand) {
+        self.total_commands += 1;
+
+        if self.recent_commands.len() == RECENT_COMMAND_COUNT {
+            self.recent_commands.pop_front();
+        }
+        self.recent_commands.push_back(command);
+
+        match command {
+            MagicqCommand::Activate(playback) => self.playbacks.entry(playback).or_default().active = true,
+            MagicqCommand::Release(playback) => self.playbacks.entry(playback).or_default().active = false,
+            MagicqCommand::Level(playback, level) => self.playbacks.entry(playback).or_default().level = Some(level),
+
+            // Go on a released playback activates it
+            MagicqCommand::Go(playback) => self.playbacks.entry(playback).or_default().active = true,
+
+            MagicqCommand::Back(_) | MagicqCommand::Stop(_) | MagicqCommand::Jump(..) => (),
+        }
+    }
+
+    pub fn playback(&self, playback: u8) -> Option<&PlaybackState> {
+        self.playbacks.get(&playback)
+    }
+
+    /// Every playback that has been sent a command, in playback order
+    pub fn playbacks(&self) -> impl Iterator<Item = (u8, &PlaybackState)> {
+        self.playbacks.iter().map(|(playback, state)| (*playback, state))
+    }
+
+    pub fn active_playbacks(&self) -> Vec<u8> {
+        self.playbacks
+            .iter()
+            .filter(|(_, state)| state.active)
+            .map(|(playback, _)| *playback)
+            .collect()
+    }
+
+    /// The most recent commands, oldest first
+    pub fn recent_commands(&self) -> impl Iterator<Item = MagicqCommand> + '_ {
+        self.recent_commands.iter().copied()
+    }
+
+    pub fn total_commands(&self) -> usize {
+        self.total_commands
+    }
+
+    /// One line summary of the active playbacks, e.g. "active: PB1, PB3"
+    pub fn summary(&self) -> String {
+        let active = self.active_playbacks();
+
+        if active.is_empty() {
+            return String::from("active: none")
+        }
+
+        let names: Vec<String> = active.iter().map(|playback| format!("PB{}", playback)).collect();
+        format!("active: {}", names.join(", "))
+    }
+}
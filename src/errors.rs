@@ -1,25 +1,195 @@
-// Standard Error Type for the program
+// Standard Error Type for the program.
+// There's a variant for each kind of failure, so library users can match on what went wrong and the CLI can suggest a fix,
+// and each keeps the error that caused it (midir, IO, TOML ...) when there is one, available through source().
+// ErrorKind is the same without the message and source, for comparing and picking a hint.
 
-pub struct ProgramError {
-    message: String,
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    // Creating the MIDI input or output client
+    MidiInit,
+
+    // There's no port, or the one asked for doesn't exist
+    PortSelection,
+
+    // Connecting to a port or creating a virtual port
+    Connection,
+
+    // Setting up the socket commands are sent from, or finding the interface to send from
+    NetworkBind,
+
+    // Sending a command to a desk
+    NetworkSend,
+
+    // The show file, command line options or other settings
+    Config,
+
+    // MIDI messages, MIDI files, recordings, notes and desk commands that can't be read
+    Parse,
+
+    // Files, the terminal and stdin
+    Io,
+}
+
+type Source = Box<dyn Error + Send + Sync>;
+
+/// What went wrong, with the error that caused it when there is one.
+/// The variants are the kinds above.
+#[derive(Debug)]
+pub enum ProgramError {
+    MidiInit { message: String, source: Option<Source> },
+    PortSelection { message: String, source: Option<Source> },
+    Connection { message: String, source: Option<Source> },
+    NetworkBind { message: String, source: Option<Source> },
+    NetworkSend { message: String, source: Option<Source> },
+    Config { message: String, source: Option<Source> },
+    Parse { message: String, source: Option<Source> },
+    Io { message: String, source: Option<Source> },
 }
 
 impl ProgramError {
-    pub fn new(message: String) -> Self {
-        Self { message }
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        Self::from_parts(kind, message, None)
+    }
+
+    /// An error caused by another one, e.g. the IO error behind failing to read a file
+    pub fn with_source<E>(kind: ErrorKind, message: String, source: E) -> Self
+    where
+        E: Into<Source>,
+    {
+        Self::from_parts(kind, message, Some(source.into()))
+    }
+
+    fn from_parts(kind: ErrorKind, message: String, source: Option<Source>) -> Self {
+        match kind {
+            ErrorKind::MidiInit => ProgramError::MidiInit { message, source },
+            ErrorKind::PortSelection => ProgramError::PortSelection { message, source },
+            ErrorKind::Connection => ProgramError::Connection { message, source },
+            ErrorKind::NetworkBind => ProgramError::NetworkBind { message, source },
+            ErrorKind::NetworkSend => ProgramError::NetworkSend { message, source },
+            ErrorKind::Config => ProgramError::Config { message, source },
+            ErrorKind::Parse => ProgramError::Parse { message, source },
+            ErrorKind::Io => ProgramError::Io { message, source },
+        }
+    }
+
+    /// The variant without its message and source, for comparing and matching on
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ProgramError::MidiInit { .. } => ErrorKind::MidiInit,
+            ProgramError::PortSelection { .. } => ErrorKind::PortSelection,
+            ProgramError::Connection { .. } => ErrorKind::Connection,
+            ProgramError::NetworkBind { .. } => ErrorKind::NetworkBind,
+            ProgramError::NetworkSend { .. } => ErrorKind::NetworkSend,
+            ProgramError::Config { .. } => ErrorKind::Config,
+            ProgramError::Parse { .. } => ErrorKind::Parse,
+            ProgramError::Io { .. } => ErrorKind::Io,
+        }
+    }
+
+    fn parts(&self) -> (&str, Option<&Source>) {
+        match self {
+            ProgramError::MidiInit { message, source }
+            | ProgramError::PortSelection { message, source }
+            | ProgramError::Connection { message, source }
+            | ProgramError::NetworkBind { message, source }
+            | ProgramError::NetworkSend { message, source }
+            | ProgramError::Config { message, source }
+            | ProgramError::Parse { message, source }
+            | ProgramError::Io { message, source } => (message, source.as_ref()),
+        }
+    }
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.parts().0)
     }
 }
 
-impl std::fmt::Display for ProgramError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+impl Error for ProgramError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.parts().1.map(|source| source.as_ref() as &(dyn Error + 'static))
     }
 }
 
-// Macro for conveniently returning a ProgramError
+// Macro for conveniently returning a ProgramError of a kind, optionally with the error that caused it:
+// return_err!(Config, "message") or return_err!(Io, format!("failed to read: {e}"), e)
 #[macro_export]
 macro_rules! return_err {
-    ($e:expr) => {
-        return Err(ProgramError::new($e.into()))
+    ($kind:ident, $message:expr) => {
+        return Err($crate::errors::ProgramError::new($crate::errors::ErrorKind::$kind, $message.into()))
     };
-}
\ No newline at end of file
+    ($kind:ident, $message:expr, $source:expr) => {
+        return Err($crate::errors::ProgramError::with_source($crate::errors::ErrorKind::$kind, $message.into(), $source))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_source() -> Result<(), ProgramError> {
+        return_err!(Config, "'midi.in_port' should be a port name")
+    }
+
+    fn with_source() -> Result<(), ProgramError> {
+        let e = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        return_err!(Io, format!("failed to read 'show.toml': {e}"), e)
+    }
+
+    #[test]
+    fn return_err_without_a_source() {
+        let e = without_source().unwrap_err();
+
+        assert!(matches!(&e, ProgramError::Config { message, source: None } if message == "'midi.in_port' should be a port name"));
+        assert_eq!(e.kind(), ErrorKind::Config);
+        assert_eq!(e.to_string(), "'midi.in_port' should be a port name");
+        assert!(e.source().is_none());
+    }
+
+    #[test]
+    fn return_err_with_a_source() {
+        let e = with_source().unwrap_err();
+
+        assert!(matches!(&e, ProgramError::Io { source: Some(_), .. }));
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert_eq!(e.to_string(), "failed to read 'show.toml': no such file");
+
+        let source = e.source().expect("the IO error is kept");
+        let io_error = source.downcast_ref::<std::io::Error>().expect("the source is the IO error");
+        assert_eq!(io_error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn every_kind_makes_its_own_variant() {
+        for kind in [
+            ErrorKind::MidiInit,
+            ErrorKind::PortSelection,
+            ErrorKind::Connection,
+            ErrorKind::NetworkBind,
+            ErrorKind::NetworkSend,
+            ErrorKind::Config,
+            ErrorKind::Parse,
+            ErrorKind::Io,
+        ] {
+            assert_eq!(ProgramError::new(kind, String::from("failed")).kind(), kind);
+            assert_eq!(ProgramError::with_source(kind, String::from("failed"), "because").kind(), kind);
+        }
+    }
+
+    #[test]
+    fn sources_can_be_followed_through_several_errors() {
+        let show = ProgramError::with_source(ErrorKind::Parse, String::from("'H3' is not a note"), "bad note name");
+        let e = ProgramError::with_source(ErrorKind::Config, String::from("invalid show file 'show.toml'"), show);
+
+        let chain: Vec<String> = std::iter::successors(Some(&e as &(dyn Error + 'static)), |e| (*e).source()).map(|e| e.to_string()).collect();
+        assert_eq!(chain, vec!["invalid show file 'show.toml'", "'H3' is not a note", "bad note name"]);
+
+        // Errors are Send and Sync, so they can be handed between threads
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&e);
+    }
+}
@@ -0,0 +1,92 @@
+// Shows what each key or control on the input would do to the desk,
+// so a controller can be set up without having the desk connected.
+// Messages go through the same translation as the Chamsys runtime, so what's shown is exactly what would be sent.
+
+use std::io::stdin;
+use std::net::Ipv4Addr;
+use color_print::cprintln;
+use crate::chamsys::{translate_midi_to_chamsys_command, AppState};
+use crate::desk_state::DeskState;
+use crate::errors::ProgramError;
+use crate::midi_io::{connect_midi_input, get_midi_input, get_midi_input_source, midi_input_source_name};
+use crate::midi_utils::{format_bytes, note_name, MiddleC, MidiMessage};
+use crate::{return_err, BankSwitching, MappingBank, MidiPortOptions, OutputMode, Setlist};
+
+/// Prints the desk command for every message received until the user presses enter
+pub fn learn_mappings(ports: &MidiPortOptions, banks: Vec<MappingBank>, bank_switching: BankSwitching, setlist: Setlist, middle_c: MiddleC) -> Result<(), ProgramError> {
+    cprintln!("\n<green>LEARNING MIDI CONTROLS</>");
+
+    let midi_in = get_midi_input()?;
+    let input_source = get_midi_input_source(&midi_in, ports)?;
+    let port_name = midi_input_source_name(&midi_in, &input_source);
+
+    // Nothing is sent, so no desks or network are needed
+    let mut state = AppState::new(Vec::new(), Ipv4Addr::UNSPECIFIED, OutputMode::Simulation);
+    state.set_banks(banks, bank_switching);
+    state.set_setlist(setlist);
+    print_bank(state.active_bank(), middle_c);
+    let mut desk_state = DeskState::new();
+
+    let _conn_in = connect_midi_input(midi_in, input_source, move |_, message, _| {
+        // Messages that can't be parsed aren't worth showing
+        let Ok(parsed) = MidiMessage::parse(message) else {
+            return
+        };
+
+        // Clock etc. would drown out the keys and controls
+        if parsed.is_realtime() {
+            return
+        }
+
+        let note = match parsed {
+            MidiMessage::NoteOn { note, .. } | MidiMessage::NoteOff { note, .. } => note_name(note, middle_c),
+            _ => String::new(),
+        };
+
+        // Song commands aren't shown, only where the setlist goes
+        if let Some(step) = state.song_step_for(&parsed) {
+            match state.song_after_step(step) {
+                Some(song) => {
+                    let song = state.enter_song(song).clone();
+                    println!("{:<12} {:<5} -> starts song '{}'", format_bytes(message), note, song.name);
+
+                    if let Some(bank) = song.bank.as_deref().and_then(|name| state.bank_index(name)) {
+                        state.switch_bank(bank);
+                        print_bank(state.active_bank(), middle_c);
+                    }
+                }
+                None => println!("{:<12} {:<5} -> steps through the setlist", format_bytes(message), note),
+            }
+            return
+        }
+
+        if let Some(bank) = state.bank_switch_for(&parsed) {
+            state.switch_bank(bank);
+            println!("{:<12} {:<5} -> switches bank", format_bytes(message), note);
+            print_bank(state.active_bank(), middle_c);
+            return
+        }
+
+        match translate_midi_to_chamsys_command(&parsed, &mut state) {
+            Some(cmd) => {
+                desk_state.apply_command(cmd);
+                println!("{:<12} {:<5} -> {:<10} {}", format_bytes(message), note, cmd, cmd.description());
+            }
+            None => println!("{:<12} {:<5} -> nothing", format_bytes(message), note),
+        }
+    })?;
+
+    println!("Listening to '{}', press keys or move controls to see what they do (press enter to stop) ...", port_name);
+
+    let mut input = String::new();
+    match stdin().read_line(&mut input) {
+        Ok(_) => (),
+        Err(e) => return_err!(Io, format!("failed to read line from stdin: {e}"), e),
+    }
+
+    Ok(())
+}
+
+fn print_bank(bank: &MappingBank, middle_c: MiddleC) {
+    cprintln!("<bold>Bank '{}'</>: PB{} is {}", bank.name, bank.first_playback, note_name(bank.first_playback_note, middle_c));
+}
@@ -1,72 +1,630 @@
+// The crate is split into features so the protocol code builds without MIDI hardware support (and ALSA):
+// midir-io  hardware and virtual MIDI ports, recording from them
+// jack      MIDI ports through JACK instead of ALSA or CoreMIDI (turns on midir-io)
+// chamsys   MIDI to MagicQ translation, the runtime, show files
+// organ     organ stop SysEx
+// serde     Serialize and Deserialize for the public types
+// api       the HTTP and WebSocket control API (turns on chamsys and serde)
+// cli       the midi_lx program, dashboard, playing files to ports, monitor (turns on midir-io, chamsys, organ and api)
+// MIDI parsing, MIDI files, recordings, the MIDI file player, port selectors and errors are always available.
+
+#[cfg(feature = "chamsys")]
 use std::collections::HashMap;
+#[cfg(feature = "chamsys")]
 use std::net::Ipv4Addr;
-use std::sync::mpsc;
-use color_print::ceprintln;
-use crate::chamsys::{start_midi_to_chamsys_runtime, AppEvent, AppState};
+#[cfg(feature = "chamsys")]
+use std::sync::{mpsc, Arc};
+#[cfg(feature = "chamsys")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "chamsys")]
+use color_print::cprintln;
+#[cfg(all(feature = "organ", feature = "midir-io"))]
+use log::error;
+#[cfg(feature = "chamsys")]
+use crate::chamsys::{start_chamsys_runtime_without_input, AppEvent};
+#[cfg(all(feature = "chamsys", feature = "midir-io"))]
+use crate::chamsys::start_midi_to_chamsys_runtime;
+#[cfg(feature = "chamsys")]
+use crate::desk_state::DeskState;
+#[cfg(feature = "chamsys")]
 use crate::errors::ProgramError;
+#[cfg(feature = "chamsys")]
+use crate::magicq::MagicqCommand;
+#[cfg(feature = "chamsys")]
+use crate::midi_queue::MidiQueue;
+#[cfg(feature = "midir-io")]
+use crate::port_selector::PortSelector;
+#[cfg(all(feature = "organ", feature = "midir-io"))]
 use crate::organ::organ_midi::play_organ;
+#[cfg(all(feature = "chamsys", feature = "midir-io"))]
+use crate::recorder::RecorderHandle;
+#[cfg(feature = "chamsys")]
+use crate::stats::{print_stats, RuntimeStats};
+#[cfg(all(feature = "chamsys", feature = "midir-io"))]
+use crate::supervisor::PortSupervisor;
+
+#[cfg(feature = "chamsys")]
+pub use crate::chamsys::{translate_midi_to_chamsys_command, AppState, DEFAULT_FIRST_PLAYBACK_NOTE};
 
 pub mod errors;
-mod midi_io;
-mod midi_utils;
-mod test;
+pub mod logging;
+pub mod midi_utils;
+pub mod player;
+pub mod port_selector;
+pub mod recorder;
+pub mod smf;
+
+#[cfg(feature = "chamsys")]
+pub mod desk_state;
+#[cfg(feature = "chamsys")]
+pub mod magicq;
+#[cfg(feature = "chamsys")]
+pub mod midi_queue;
+#[cfg(feature = "chamsys")]
+pub mod network;
+#[cfg(feature = "chamsys")]
+pub mod show;
+#[cfg(feature = "chamsys")]
+pub mod stats;
+#[cfg(feature = "chamsys")]
+mod desk_monitor;
+#[cfg(feature = "chamsys")]
 mod chamsys;
 
-mod organ {
+#[cfg(feature = "midir-io")]
+pub mod midi_io;
+#[cfg(all(feature = "chamsys", feature = "midir-io"))]
+mod supervisor;
+
+#[cfg(feature = "organ")]
+pub mod organ {
     pub mod stops_table;
     pub mod organ_midi;
 }
+
+#[cfg(feature = "cli")]
 pub mod cli;
+#[cfg(feature = "api")]
+pub mod api;
+#[cfg(feature = "cli")]
+pub mod monitor;
+#[cfg(feature = "cli")]
+pub mod tui;
+#[cfg(feature = "cli")]
+mod learn;
 
-#[derive(Clone)]
+#[cfg(feature = "chamsys")]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum LxCommand {
     Activate,
     Deactivate,
     Intensity,
 }
 
+#[cfg(feature = "chamsys")]
+impl LxCommand {
+    /// Reads a command written as "activate", "deactivate" or "intensity"
+    pub fn parse(text: &str) -> Result<LxCommand, ProgramError> {
+        match text {
+            "activate" => Ok(LxCommand::Activate),
+            "deactivate" => Ok(LxCommand::Deactivate),
+            "intensity" => Ok(LxCommand::Intensity),
+            _ => return_err!(Parse, format!("'{text}' is not a command, should be activate, deactivate or intensity")),
+        }
+    }
+}
+
+#[cfg(feature = "chamsys")]
+/// Name of the bank used when no banks are set up
+pub const DEFAULT_BANK_NAME: &str = "Default";
+
+#[cfg(feature = "chamsys")]
+/// A set of mappings that can be switched to during a show, e.g. one for each song
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MappingBank {
+    pub name: String,
+
+    // Program change that switches to this bank, if any
+    pub program: Option<u8>,
+
+    // The note that controls first_playback, the notes above it control the playbacks after it
+    pub first_playback_note: u8,
+    pub first_playback: u8,
+
+    pub mappings: HashMap<usize, LxCommand>,
+}
+
+#[cfg(feature = "chamsys")]
+impl MappingBank {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            program: None,
+            first_playback_note: DEFAULT_FIRST_PLAYBACK_NOTE,
+            first_playback: 1,
+            mappings: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(feature = "chamsys")]
+/// How banks are switched from MIDI.
+/// Program changes switch to the bank with that program, the rest are optional.
+/// Messages that switch banks aren't translated into commands.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BankSwitching {
+    // Only messages on this channel switch banks, any channel if None
+    pub channel: Option<u8>,
+
+    pub next_note: Option<u8>,
+    pub previous_note: Option<u8>,
+
+    // CC whose value picks the bank by its position (0 is the first bank)
+    pub select_cc: Option<u8>,
+
+    // Release the playbacks activated from the old bank when switching
+    pub release_on_switch: bool,
+}
+
+#[cfg(feature = "chamsys")]
+/// A song in the setlist
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Song {
+    pub name: String,
+
+    // Bank switched to when the song starts, the bank is left alone if None
+    pub bank: Option<String>,
+
+    // Beats per minute, shown to whoever is running the show
+    pub tempo: Option<f64>,
+
+    // MagicQ commands sent when the song starts, e.g. "1G" or "2,100L"
+    pub commands: Vec<MagicqCommand>,
+}
+
+#[cfg(feature = "chamsys")]
+impl Song {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            bank: None,
+            tempo: None,
+            commands: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "chamsys")]
+/// The songs of a gig in order, and how to step through them from MIDI.
+/// The setlist starts before the first song, so the first next enters it.
+/// Messages that step through the setlist aren't translated into commands.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Setlist {
+    pub songs: Vec<Song>,
+
+    // Only messages on this channel step through the setlist, any channel if None
+    pub channel: Option<u8>,
+
+    pub next_note: Option<u8>,
+    pub previous_note: Option<u8>,
+
+    // A footswitch or button CC, pressing it (a value of 64 or more) goes to the next song
+    pub next_cc: Option<u8>,
+}
+
+#[cfg(feature = "chamsys")]
+/// Where translated commands go
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OutputMode {
+    // Sent to the desk over UDP
+    Network,
+
+    // Nothing is sent, commands are traced and applied to a simulated desk instead
+    Simulation,
+}
+
+#[cfg(all(feature = "organ", feature = "midir-io"))]
 pub fn organ_control() {
-    match play_organ(false) {
+    match play_organ(false, &MidiPortOptions::default(), None) {
         Ok(_) => (),
-        Err(e) => {
-            ceprintln!("<red>{}</>", e)
-        },
+        Err(e) => error!("{}", e),
     }
 }
 
+#[cfg(feature = "chamsys")]
+/// Prints what the simulated desk ended up doing, if the runtime is in simulation mode
+pub fn print_simulation_summary(runtime: &MidiRuntime) {
+    if runtime.output_mode != OutputMode::Simulation {
+        return
+    }
+
+    if let Some(desk_state) = runtime.desk_state() {
+        cprintln!("\n<yellow, bold>Simulation finished</>");
+        println!("Commands sent: {}", desk_state.total_commands());
+
+        for (playback, state) in desk_state.playbacks() {
+            let level = match state.level {
+                Some(level) => level.to_string(),
+                None => String::from("-"),
+            };
+
+            println!("PB{:<4} {:<10} level {}", playback, if state.active { "active" } else { "released" }, level);
+        }
+    }
+}
+
+#[cfg(feature = "chamsys")]
+/// Prints the runtime's counters and latencies, e.g. when it's about to stop
+pub fn print_runtime_stats(runtime: &MidiRuntime) {
+    if let Some(stats) = runtime.stats() {
+        print_stats(&stats);
+    }
+}
+
+#[cfg(feature = "chamsys")]
+/// Things that happen while the runtime is running, for front ends to show
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "event", content = "data", rename_all = "snake_case"))]
+pub enum RuntimeEvent {
+    MidiInputDisconnected(String),
+    MidiInputReconnected(String),
+
+    // The desk started or stopped answering, with the reason it can't be reached.
+    // Unknown is when nothing has failed but nothing comes back either (MagicQ doesn't answer, nor do broadcast targets)
+    DeskReachable(Ipv4Addr),
+    DeskReachabilityUnknown(Ipv4Addr),
+    DeskUnreachable(Ipv4Addr, String),
+
+    // The desks commands are being sent to changed, e.g. after failing over to a backup
+    ActiveDesksChanged(Vec<Ipv4Addr>),
+
+    // Switched to the bank with this name
+    BankChanged(String),
+
+    // Started the song at this position in the setlist (from 0)
+    SongChanged(usize, Song),
+
+    // A command was sent (or simulated)
+    CommandSent(MagicqCommand),
+
+    // A command couldn't be sent to this desk
+    CommandFailed(Ipv4Addr, String),
+
+    // Counters and latencies so far, sent every few seconds
+    Stats(Box<RuntimeStats>),
+}
+
+#[cfg(feature = "chamsys")]
+/// Somewhere commands are sent to
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeskTarget {
+    // A single desk, a subnet broadcast address (e.g. 2.255.255.255) or a multicast group
+    pub address: Ipv4Addr,
+
+    pub enabled: bool,
+    pub role: DeskRole,
+}
+
+#[cfg(feature = "chamsys")]
+impl DeskTarget {
+    pub fn new(address: Ipv4Addr) -> Self {
+        Self {
+            address,
+            enabled: true,
+            role: DeskRole::Main,
+        }
+    }
+
+    pub fn backup(address: Ipv4Addr) -> Self {
+        Self {
+            role: DeskRole::Backup,
+            ..Self::new(address)
+        }
+    }
+}
+
+#[cfg(feature = "chamsys")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum DeskRole {
+    // Always sent every command (e.g. the main desk or a desk running video)
+    Main,
+
+    // Only sent commands while none of the main desks can be reached.
+    // If there are several backups, the first one that can be reached is used.
+    Backup,
+}
+
+#[cfg(feature = "midir-io")]
+/// Where the runtime's MIDI input comes from
+pub enum MidiInputSource {
+    // An existing port, e.g. a USB controller
+    Port(midir::MidiInputPort),
+
+    // A new virtual port with this name that other programs (e.g. a DAW) can connect to.
+    // Only available with the ALSA sequencer and CoreMIDI.
+    Virtual(String),
+}
+
+#[cfg(feature = "midir-io")]
+/// Which MIDI ports to use.
+/// Ports that aren't selected or virtual are chosen by the user when there is more than one.
+#[derive(Clone, Debug, Default)]
+pub struct MidiPortOptions {
+    pub input: Option<PortSelector>,
+    pub output: Option<PortSelector>,
+
+    // Names for virtual ports to create instead of opening existing hardware ports
+    pub virtual_input: Option<String>,
+    pub virtual_output: Option<String>,
+
+    // Ports the virtual output is connected to when it's created, by name (JACK only)
+    pub connect_output: Option<regex::Regex>,
+}
+
+#[cfg(feature = "chamsys")]
 pub struct MidiRuntime {
     tx: mpsc::Sender<AppEvent>,
+    queue: Arc<MidiQueue>,
+    output_mode: OutputMode,
+
+    // MIDI input stays open for as long as the runtime exists.
+    // Hardware ports are held by the supervisor so they can be reconnected, virtual ports are held directly.
+    // Both are None when the runtime is only fed by send_midi (e.g. playing a MIDI file)
+    #[cfg(feature = "midir-io")]
+    _midi_connection: Option<midir::MidiInputConnection<()>>,
+    #[cfg(feature = "midir-io")]
+    _port_supervisor: Option<PortSupervisor>,
 }
 
+#[cfg(feature = "chamsys")]
 impl MidiRuntime {
 
+    #[cfg(feature = "midir-io")]
     pub fn create(
-        desk_ip: Ipv4Addr,
+        desk_targets: Vec<DeskTarget>,
         app_ip: Ipv4Addr,
+        output_mode: OutputMode,
         midi_input: midir::MidiInput,
-        selected_midi_port: midir::MidiInputPort,
+        input_source: MidiInputSource,
         midi_through: Option<midir::MidiOutputConnection>,
+        recorder: Option<RecorderHandle>,
     ) -> Result<MidiRuntime, ProgramError> {
 
         start_midi_to_chamsys_runtime(
-            AppState::new(desk_ip, app_ip),
+            AppState::new(desk_targets, app_ip, output_mode),
             midi_input,
-            selected_midi_port,
+            input_source,
             midi_through,
+            recorder,
         )
     }
 
+    /// Creates a runtime with no MIDI input port, messages are fed in with send_midi instead.
+    /// Like create, it fails with NetworkBind if the socket commands are sent from can't be opened.
+    pub fn create_without_input(desk_targets: Vec<DeskTarget>, app_ip: Ipv4Addr, output_mode: OutputMode) -> Result<MidiRuntime, ProgramError> {
+        start_chamsys_runtime_without_input(AppState::new(desk_targets, app_ip, output_mode))
+    }
+
+    /// A handle for controlling the runtime from other threads (e.g. the control API)
+    pub fn handle(&self) -> RuntimeHandle {
+        RuntimeHandle {
+            tx: self.tx.clone(),
+            queue: Arc::clone(&self.queue),
+        }
+    }
+
+    /// Feeds a MIDI message into the runtime as if it came from the input port
+    pub fn send_midi(&self, message: &[u8]) {
+        self.handle().send_midi(message);
+    }
+
+    /// Returns a function that feeds MIDI messages into the runtime from another thread
+    pub fn midi_feed(&self) -> impl FnMut(&[u8]) + Send + 'static + use<> {
+        self.handle().midi_feed()
+    }
+
+    /// Replaces the mappings of the active bank
     pub fn update_mappings(&self, mappings: HashMap<usize, LxCommand>) {
-        let _ = self.tx.send(AppEvent::UpdateMappings(mappings));
+        self.handle().update_mappings(mappings);
+    }
+
+    /// Replaces the mapping banks, starting from the first one
+    pub fn set_banks(&self, banks: Vec<MappingBank>, switching: BankSwitching) {
+        self.handle().set_banks(banks, switching);
+    }
+
+    /// Switches to the bank with this name
+    pub fn select_bank(&self, name: &str) {
+        self.handle().select_bank(name);
+    }
+
+    /// Replaces the setlist, staying on the current song if it's still in it
+    pub fn set_setlist(&self, setlist: Setlist) {
+        self.handle().set_setlist(setlist);
+    }
+
+    /// Starts the next song in the setlist
+    pub fn next_song(&self) {
+        self.handle().next_song();
+    }
+
+    /// Starts the previous song in the setlist
+    pub fn previous_song(&self) {
+        self.handle().previous_song();
+    }
+
+    /// Starts the song with this name
+    pub fn select_song(&self, name: &str) {
+        self.handle().select_song(name);
     }
 
+    /// Sends everything to a single desk, replacing all the desk targets
     pub fn set_desk_ip(&self, ip: Ipv4Addr) {
-        let _ = self.tx.send(AppEvent::SetDeskIp(ip));
+        self.handle().set_desk_ip(ip);
+    }
+
+    pub fn set_desk_targets(&self, desk_targets: Vec<DeskTarget>) {
+        self.handle().set_desk_targets(desk_targets);
+    }
+
+    /// Turns sending to a desk target on or off without removing it
+    pub fn set_desk_target_enabled(&self, address: Ipv4Addr, enabled: bool) {
+        self.handle().set_desk_target_enabled(address, enabled);
+    }
+
+    /// Releases every playback that is currently active
+    pub fn blackout(&self) {
+        self.handle().blackout();
+    }
+
+    /// What the desk should currently be doing, based on the commands sent so far.
+    /// Returns None if the runtime has stopped.
+    pub fn desk_state(&self) -> Option<DeskState> {
+        self.handle().desk_state()
+    }
+
+    /// Counters and latencies since the runtime started.
+    /// Returns None if the runtime has stopped.
+    pub fn stats(&self) -> Option<RuntimeStats> {
+        self.handle().stats()
+    }
+
+    /// Receives every runtime event from now on, until the runtime stops
+    pub fn subscribe(&self) -> mpsc::Receiver<RuntimeEvent> {
+        self.handle().subscribe()
     }
 
     pub fn stop(&self) {
-        let _ = self.tx.send(AppEvent::Stop);
+        self.handle().stop();
     }
 }
 
+#[cfg(feature = "chamsys")]
+/// Controls a running MidiRuntime. Can be cloned and sent to other threads.
+/// Everything does nothing once the runtime has stopped.
+#[derive(Clone)]
+pub struct RuntimeHandle {
+    tx: mpsc::Sender<AppEvent>,
+    queue: Arc<MidiQueue>,
+}
+
+#[cfg(feature = "chamsys")]
+impl RuntimeHandle {
+    /// Feeds a MIDI message into the runtime as if it came from the input port.
+    /// Short messages go on the queue, anything longer (SysEx) goes through the event channel.
+    pub fn send_midi(&self, message: &[u8]) {
+        let received = Instant::now();
+
+        if !self.queue.push(message, received) {
+            self.send(AppEvent::Midi(message.to_vec(), received));
+        }
+    }
+
+    /// Returns a function that feeds MIDI messages into the runtime from another thread
+    pub fn midi_feed(&self) -> impl FnMut(&[u8]) + Send + 'static + use<> {
+        let handle = self.clone();
+
+        move |message: &[u8]| {
+            handle.send_midi(message);
+        }
+    }
+
+    /// Replaces the mappings of the active bank
+    pub fn update_mappings(&self, mappings: HashMap<usize, LxCommand>) {
+        self.send(AppEvent::UpdateMappings(mappings));
+    }
+
+    /// Replaces the mapping banks, starting from the first one
+    pub fn set_banks(&self, banks: Vec<MappingBank>, switching: BankSwitching) {
+        self.send(AppEvent::SetBanks(banks, switching));
+    }
+
+    /// Switches to the bank with this name
+    pub fn select_bank(&self, name: &str) {
+        self.send(AppEvent::SelectBank(name.to_owned()));
+    }
+
+    /// Replaces the setlist, staying on the current song if it's still in it
+    pub fn set_setlist(&self, setlist: Setlist) {
+        self.send(AppEvent::SetSetlist(setlist));
+    }
+
+    /// Starts the next song in the setlist
+    pub fn next_song(&self) {
+        self.send(AppEvent::StepSong(1));
+    }
+
+    /// Starts the previous song in the setlist
+    pub fn previous_song(&self) {
+        self.send(AppEvent::StepSong(-1));
+    }
+
+    /// Starts the song with this name
+    pub fn select_song(&self, name: &str) {
+        self.send(AppEvent::SelectSong(name.to_owned()));
+    }
+
+    /// Sends everything to a single desk, replacing all the desk targets
+    pub fn set_desk_ip(&self, ip: Ipv4Addr) {
+        self.send(AppEvent::SetDeskTargets(vec![DeskTarget::new(ip)]));
+    }
+
+    pub fn set_desk_targets(&self, desk_targets: Vec<DeskTarget>) {
+        self.send(AppEvent::SetDeskTargets(desk_targets));
+    }
+
+    /// Turns sending to a desk target on or off without removing it
+    pub fn set_desk_target_enabled(&self, address: Ipv4Addr, enabled: bool) {
+        self.send(AppEvent::SetDeskTargetEnabled(address, enabled));
+    }
+
+    /// Releases every playback that is currently active
+    pub fn blackout(&self) {
+        self.send(AppEvent::Blackout);
+    }
+
+    /// What the desk should currently be doing, based on the commands sent so far.
+    /// Returns None if the runtime has stopped.
+    pub fn desk_state(&self) -> Option<DeskState> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.send(AppEvent::GetDeskState(reply_tx));
+        reply_rx.recv_timeout(Duration::from_secs(1)).ok()
+    }
 
+    /// Counters and latencies since the runtime started.
+    /// Returns None if the runtime has stopped.
+    pub fn stats(&self) -> Option<RuntimeStats> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.send(AppEvent::GetStats(reply_tx));
+        reply_rx.recv_timeout(Duration::from_secs(1)).ok()
+    }
+
+    /// Receives every runtime event from now on, until the runtime stops
+    pub fn subscribe(&self) -> mpsc::Receiver<RuntimeEvent> {
+        let (event_tx, event_rx) = mpsc::channel();
+        self.send(AppEvent::Subscribe(event_tx));
+        event_rx
+    }
+
+    pub fn stop(&self) {
+        self.send(AppEvent::Stop);
+    }
+
+    // Events go through the channel, then the event loop is woken to pick them up
+    fn send(&self, event: AppEvent) {
+        let _ = self.tx.send(event);
+        self.queue.wake();
+    }
+}
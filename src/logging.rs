@@ -0,0 +1,412 @@
+// Logging for the whole program.
+// Every subsystem logs through the `log` macros, so each record carries the module it came from as its target
+// (midilx::chamsys, midilx::organ::organ_midi, midilx::midi_io ...).
+// Records are written to stderr and, if a log directory is set, to a log file for the session
+// so what happened during a show can be pieced together afterwards.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{DateTime, Local};
+use color_print::cformat;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::errors::ProgramError;
+use crate::return_err;
+
+/// Log files start with this, followed by the time the session started
+const SESSION_FILE_PREFIX: &str = "midilx-session-";
+
+pub struct LogConfig {
+    pub level: LevelFilter,
+
+    // Write records as one JSON object per line instead of plain text
+    pub json: bool,
+
+    // Write records to stderr. Turned off when something else is drawing on the terminal (e.g. the dashboard)
+    pub console: bool,
+
+    // Where session log files go. No session file is written if this is None
+    pub log_dir: Option<PathBuf>,
+
+    // Once a session file gets this big, the session continues in a new file
+    pub max_file_size: u64,
+
+    // How many log files to keep in the log directory before deleting the oldest ones
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            json: false,
+            console: true,
+            log_dir: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 20,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Reads the logging setup from the environment.
+    /// MIDILX_LOG sets the level (error, warn, info, debug, trace),
+    /// MIDILX_LOG_JSON=1 switches to JSON lines and MIDILX_LOG_DIR sets the session log directory.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Ok(level) = std::env::var("MIDILX_LOG")
+            && let Ok(level) = level.parse::<LevelFilter>()
+        {
+            config.level = level;
+        }
+
+        if let Ok(json) = std::env::var("MIDILX_LOG_JSON") {
+            config.json = json == "1" || json.eq_ignore_ascii_case("true");
+        }
+
+        if let Ok(dir) = std::env::var("MIDILX_LOG_DIR") {
+            config.log_dir = Some(PathBuf::from(dir));
+        }
+
+        config
+    }
+}
+
+/// Sets up the global logger. Can only be called once per program run.
+pub fn init_logging(config: LogConfig) -> Result<(), ProgramError> {
+    let session_file = match &config.log_dir {
+        Some(dir) => Some(Mutex::new(SessionFile::create(dir, config.max_file_size, config.max_files)?)),
+        None => None,
+    };
+
+    let logger = Logger {
+        level: config.level,
+        json: config.json,
+        console: config.console,
+        session_file,
+    };
+
+    match log::set_boxed_logger(Box::new(logger)) {
+        Ok(_) => (),
+        Err(e) => return_err!(Config, format!("failed to set up logging: {e}"), e),
+    }
+
+    log::set_max_level(config.level);
+
+    Ok(())
+}
+
+struct Logger {
+    level: LevelFilter,
+    json: bool,
+    console: bool,
+    session_file: Option<Mutex<SessionFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+
+        let time = Local::now();
+
+        let line = if self.json {
+            format_json(&time, record)
+        } else {
+            format_plain(&time, record)
+        };
+
+        // The console gets a shorter, coloured version unless JSON was asked for
+        if self.console {
+            if self.json {
+                eprintln!("{}", line);
+            } else {
+                eprintln!("{}", format_console(&time, record));
+            }
+        }
+
+        if let Some(session_file) = &self.session_file
+            && let Ok(mut session_file) = session_file.lock()
+        {
+            session_file.write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(session_file) = &self.session_file
+            && let Ok(mut session_file) = session_file.lock()
+        {
+            let _ = session_file.file.flush();
+        }
+    }
+}
+
+fn format_console(time: &DateTime<Local>, record: &Record) -> String {
+    let time = time.format("%H:%M:%S%.3f");
+    let target = record.target();
+    let message = record.args().to_string();
+
+    match record.level() {
+        Level::Error => cformat!("<dim>{}</> <red, bold>ERROR</> <dim>{}</> <red>{}</>", time, target, message),
+        Level::Warn => cformat!("<dim>{}</> <yellow, bold>WARN </> <dim>{}</> {}", time, target, message),
+        Level::Info => cformat!("<dim>{}</> <green>INFO </> <dim>{}</> {}", time, target, message),
+        Level::Debug => cformat!("<dim>{}</> <blue>DEBUG</> <dim>{}</> {}", time, target, message),
+        Level::Trace => cformat!("<dim>{}</> <magenta>TRACE</> <dim>{}</> {}", time, target, message),
+    }
+}
+
+fn format_plain(time: &DateTime<Local>, record: &Record) -> String {
+    format!(
+        "{} {:<5} {} {}",
+        time.to_rfc3339_opts(chrono::SecondsFormat::Micros, false),
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+fn format_json(time: &DateTime<Local>, record: &Record) -> String {
+    format!(
+        "{{\"time\":\"{}\",\"level\":\"{}\",\"target\":\"{}\",\"message\":\"{}\"}}",
+        time.to_rfc3339_opts(chrono::SecondsFormat::Micros, false),
+        record.level(),
+        escape_json(record.target()),
+        escape_json(&record.args().to_string())
+    )
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// The log file for this run of the program.
+// When it reaches max_size the session carries on in a new numbered file,
+// so a long show never produces one enormous file.
+struct SessionFile {
+    dir: PathBuf,
+    session_name: String,
+    file: File,
+    written: u64,
+    max_size: u64,
+    max_files: usize,
+    part: u32,
+}
+
+impl SessionFile {
+    fn create(dir: &Path, max_size: u64, max_files: usize) -> Result<Self, ProgramError> {
+        match fs::create_dir_all(dir) {
+            Ok(_) => (),
+            Err(e) => return_err!(Io, format!("failed to create log directory '{}': {e}", dir.display()), e),
+        }
+
+        remove_old_sessions(dir, max_files);
+
+        let session_name = format!("{}{}", SESSION_FILE_PREFIX, Local::now().format("%Y%m%d-%H%M%S"));
+        let file = open_log_file(&dir.join(format!("{session_name}.log")))?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            session_name,
+            file,
+            written: 0,
+            max_size,
+            max_files,
+            part: 0,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.written + line.len() as u64 > self.max_size && self.written > 0 {
+            self.rotate();
+        }
+
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.written += line.len() as u64 + 1;
+        }
+    }
+
+    fn rotate(&mut self) {
+        // A long session would otherwise fill the directory between runs of the program.
+        // The file being rotated away from is the newest, so it's only removed if max_files is 1.
+        remove_old_sessions(&self.dir, self.max_files);
+
+        self.part += 1;
+        let path = self.dir.join(format!("{}.{}.log", self.session_name, self.part));
+
+        // If the new file can't be opened, just keep writing to the current one
+        if let Ok(file) = open_log_file(&path) {
+            let _ = self.file.flush();
+            self.file = file;
+            self.written = 0;
+        }
+    }
+}
+
+fn open_log_file(path: &Path) -> Result<File, ProgramError> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(f) => Ok(f),
+        Err(e) => return_err!(Io, format!("failed to open log file '{}': {e}", path.display()), e),
+    }
+}
+
+// Deletes the oldest session log files so only the newest (max_files - 1) remain,
+// leaving room for the session or part that is about to start.
+fn remove_old_sessions(dir: &Path, max_files: usize) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let mut session_files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(SESSION_FILE_PREFIX))
+        })
+        .collect();
+
+    if session_files.len() < max_files {
+        return
+    }
+
+    session_files.sort_by_cached_key(|path| session_file_age(path));
+
+    let remove_count = session_files.len() + 1 - max_files.max(1);
+    for path in session_files.iter().take(remove_count) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// File names start with the session start time followed by the part number, if it isn't the first part
+// (midilx-session-20250101-200000.log, midilx-session-20250101-200000.1.log ...).
+// Sorting by name alone would put part 10 before part 2, and every part before the first.
+fn session_file_age(path: &Path) -> (String, u32) {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let name = name.strip_suffix(".log").unwrap_or(name);
+
+    match name.rsplit_once('.').and_then(|(session_name, part)| Some((session_name, part.parse().ok()?))) {
+        Some((session_name, part)) => (session_name.to_string(), part),
+        None => (name.to_string(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("midilx-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+
+        names.sort();
+        names
+    }
+
+    fn json_line(target: &str, message: &str) -> serde_json::Value {
+        let line = format_json(
+            &Local::now(),
+            &Record::builder().args(format_args!("{}", message)).level(Level::Warn).target(target).build(),
+        );
+
+        serde_json::from_str(&line).unwrap_or_else(|e| panic!("{line} isn't valid JSON: {e}"))
+    }
+
+    #[test]
+    fn json_escapes_quotes_backslashes_and_control_characters() {
+        assert_eq!(escape_json(r#"port "MIDI 1""#), r#"port \"MIDI 1\""#);
+        assert_eq!(escape_json(r"C:\shows\"), r"C:\\shows\\");
+        assert_eq!(escape_json("a\nb\rc\td"), r"a\nb\rc\td");
+        assert_eq!(escape_json("\u{0}\u{1}\u{1b}\u{1f}"), r"\u0000\u0001\u001b\u001f");
+
+        // Everything else, including DEL and non-ASCII, is fine in a JSON string as it is
+        assert_eq!(escape_json("\u{7f} é ♪"), "\u{7f} é ♪");
+    }
+
+    #[test]
+    fn json_lines_parse_back_to_what_was_logged() {
+        let message = "desk \"main\"\n\tC:\\shows\u{1}\u{1f} é ♪";
+        let line = json_line("midilx::chamsys", message);
+
+        assert_eq!(line["message"], message);
+        assert_eq!(line["target"], "midilx::chamsys");
+        assert_eq!(line["level"], "WARN");
+        assert!(line["time"].is_string());
+    }
+
+    #[test]
+    fn session_files_sort_by_start_time_then_part() {
+        let mut names = vec![
+            "midilx-session-20250102-090000.log",
+            "midilx-session-20250101-200000.10.log",
+            "midilx-session-20250101-200000.2.log",
+            "midilx-session-20250101-200000.log",
+            "midilx-session-20250101-200000.1.log",
+        ];
+
+        names.sort_by_cached_key(|name| session_file_age(Path::new(name)));
+
+        assert_eq!(names, [
+            "midilx-session-20250101-200000.log",
+            "midilx-session-20250101-200000.1.log",
+            "midilx-session-20250101-200000.2.log",
+            "midilx-session-20250101-200000.10.log",
+            "midilx-session-20250102-090000.log",
+        ]);
+    }
+
+    #[test]
+    fn rotating_keeps_at_most_max_files() {
+        let dir = log_dir("rotating");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("midilx-session-20000101-000000.log"), "old session\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a log file\n").unwrap();
+
+        let mut session_file = SessionFile::create(&dir, 100, 3).unwrap();
+        let session_name = session_file.session_name.clone();
+
+        for _ in 0..20 {
+            session_file.write_line(&"x".repeat(60));
+        }
+
+        assert_eq!(session_file.part, 19);
+        assert_eq!(file_names(&dir), [
+            format!("{session_name}.17.log"),
+            format!("{session_name}.18.log"),
+            format!("{session_name}.19.log"),
+            "notes.txt".to_string(),
+        ]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,159 @@
+// MagicQ remote commands, as sent to the desk over UDP in rx (no header) mode, e.g. "3A", "3,100L".
+// Commands stay typed until they're sent, then they're written into a buffer on the stack,
+// so translating and sending a message doesn't allocate.
+
+use std::fmt::{self, Write};
+use crate::errors::ProgramError;
+use crate::return_err;
+
+/// Longer than the longest command, "255,65535J"
+const MAX_COMMAND_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MagicqCommand {
+    Activate(u8),
+    Release(u8),
+
+    // Playback and level
+    Level(u8, u8),
+
+    Go(u8),
+    Back(u8),
+    Stop(u8),
+
+    // Playback and cue number
+    Jump(u8, u16),
+}
+
+impl MagicqCommand {
+    /// Reads a command written the way it's sent, e.g. "3A", "3R", "3,100L", "3G", "3,5J"
+    pub fn parse(text: &str) -> Result<MagicqCommand, ProgramError> {
+        match parse_command(text.trim()) {
+            Some(command) => Ok(command),
+            None => return_err!(Parse, format!("'{text}' is not a MagicQ command, e.g. \"3A\", \"3R\", \"3,100L\", \"3G\", \"3B\", \"3S\" or \"3,5J\"")),
+        }
+    }
+
+    pub fn playback(&self) -> u8 {
+        match self {
+            MagicqCommand::Activate(playback)
+            | MagicqCommand::Release(playback)
+            | MagicqCommand::Level(playback, _)
+            | MagicqCommand::Go(playback)
+            | MagicqCommand::Back(playback)
+            | MagicqCommand::Stop(playback)
+            | MagicqCommand::Jump(playback, _) => *playback,
+        }
+    }
+
+    /// The command as sent to the desk
+    pub fn encode(&self) -> EncodedCommand {
+        let mut encoded = EncodedCommand {
+            bytes: [0; MAX_COMMAND_LENGTH],
+            len: 0,
+        };
+
+        // Every command fits, so this can't fail
+        let _ = match self {
+            MagicqCommand::Activate(playback) => write!(encoded, "{}A", playback),
+            MagicqCommand::Release(playback) => write!(encoded, "{}R", playback),
+            MagicqCommand::Level(playback, level) => write!(encoded, "{},{}L", playback, level),
+            MagicqCommand::Go(playback) => write!(encoded, "{}G", playback),
+            MagicqCommand::Back(playback) => write!(encoded, "{}B", playback),
+            MagicqCommand::Stop(playback) => write!(encoded, "{}S", playback),
+            MagicqCommand::Jump(playback, cue) => write!(encoded, "{},{}J", playback, cue),
+        };
+
+        encoded
+    }
+
+    /// What the command does, e.g. "PB3 activated"
+    pub fn description(&self) -> String {
+        match self {
+            MagicqCommand::Activate(playback) => format!("PB{} activated", playback),
+            MagicqCommand::Release(playback) => format!("PB{} released", playback),
+            MagicqCommand::Level(playback, level) => format!("PB{} level set to {}", playback, level),
+            MagicqCommand::Go(playback) => format!("PB{} go", playback),
+            MagicqCommand::Back(playback) => format!("PB{} go back", playback),
+            MagicqCommand::Stop(playback) => format!("PB{} stopped", playback),
+            MagicqCommand::Jump(playback, cue) => format!("PB{} jumped to cue {}", playback, cue),
+        }
+    }
+}
+
+impl fmt::Display for MagicqCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // pad so widths like {:<10} work
+        f.pad(self.encode().as_str())
+    }
+}
+
+// Written the same way as it's sent, e.g. "3,100L"
+#[cfg(feature = "serde")]
+impl serde::Serialize for MagicqCommand {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MagicqCommand {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text: String = serde::Deserialize::deserialize(deserializer)?;
+        MagicqCommand::parse(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A command ready to send, held on the stack
+pub struct EncodedCommand {
+    bytes: [u8; MAX_COMMAND_LENGTH],
+    len: usize,
+}
+
+impl EncodedCommand {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever written to from strings
+        std::str::from_utf8(self.as_bytes()).unwrap_or_default()
+    }
+}
+
+impl Write for EncodedCommand {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let end = self.len + text.len();
+
+        match self.bytes.get_mut(self.len..end) {
+            Some(space) => {
+                space.copy_from_slice(text.as_bytes());
+                self.len = end;
+                Ok(())
+            }
+            None => Err(fmt::Error),
+        }
+    }
+}
+
+fn parse_command(command: &str) -> Option<MagicqCommand> {
+    let letter_index = command.len().checked_sub(1)?;
+    let (arguments, letter) = (command.get(..letter_index)?, command.get(letter_index..)?);
+
+    match letter {
+        "A" => Some(MagicqCommand::Activate(arguments.parse().ok()?)),
+        "R" => Some(MagicqCommand::Release(arguments.parse().ok()?)),
+        "L" => {
+            let (playback, level) = arguments.split_once(',')?;
+            Some(MagicqCommand::Level(playback.parse().ok()?, level.parse().ok()?))
+        }
+        "G" => Some(MagicqCommand::Go(arguments.parse().ok()?)),
+        "B" => Some(MagicqCommand::Back(arguments.parse().ok()?)),
+        "S" => Some(MagicqCommand::Stop(arguments.parse().ok()?)),
+        "J" => {
+            let (playback, cue) = arguments.split_once(',')?;
+            Some(MagicqCommand::Jump(playback.parse().ok()?, cue.parse().ok()?))
+        }
+        _ => None,
+    }
+}
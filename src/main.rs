@@ -1,5 +1,6 @@
+use std::process::ExitCode;
 use midilx::cli::run_cli;
 
-fn main() {
-    run_cli();
-}
\ No newline at end of file
+fn main() -> ExitCode {
+    run_cli()
+}
@@ -1,13 +1,83 @@
+use std::fmt;
 use std::io::{stdin, stdout, Write};
+use std::sync::OnceLock;
 use crate::errors::ProgramError;
+use log::info;
 use midir::*;
+use regex::Regex;
 use crate::return_err;
+use crate::{MidiInputSource, MidiPortOptions};
+use crate::port_selector::PortSelector;
+
+/// Name of the MIDI client ports are opened from, unless set_client_name says otherwise
+pub const DEFAULT_CLIENT_NAME: &str = "midi_lx";
+
+// Names of the ports opened to connect to other programs' ports, e.g. "midi_lx:in" in JACK
+pub(crate) const INPUT_PORT_NAME: &str = "in";
+const OUTPUT_PORT_NAME: &str = "out";
+
+static CLIENT_NAME: OnceLock<String> = OnceLock::new();
+
+/// The MIDI system ports come from.
+/// midir only has one at a time, so it's picked when building (JACK with the jack feature)
+/// and can only be checked when running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MidiBackend {
+    // ALSA on Linux, CoreMIDI on macOS, WinMM on Windows
+    Native,
+
+    Jack,
+}
+
+/// The backend this build uses. midir has no JACK backend on Windows.
+pub const MIDI_BACKEND: MidiBackend = if cfg!(all(feature = "jack", not(windows))) {
+    MidiBackend::Jack
+} else {
+    MidiBackend::Native
+};
+
+impl fmt::Display for MidiBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MidiBackend::Jack => "JACK",
+            MidiBackend::Native if cfg!(target_os = "linux") => "ALSA",
+            MidiBackend::Native if cfg!(target_os = "macos") => "CoreMIDI",
+            MidiBackend::Native if cfg!(windows) => "WinMM",
+            MidiBackend::Native => "native MIDI",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Fails if this build doesn't use the backend asked for, e.g. so a show isn't run on ALSA by mistake
+pub fn check_midi_backend(backend: MidiBackend) -> Result<(), ProgramError> {
+    if backend == MIDI_BACKEND {
+        return Ok(())
+    }
+
+    match backend {
+        MidiBackend::Jack => return_err!(Config, "JACK MIDI needs midi_lx built with the jack feature (cargo build --features jack)"),
+        MidiBackend::Native => return_err!(Config, format!("this midi_lx was built for JACK MIDI, build it without the jack feature to use {}", backend)),
+    }
+}
+
+/// Names the MIDI client everything is opened from, e.g. so JACK connections can be saved by name.
+/// Only the first call counts, so it should be called before any ports are opened.
+pub fn set_client_name(name: &str) {
+    let _ = CLIENT_NAME.set(name.to_owned());
+}
+
+pub fn client_name() -> &'static str {
+    CLIENT_NAME.get().map_or(DEFAULT_CLIENT_NAME, String::as_str)
+}
 
 pub fn get_midi_input() -> Result<MidiInput, ProgramError> {
     // Create the MIDI input
-    let mut midi_in = match MidiInput::new("midir reading input") {
+    let mut midi_in = match MidiInput::new(client_name()) {
         Ok(m) => m,
-        Err(e) => return_err!(format!("failed to create midi input: {}", e))
+        Err(e) => return_err!(MidiInit, format!("failed to create midi input: {}", e), e)
     };
 
     midi_in.ignore(Ignore::None);
@@ -15,13 +85,23 @@ pub fn get_midi_input() -> Result<MidiInput, ProgramError> {
     Ok(midi_in)
 }
 
-pub fn get_midi_input_port(midi_in: &MidiInput) -> Result<MidiInputPort, ProgramError> {
+/// Gets the input port picked by the selector,
+/// or asks the user to choose one if there's no selector and more than one port
+pub fn get_midi_input_port(midi_in: &MidiInput, selector: Option<&PortSelector>) -> Result<MidiInputPort, ProgramError> {
     let in_ports = midi_in.ports();
 
+    if let Some(selector) = selector {
+        let port_names: Vec<String> = in_ports.iter().map(|p| midi_in.port_name(p).unwrap_or_default()).collect();
+        let index = selector.select(&port_names)?;
+
+        info!("Selected input port: {}", port_names[index]);
+        return Ok(in_ports[index].to_owned())
+    }
+
     match in_ports.len() {
-        0 => return_err!("no input port found"),
+        0 => return_err!(PortSelection, "no input port found"),
         1 => {
-            println!(
+            info!(
                 "Choosing the only available input port: {}",
                 midi_in.port_name(&in_ports[0]).unwrap()
             );
@@ -39,19 +119,19 @@ pub fn get_midi_input_port(midi_in: &MidiInput) -> Result<MidiInputPort, Program
 
             match stdout().flush() {
                 Ok(_) => (),
-                Err(e) => return_err!(format!("failed to flush stdout: {e}")),
+                Err(e) => return_err!(Io, format!("failed to flush stdout: {e}"), e),
             }
 
             let mut input = String::new();
             match stdin().read_line(&mut input) {
                 Ok(_) => (),
-                Err(e) => return_err!(format!("failed to read line from stdin: {e}")),
+                Err(e) => return_err!(Io, format!("failed to read line from stdin: {e}"), e),
             }
 
             let parsed_input = match input.trim().parse::<usize>() {
                 Ok(i) => i,
                 Err(e) => {
-                    return_err!(
+                    return_err!(PortSelection,
                         format!("failed to parse input. Expected a number, got '{e}' instead")
                     )
                 }
@@ -59,25 +139,73 @@ pub fn get_midi_input_port(midi_in: &MidiInput) -> Result<MidiInputPort, Program
 
             match in_ports.get(parsed_input) {
                 Some(p) => Ok(p.to_owned()),
-                None => return_err!("invalid input port selected")
+                None => return_err!(PortSelection, "invalid input port selected")
             }
         }
     }
 }
 
-pub fn get_midi_output() -> Result<MidiOutputConnection, ProgramError> {
-    let midi_out = match MidiOutput::new("Midi Output Connection") {
+/// Gets the virtual input to create if there is one, otherwise the selected input port
+pub fn get_midi_input_source(midi_in: &MidiInput, ports: &MidiPortOptions) -> Result<MidiInputSource, ProgramError> {
+    match &ports.virtual_input {
+        Some(name) => Ok(MidiInputSource::Virtual(name.to_owned())),
+        None => Ok(MidiInputSource::Port(get_midi_input_port(midi_in, ports.input.as_ref())?)),
+    }
+}
+
+/// Prints every MIDI input and output port with the index used to select it
+pub fn print_midi_ports() -> Result<(), ProgramError> {
+    println!("MIDI backend: {}", MIDI_BACKEND);
+
+    let midi_in = get_midi_input()?;
+    let midi_out = match MidiOutput::new(client_name()) {
+        Ok(m) => m,
+        Err(e) => return_err!(MidiInit, format!("failed to create midi output: {}", e), e)
+    };
+
+    println!("\nInput ports:");
+    let in_ports = midi_in.ports();
+    if in_ports.is_empty() {
+        println!("none");
+    }
+    for (i, p) in in_ports.iter().enumerate() {
+        println!("{}: {}", i, midi_in.port_name(p).unwrap_or_default());
+    }
+
+    println!("\nOutput ports:");
+    let out_ports = midi_out.ports();
+    if out_ports.is_empty() {
+        println!("none");
+    }
+    for (i, p) in out_ports.iter().enumerate() {
+        println!("{}: {}", i, midi_out.port_name(p).unwrap_or_default());
+    }
+
+    Ok(())
+}
+
+/// Connects to the output port picked by the selector,
+/// or asks the user to choose one if there's no selector and more than one port
+pub fn get_midi_output(selector: Option<&PortSelector>) -> Result<MidiOutputConnection, ProgramError> {
+    let midi_out = match MidiOutput::new(client_name()) {
         Ok(m) => m,
-        Err(e) => return_err!(format!("failed to create midi output: {}", e))
+        Err(e) => return_err!(MidiInit, format!("failed to create midi output: {}", e), e)
     };
 
     // Get an output port (read from console if multiple are available)
     let out_ports = midi_out.ports();
 
-    let out_port = match out_ports.len() {
-        0 => return_err!("no output port found"),
-        1 => {
-            println!(
+    let out_port = match (out_ports.len(), selector) {
+        (_, Some(selector)) => {
+            let port_names: Vec<String> = out_ports.iter().map(|p| midi_out.port_name(p).unwrap_or_default()).collect();
+            let index = selector.select(&port_names)?;
+
+            info!("Selected output port: {}", port_names[index]);
+            Ok(out_ports[index].to_owned())
+        }
+        (0, None) => return_err!(PortSelection, "no output port found"),
+        (1, None) => {
+            info!(
                 "Choosing the only available output port: {}",
                 midi_out.port_name(&out_ports[0]).unwrap()
             );
@@ -93,19 +221,19 @@ pub fn get_midi_output() -> Result<MidiOutputConnection, ProgramError> {
             print!("Please select output port: ");
             match stdout().flush() {
                 Ok(_) => (),
-                Err(e) => return_err!(format!("failed to flush stdout: {e}")),
+                Err(e) => return_err!(Io, format!("failed to flush stdout: {e}"), e),
             }
 
             let mut input = String::new();
             match stdin().read_line(&mut input) {
                 Ok(_) => (),
-                Err(e) => return_err!(format!("failed to read line from stdin: {e}")),
+                Err(e) => return_err!(Io, format!("failed to read line from stdin: {e}"), e),
             }
 
             let parsed_input = match input.trim().parse::<usize>() {
                 Ok(i) => i,
                 Err(e) => {
-                    return_err!(
+                    return_err!(PortSelection,
                         format!("failed to parse input. Expected a number, got '{e}' instead")
                     )
                 }
@@ -113,13 +241,200 @@ pub fn get_midi_output() -> Result<MidiOutputConnection, ProgramError> {
 
             match out_ports.get(parsed_input) {
                 Some(p) => Ok(p.to_owned()),
-                None => return_err!("invalid output port selected")
+                None => return_err!(PortSelection, "invalid output port selected")
             }
         }
     }?;
 
-    match midi_out.connect(&out_port, "midir-test") {
+    match midi_out.connect(&out_port, OUTPUT_PORT_NAME) {
         Ok(c) => Ok(c),
-        Err(e) => return_err!(format!("failed to connect midi output: {}", e))
+        Err(e) => return_err!(Connection, format!("failed to connect midi output: {}", e), connect_error_source(&e))
+    }
+}
+
+/// midir's connect errors hold on to the MIDI client, which can't be shared between threads,
+/// so this is the same error without it, to keep as the source
+pub(crate) fn connect_error_source<T>(e: &ConnectError<T>) -> ConnectError<()> {
+    ConnectError::new(e.kind(), ())
+}
+
+/// Connects to the input source, calling the callback for every message received
+pub fn connect_midi_input<F>(
+    midi_in: MidiInput,
+    source: MidiInputSource,
+    callback: F,
+) -> Result<MidiInputConnection<()>, ProgramError>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    match source {
+        MidiInputSource::Port(port) => match midi_in.connect(&port, INPUT_PORT_NAME, callback, ()) {
+            Ok(connection) => Ok(connection),
+            Err(e) => return_err!(Connection, format!("failed to connect: {}", e), connect_error_source(&e))
+        },
+
+        MidiInputSource::Virtual(name) => create_virtual_input(midi_in, &name, callback),
+    }
+}
+
+pub fn midi_input_source_name(midi_in: &MidiInput, source: &MidiInputSource) -> String {
+    match source {
+        MidiInputSource::Port(port) => midi_in.port_name(port).unwrap_or_default(),
+        MidiInputSource::Virtual(name) => name.to_owned(),
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual_input<F>(midi_in: MidiInput, name: &str, callback: F) -> Result<MidiInputConnection<()>, ProgramError>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    use midir::os::unix::VirtualInput;
+
+    match midi_in.create_virtual(name, callback, ()) {
+        Ok(connection) => {
+            info!("Created virtual input port '{}'", name);
+            Ok(connection)
+        }
+        Err(e) => return_err!(Connection, format!("failed to create virtual input port '{name}': {e}"), connect_error_source(&e))
+    }
+}
+
+#[cfg(not(unix))]
+fn create_virtual_input<F>(_midi_in: MidiInput, name: &str, _callback: F) -> Result<MidiInputConnection<()>, ProgramError>
+where
+    F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+{
+    return_err!(Connection, format!("can't create virtual input port '{name}', virtual ports are not supported on this platform"))
+}
+
+/// Creates a virtual output port with this name that other programs can connect to.
+/// With JACK it's also connected to every port whose name matches connect_to.
+#[cfg(unix)]
+pub fn create_virtual_output(name: &str, connect_to: Option<&Regex>) -> Result<MidiOutputConnection, ProgramError> {
+    use midir::os::unix::VirtualOutput;
+
+    let midi_out = match MidiOutput::new(client_name()) {
+        Ok(m) => m,
+        Err(e) => return_err!(MidiInit, format!("failed to create midi output: {}", e), e)
+    };
+
+    let connection = match midi_out.create_virtual(name) {
+        Ok(connection) => connection,
+        Err(e) => return_err!(Connection, format!("failed to create virtual output port '{name}': {e}"), connect_error_source(&e))
+    };
+
+    info!("Created virtual output port '{}'", name);
+
+    if let Some(pattern) = connect_to {
+        connect_jack_output(name, pattern)?;
+    }
+
+    Ok(connection)
+}
+
+#[cfg(not(unix))]
+pub fn create_virtual_output(name: &str, _connect_to: Option<&Regex>) -> Result<MidiOutputConnection, ProgramError> {
+    return_err!(Connection, format!("can't create virtual output port '{name}', virtual ports are not supported on this platform"))
+}
+
+// midir doesn't connect virtual ports to anything, so a short lived JACK client makes the connections.
+// Only ports there when the virtual output is created are connected.
+#[cfg(all(unix, feature = "jack"))]
+fn connect_jack_output(port_name: &str, pattern: &Regex) -> Result<(), ProgramError> {
+    use std::ffi::CString;
+
+    let connector_name = match CString::new(format!("{} connector", client_name())) {
+        Ok(name) => name,
+        Err(e) => return_err!(Config, format!("MIDI client name '{}' can't be used with JACK: {e}", client_name()), e),
+    };
+
+    let mut status: jack_sys::jack_status_t = 0;
+    let client = unsafe { jack_sys::jack_client_open(connector_name.as_ptr(), jack_sys::JackNoStartServer, &mut status) };
+    if client.is_null() {
+        return_err!(Connection, format!("failed to open a JACK client to connect '{port_name}' (JACK status {status:#x})"))
+    }
+
+    let result = connect_jack_ports(client, port_name, pattern);
+
+    unsafe { jack_sys::jack_client_close(client) };
+    result
+}
+
+// JACK renames a client whose name is taken (e.g. "midi_lx-01"), so the port is found by its client and port name
+#[cfg(all(unix, feature = "jack"))]
+fn connect_jack_ports(client: *mut jack_sys::jack_client_t, port_name: &str, pattern: &Regex) -> Result<(), ProgramError> {
+    use std::ffi::CString;
+
+    let is_own_client = |name: &str| {
+        name.strip_prefix(client_name()).is_some_and(|suffix| {
+            suffix.is_empty() || suffix.strip_prefix('-').is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+        })
+    };
+
+    let sources: Vec<String> = jack_midi_ports(client, jack_sys::JackPortIsOutput)
+        .into_iter()
+        .filter(|name| name.split_once(':').is_some_and(|(client, port)| port == port_name && is_own_client(client)))
+        .collect();
+
+    let source = match sources.as_slice() {
+        [source] => source,
+        [] => return_err!(Connection, format!("can't find virtual output port '{port_name}' in JACK to connect it")),
+        _ => return_err!(Connection, format!("more than one JACK client has a port called '{port_name}', give this one a different name with --client-name")),
+    };
+
+    let destinations: Vec<String> = jack_midi_ports(client, jack_sys::JackPortIsInput)
+        .into_iter()
+        .filter(|name| pattern.is_match(name) && !name.split_once(':').is_some_and(|(client, _)| is_own_client(client)))
+        .collect();
+
+    if destinations.is_empty() {
+        log::warn!("No JACK MIDI inputs match '{}', '{}' isn't connected to anything", pattern, source);
+    }
+
+    // JACK port names can't have NUL in them, they came from C strings
+    let source_c = CString::new(source.as_str()).unwrap_or_default();
+
+    for destination in &destinations {
+        let destination_c = CString::new(destination.as_str()).unwrap_or_default();
+
+        // EEXIST if they're already connected
+        match unsafe { jack_sys::jack_connect(client, source_c.as_ptr(), destination_c.as_ptr()) } {
+            0 | 17 => info!("Connected '{}' to '{}'", source, destination),
+            code => return_err!(Connection, format!("failed to connect '{source}' to '{destination}' (JACK error {code})")),
+        }
     }
+
+    Ok(())
+}
+
+// Every MIDI port with these flags
+#[cfg(all(unix, feature = "jack"))]
+fn jack_midi_ports(client: *mut jack_sys::jack_client_t, flags: std::ffi::c_uint) -> Vec<String> {
+    use std::ffi::{c_ulong, CStr};
+
+    let mut names = Vec::new();
+
+    unsafe {
+        // NULL terminated, and freed with jack_free
+        let ports = jack_sys::jack_get_ports(client, std::ptr::null(), c"8 bit raw midi".as_ptr(), flags as c_ulong);
+        if ports.is_null() {
+            return names
+        }
+
+        let mut port = ports;
+        while !(*port).is_null() {
+            names.push(CStr::from_ptr(*port).to_string_lossy().into_owned());
+            port = port.add(1);
+        }
+
+        jack_sys::jack_free(ports.cast());
+    }
+
+    names
+}
+
+#[cfg(all(unix, not(feature = "jack")))]
+fn connect_jack_output(port_name: &str, pattern: &Regex) -> Result<(), ProgramError> {
+    return_err!(Config, format!("can't connect '{port_name}' to ports matching '{pattern}', connecting ports needs midi_lx built with the jack feature"))
 }
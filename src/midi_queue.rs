@@ -0,0 +1,198 @@
+// Gets MIDI messages from the input callback to the runtime's event loop without allocating or locking.
+// Channel messages (3 bytes at most) are copied into fixed size packets on a bounded lock-free queue,
+// and the event loop is woken with thread unpark. Anything longer (SysEx) still goes through the event channel.
+// If the event loop falls so far behind that the queue fills up, new messages are dropped and counted
+// rather than making the MIDI callback wait.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::thread::Thread;
+use std::time::Instant;
+use crossbeam_queue::ArrayQueue;
+
+/// How many messages can be waiting for the event loop
+pub const MIDI_QUEUE_CAPACITY: usize = 1024;
+
+/// Longest message that fits in a packet, a channel message with its status byte
+const MAX_PACKET_LENGTH: usize = 3;
+
+/// A short MIDI message and when it arrived
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiPacket {
+    bytes: [u8; MAX_PACKET_LENGTH],
+    len: u8,
+    pub received: Instant,
+}
+
+impl MidiPacket {
+    /// None if the message is empty or too long for a packet
+    pub fn new(message: &[u8], received: Instant) -> Option<MidiPacket> {
+        if message.is_empty() || message.len() > MAX_PACKET_LENGTH {
+            return None
+        }
+
+        let mut bytes = [0; MAX_PACKET_LENGTH];
+        bytes[..message.len()].copy_from_slice(message);
+
+        Some(MidiPacket {
+            bytes,
+            len: message.len() as u8,
+            received,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// Shared between everything feeding the runtime and its event loop
+pub struct MidiQueue {
+    packets: ArrayQueue<MidiPacket>,
+    dropped: AtomicU64,
+
+    // Set by the event loop when it starts
+    event_loop: OnceLock<Thread>,
+}
+
+impl MidiQueue {
+    pub fn new() -> Self {
+        Self::with_capacity(MIDI_QUEUE_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            packets: ArrayQueue::new(capacity),
+            dropped: AtomicU64::new(0),
+            event_loop: OnceLock::new(),
+        }
+    }
+
+    /// Queues a message for the event loop and wakes it.
+    /// Returns false if the message doesn't fit in a packet, so it has to be sent some other way.
+    pub fn push(&self, message: &[u8], received: Instant) -> bool {
+        let Some(packet) = MidiPacket::new(message, received) else {
+            return false
+        };
+
+        if self.packets.push(packet).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.wake();
+        true
+    }
+
+    pub fn pop(&self) -> Option<MidiPacket> {
+        self.packets.pop()
+    }
+
+    /// How many messages were dropped because the queue was full since this was last called
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    /// Makes the calling thread the one woken when something is queued
+    pub fn set_event_loop(&self) {
+        let _ = self.event_loop.set(std::thread::current());
+    }
+
+    /// Wakes the event loop, also used after sending it an event.
+    /// Does nothing before the event loop has started, it checks for messages when it does.
+    pub fn wake(&self) {
+        if let Some(event_loop) = self.event_loop.get() {
+            event_loop.unpark();
+        }
+    }
+}
+
+impl Default for MidiQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    #[test]
+    fn packets_hold_channel_messages_only() {
+        let now = Instant::now();
+
+        assert_eq!(MidiPacket::new(&[0x90, 60, 100], now).unwrap().as_bytes(), &[0x90, 60, 100]);
+        assert_eq!(MidiPacket::new(&[0xF8], now).unwrap().as_bytes(), &[0xF8]);
+        assert_eq!(MidiPacket::new(&[], now), None);
+        assert_eq!(MidiPacket::new(&[0xF0, 0x7E, 0x7F, 0xF7], now), None);
+
+        let queue = MidiQueue::new();
+        assert!(!queue.push(&[0xF0, 0x7E, 0x7F, 0xF7], now));
+        assert!(!queue.push(&[], now));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn a_full_queue_drops_and_counts_new_messages() {
+        let queue = MidiQueue::with_capacity(2);
+        let now = Instant::now();
+
+        // Dropped messages still count as handled, so they aren't sent some other way
+        assert!(queue.push(&[0x90, 60, 100], now));
+        assert!(queue.push(&[0x90, 61, 100], now));
+        assert!(queue.push(&[0x90, 62, 100], now));
+        assert!(queue.push(&[0x90, 63, 100], now));
+
+        assert_eq!(queue.pop().unwrap().as_bytes(), &[0x90, 60, 100]);
+        assert_eq!(queue.pop().unwrap().as_bytes(), &[0x90, 61, 100]);
+        assert_eq!(queue.pop(), None);
+
+        assert_eq!(queue.take_dropped(), 2);
+        assert_eq!(queue.take_dropped(), 0);
+
+        // There's room again once the event loop catches up
+        assert!(queue.push(&[0x80, 60, 0], now));
+        assert_eq!(queue.pop().unwrap().as_bytes(), &[0x80, 60, 0]);
+        assert_eq!(queue.take_dropped(), 0);
+    }
+
+    #[test]
+    fn pushing_wakes_the_event_loop() {
+        let queue = Arc::new(MidiQueue::new());
+        let (ready_tx, ready) = mpsc::channel();
+
+        // Nothing to wake yet, the message waits for the event loop to start
+        queue.wake();
+        assert!(queue.push(&[0x90, 60, 100], Instant::now()));
+
+        let event_loop_queue = Arc::clone(&queue);
+        let event_loop = std::thread::spawn(move || {
+            event_loop_queue.set_event_loop();
+            let queued = event_loop_queue.pop().map(|packet| packet.as_bytes().to_vec());
+            ready_tx.send(()).unwrap();
+
+            // Parks far longer than the test waits, so only an unpark gets the second message in time
+            let started = Instant::now();
+            loop {
+                if let Some(packet) = event_loop_queue.pop() {
+                    return (queued, Some(packet.as_bytes().to_vec()), started.elapsed())
+                }
+
+                if started.elapsed() > Duration::from_secs(30) {
+                    return (queued, None, started.elapsed())
+                }
+
+                std::thread::park_timeout(Duration::from_secs(30));
+            }
+        });
+
+        ready.recv().unwrap();
+        assert!(queue.push(&[0x80, 60, 0], Instant::now()));
+
+        let (queued, woken, waited) = event_loop.join().unwrap();
+        assert_eq!(queued, Some(vec![0x90, 60, 100]));
+        assert_eq!(woken, Some(vec![0x80, 60, 0]));
+        assert!(waited < Duration::from_secs(5), "took {:?} to wake", waited);
+    }
+}
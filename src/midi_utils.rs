@@ -1,4 +1,9 @@
 // Utility functions for MIDI messages
+// Incoming bytes should be parsed into a MidiMessage rather than indexed by hand,
+// so things like running status and note ons with velocity 0 are handled in one place.
+
+use crate::errors::ProgramError;
+use crate::return_err;
 
 pub fn is_on_status(status: u8) -> bool {
     status >= 144 && status <= 159
@@ -15,4 +20,479 @@ pub fn status_channel(status: u8) -> u8 {
     }
 
     status % 16 + 1
-}
\ No newline at end of file
+}
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Which octave number middle C (note 60) is called.
+/// Roland and scientific pitch call it C4, Yamaha and a lot of DAWs call it C3.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MiddleC {
+    C3,
+    #[default]
+    C4,
+}
+
+impl MiddleC {
+    pub fn parse(text: &str) -> Result<MiddleC, ProgramError> {
+        match text.trim().to_ascii_uppercase().as_str() {
+            "C3" => Ok(MiddleC::C3),
+            "C4" => Ok(MiddleC::C4),
+            _ => return_err!(Parse, format!("'{text}' is not a middle C, should be C3 or C4")),
+        }
+    }
+
+    // Octave number of note 0
+    fn lowest_octave(self) -> i32 {
+        match self {
+            MiddleC::C3 => -2,
+            MiddleC::C4 => -1,
+        }
+    }
+}
+
+/// Name of a MIDI note number, e.g. 61 is C#4 when middle C is C4
+pub fn note_name(note: u8, middle_c: MiddleC) -> String {
+    let octave = (note / 12) as i32 + middle_c.lowest_octave();
+    format!("{}{}", NOTE_NAMES[(note % 12) as usize], octave)
+}
+
+/// Reads a note written as a name ("C3", "F#4", "Bb-1") or a note number ("48")
+pub fn parse_note(text: &str, middle_c: MiddleC) -> Result<u8, ProgramError> {
+    let text = text.trim();
+
+    if let Ok(number) = text.parse::<u8>() {
+        return match number {
+            0..=127 => Ok(number),
+            _ => return_err!(Parse, format!("note number {number} is too high, should be 0-127")),
+        }
+    }
+
+    let mut chars = text.chars();
+    let pitch: i32 = match chars.next().map(|letter| letter.to_ascii_uppercase()) {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return_err!(Parse, format!("'{text}' is not a note, should be a name like C3 or F#4, or a number")),
+    };
+
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.strip_prefix('#') {
+        Some(octave) => (1, octave),
+        None => match rest.strip_prefix('b') {
+            Some(octave) => (-1, octave),
+            None => (0, rest),
+        },
+    };
+
+    let octave = match octave.parse::<i32>() {
+        Ok(octave) => octave,
+        Err(_) => return_err!(Parse, format!("'{text}' is missing an octave number, e.g. C3")),
+    };
+
+    let note = (octave - middle_c.lowest_octave()) * 12 + pitch + accidental;
+    match u8::try_from(note) {
+        Ok(note) if note <= 127 => Ok(note),
+        _ => return_err!(Parse, format!("'{text}' is outside the MIDI note range")),
+    }
+}
+
+/// Raw bytes as hex, e.g. "F0 7E 7F F7"
+pub fn format_bytes(message: &[u8]) -> String {
+    message.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(" ")
+}
+
+/// A single MIDI message.
+/// Channels are 1-16, the same as status_channel.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MidiMessage {
+    // Channel voice messages
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyAftertouch { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelAftertouch { channel: u8, pressure: u8 },
+
+    // -8192 to 8191, centred on 0
+    PitchBend { channel: u8, value: i16 },
+
+    // The data between F0 and F7
+    SysEx(Vec<u8>),
+
+    // System common messages
+    MtcQuarterFrame(u8),
+    SongPosition(u16),
+    SongSelect(u8),
+    TuneRequest,
+
+    // System realtime messages
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+}
+
+impl MidiMessage {
+    /// Parses a complete message that starts with a status byte.
+    /// Note ons with velocity 0 are returned as note offs.
+    pub fn parse(bytes: &[u8]) -> Result<MidiMessage, ProgramError> {
+        let status = match bytes.first() {
+            Some(status) if *status >= 0x80 => *status,
+            Some(status) => return_err!(Parse, format!("MIDI message starts with data byte {status:#04X} instead of a status byte")),
+            None => return_err!(Parse, "MIDI message is empty"),
+        };
+
+        parse_with_status(status, &bytes[1..])
+    }
+
+    /// The bytes to send for this message, always with a status byte
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MidiMessage::NoteOff { channel, note, velocity } => vec![0x80 | channel_bits(*channel), *note, *velocity],
+            MidiMessage::NoteOn { channel, note, velocity } => vec![0x90 | channel_bits(*channel), *note, *velocity],
+            MidiMessage::PolyAftertouch { channel, note, pressure } => vec![0xA0 | channel_bits(*channel), *note, *pressure],
+            MidiMessage::ControlChange { channel, controller, value } => vec![0xB0 | channel_bits(*channel), *controller, *value],
+            MidiMessage::ProgramChange { channel, program } => vec![0xC0 | channel_bits(*channel), *program],
+            MidiMessage::ChannelAftertouch { channel, pressure } => vec![0xD0 | channel_bits(*channel), *pressure],
+            MidiMessage::PitchBend { channel, value } => {
+                let value = (*value as i32 + 8192).clamp(0, 16383) as u16;
+                vec![0xE0 | channel_bits(*channel), (value & 0x7F) as u8, (value >> 7) as u8]
+            }
+            MidiMessage::SysEx(data) => {
+                let mut bytes = Vec::with_capacity(data.len() + 2);
+                bytes.push(0xF0);
+                bytes.extend_from_slice(data);
+                bytes.push(0xF7);
+                bytes
+            }
+            MidiMessage::MtcQuarterFrame(value) => vec![0xF1, *value],
+            MidiMessage::SongPosition(beats) => vec![0xF2, (beats & 0x7F) as u8, (beats >> 7 & 0x7F) as u8],
+            MidiMessage::SongSelect(song) => vec![0xF3, *song],
+            MidiMessage::TuneRequest => vec![0xF6],
+            MidiMessage::Clock => vec![0xF8],
+            MidiMessage::Start => vec![0xFA],
+            MidiMessage::Continue => vec![0xFB],
+            MidiMessage::Stop => vec![0xFC],
+            MidiMessage::ActiveSensing => vec![0xFE],
+            MidiMessage::Reset => vec![0xFF],
+        }
+    }
+
+    /// The channel for channel voice messages, None for system messages
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::PolyAftertouch { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::ChannelAftertouch { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    /// Clock, start, stop etc. which can arrive in the middle of other messages
+    pub fn is_realtime(&self) -> bool {
+        matches!(
+            self,
+            MidiMessage::Clock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop | MidiMessage::ActiveSensing | MidiMessage::Reset
+        )
+    }
+}
+
+/// Parses messages that may use running status,
+/// where the status byte is left out when it's the same as the previous channel message.
+#[derive(Clone, Debug, Default)]
+pub struct MidiParser {
+    running_status: Option<u8>,
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(&mut self, bytes: &[u8]) -> Result<MidiMessage, ProgramError> {
+        let first = match bytes.first() {
+            Some(first) => *first,
+            None => return_err!(Parse, "MIDI message is empty"),
+        };
+
+        // Data byte first means the status from the previous channel message is reused
+        if first < 0x80 {
+            return match self.running_status {
+                Some(status) => parse_with_status(status, bytes),
+                None => return_err!(Parse, "MIDI message uses running status before any status byte"),
+            }
+        }
+
+        // Channel messages set the running status, system common and SysEx clear it.
+        // Realtime messages can be sent between running status messages so leave it alone.
+        match first {
+            0x80..=0xEF => self.running_status = Some(first),
+            0xF0..=0xF7 => self.running_status = None,
+            _ => (),
+        }
+
+        MidiMessage::parse(bytes)
+    }
+}
+
+// Parses the data bytes that follow a status byte
+fn parse_with_status(status: u8, data: &[u8]) -> Result<MidiMessage, ProgramError> {
+    let channel = status_channel(status);
+
+    // Realtime bytes can be sent in the middle of any other message, they're left out of it
+    let without_realtime: Vec<u8>;
+    let data = if status < 0xF8 && data.iter().any(|byte| *byte >= 0xF8) {
+        without_realtime = data.iter().copied().filter(|byte| *byte < 0xF8).collect();
+        without_realtime.as_slice()
+    } else {
+        data
+    };
+
+    if status == 0xF0 {
+        // Some backends leave off the end of SysEx byte
+        let data = data.strip_suffix(&[0xF7]).unwrap_or(data);
+
+        if let Some(byte) = data.iter().find(|byte| **byte >= 0x80) {
+            return_err!(Parse, format!("SysEx message contains status byte {byte:#04X}"))
+        }
+
+        return Ok(MidiMessage::SysEx(data.to_vec()))
+    }
+
+    let data_length = match status {
+        0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+        0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+        _ => 0,
+    };
+
+    if data.len() < data_length {
+        return_err!(Parse, format!("MIDI message with status {status:#04X} needs {data_length} data bytes, got {}", data.len()))
+    }
+
+    let data = &data[..data_length];
+    if let Some(byte) = data.iter().find(|byte| **byte >= 0x80) {
+        return_err!(Parse, format!("MIDI message with status {status:#04X} contains status byte {byte:#04X} as data"))
+    }
+
+    let message = match (status & 0xF0, data) {
+        (0x80, [note, velocity]) => MidiMessage::NoteOff { channel, note: *note, velocity: *velocity },
+
+        // A lot of keyboards send note offs as note ons with velocity 0
+        (0x90, [note, 0]) => MidiMessage::NoteOff { channel, note: *note, velocity: 0 },
+        (0x90, [note, velocity]) => MidiMessage::NoteOn { channel, note: *note, velocity: *velocity },
+
+        (0xA0, [note, pressure]) => MidiMessage::PolyAftertouch { channel, note: *note, pressure: *pressure },
+        (0xB0, [controller, value]) => MidiMessage::ControlChange { channel, controller: *controller, value: *value },
+        (0xC0, [program]) => MidiMessage::ProgramChange { channel, program: *program },
+        (0xD0, [pressure]) => MidiMessage::ChannelAftertouch { channel, pressure: *pressure },
+
+        // 14 bit value, LSB first
+        (0xE0, [lsb, msb]) => MidiMessage::PitchBend { channel, value: ((*msb as i16) << 7 | *lsb as i16) - 8192 },
+
+        _ => match (status, data) {
+            (0xF1, [value]) => MidiMessage::MtcQuarterFrame(*value),
+            (0xF2, [lsb, msb]) => MidiMessage::SongPosition((*msb as u16) << 7 | *lsb as u16),
+            (0xF3, [song]) => MidiMessage::SongSelect(*song),
+            (0xF6, _) => MidiMessage::TuneRequest,
+            (0xF8, _) => MidiMessage::Clock,
+            (0xFA, _) => MidiMessage::Start,
+            (0xFB, _) => MidiMessage::Continue,
+            (0xFC, _) => MidiMessage::Stop,
+            (0xFE, _) => MidiMessage::ActiveSensing,
+            (0xFF, _) => MidiMessage::Reset,
+            _ => return_err!(Parse, format!("undefined MIDI status byte {status:#04X}")),
+        },
+    };
+
+    Ok(message)
+}
+
+fn channel_bits(channel: u8) -> u8 {
+    channel.saturating_sub(1) & 0x0F
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_status_reuses_the_last_channel_status() {
+        let mut parser = MidiParser::new();
+
+        assert!(parser.parse(&[60, 100]).is_err());
+        assert_eq!(parser.parse(&[0x91, 60, 100]).unwrap(), MidiMessage::NoteOn { channel: 2, note: 60, velocity: 100 });
+        assert_eq!(parser.parse(&[62, 90]).unwrap(), MidiMessage::NoteOn { channel: 2, note: 62, velocity: 90 });
+        assert_eq!(parser.parse(&[60, 0]).unwrap(), MidiMessage::NoteOff { channel: 2, note: 60, velocity: 0 });
+
+        // Realtime messages don't change it
+        assert_eq!(parser.parse(&[0xF8]).unwrap(), MidiMessage::Clock);
+        assert_eq!(parser.parse(&[64, 80]).unwrap(), MidiMessage::NoteOn { channel: 2, note: 64, velocity: 80 });
+
+        // Another channel message replaces it
+        assert_eq!(parser.parse(&[0xB0, 1, 20]).unwrap(), MidiMessage::ControlChange { channel: 1, controller: 1, value: 20 });
+        assert_eq!(parser.parse(&[1, 30]).unwrap(), MidiMessage::ControlChange { channel: 1, controller: 1, value: 30 });
+
+        // SysEx and system common messages clear it
+        assert_eq!(parser.parse(&[0xF0, 0x7D, 0x01, 0xF7]).unwrap(), MidiMessage::SysEx(vec![0x7D, 0x01]));
+        assert!(parser.parse(&[1, 40]).is_err());
+    }
+
+    #[test]
+    fn realtime_bytes_in_the_middle_of_a_message_are_skipped() {
+        let note_on = MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 };
+
+        assert_eq!(MidiMessage::parse(&[0x90, 0xF8, 60, 100]).unwrap(), note_on);
+        assert_eq!(MidiMessage::parse(&[0x90, 60, 0xFE, 100]).unwrap(), note_on);
+        assert_eq!(MidiMessage::parse(&[0xF0, 0x7D, 0xF8, 0x01, 0xF7]).unwrap(), MidiMessage::SysEx(vec![0x7D, 0x01]));
+
+        let mut parser = MidiParser::new();
+        parser.parse(&[0x90, 60, 100]).unwrap();
+        assert_eq!(parser.parse(&[62, 0xFA, 100]).unwrap(), MidiMessage::NoteOn { channel: 1, note: 62, velocity: 100 });
+
+        // Other status bytes can't be
+        assert!(MidiMessage::parse(&[0x90, 0x80, 60, 100]).is_err());
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        assert!(MidiMessage::parse(&[]).is_err());
+        assert!(MidiMessage::parse(&[60, 100]).is_err());
+        assert!(MidiMessage::parse(&[0x90]).is_err());
+        assert!(MidiMessage::parse(&[0x90, 60]).is_err());
+        assert!(MidiMessage::parse(&[0xC0]).is_err());
+        assert!(MidiMessage::parse(&[0xE0, 0x00]).is_err());
+        assert!(MidiMessage::parse(&[0xF2, 0x00]).is_err());
+
+        // Realtime bytes don't count towards the data
+        assert!(MidiMessage::parse(&[0x90, 60, 0xF8]).is_err());
+
+        let mut parser = MidiParser::new();
+        parser.parse(&[0x90, 60, 100]).unwrap();
+        assert!(parser.parse(&[62]).is_err());
+    }
+
+    #[test]
+    fn note_on_with_velocity_0_is_a_note_off() {
+        assert_eq!(MidiMessage::parse(&[0x99, 36, 0]).unwrap(), MidiMessage::NoteOff { channel: 10, note: 36, velocity: 0 });
+        assert_eq!(MidiMessage::parse(&[0x99, 36, 1]).unwrap(), MidiMessage::NoteOn { channel: 10, note: 36, velocity: 1 });
+    }
+
+    #[test]
+    fn to_bytes_round_trips() {
+        let messages = [
+            MidiMessage::NoteOff { channel: 1, note: 60, velocity: 64 },
+            MidiMessage::NoteOn { channel: 16, note: 127, velocity: 127 },
+            MidiMessage::PolyAftertouch { channel: 3, note: 0, pressure: 50 },
+            MidiMessage::ControlChange { channel: 4, controller: 64, value: 0 },
+            MidiMessage::ProgramChange { channel: 5, program: 127 },
+            MidiMessage::ChannelAftertouch { channel: 6, pressure: 1 },
+            MidiMessage::PitchBend { channel: 7, value: -8192 },
+            MidiMessage::PitchBend { channel: 7, value: 0 },
+            MidiMessage::PitchBend { channel: 7, value: 8191 },
+            MidiMessage::SysEx(vec![0x2B, 0x01, 0x01, 0x23, 0x00, 0x00, 0x09]),
+            MidiMessage::SysEx(Vec::new()),
+            MidiMessage::MtcQuarterFrame(0x71),
+            MidiMessage::SongPosition(16383),
+            MidiMessage::SongSelect(3),
+            MidiMessage::TuneRequest,
+            MidiMessage::Clock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::ActiveSensing,
+            MidiMessage::Reset,
+        ];
+
+        for message in messages {
+            assert_eq!(MidiMessage::parse(&message.to_bytes()).unwrap(), message);
+        }
+
+        assert_eq!(MidiMessage::PitchBend { channel: 1, value: 0 }.to_bytes(), vec![0xE0, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn note_names_follow_middle_c() {
+        let cases = [
+            (0, MiddleC::C4, "C-1"),
+            (60, MiddleC::C4, "C4"),
+            (61, MiddleC::C4, "C#4"),
+            (127, MiddleC::C4, "G9"),
+            (0, MiddleC::C3, "C-2"),
+            (60, MiddleC::C3, "C3"),
+            (70, MiddleC::C3, "A#3"),
+            (127, MiddleC::C3, "G8"),
+        ];
+
+        for (note, middle_c, name) in cases {
+            assert_eq!(note_name(note, middle_c), name, "{note} with middle C {middle_c:?}");
+        }
+    }
+
+    #[test]
+    fn notes_parse_from_names_and_numbers() {
+        let cases = [
+            ("C-1", MiddleC::C4, 0),
+            ("G9", MiddleC::C4, 127),
+            ("C4", MiddleC::C4, 60),
+            ("C#4", MiddleC::C4, 61),
+            ("Db4", MiddleC::C4, 61),
+            ("Bb3", MiddleC::C4, 58),
+            ("A#3", MiddleC::C4, 58),
+            ("c4", MiddleC::C4, 60),
+            (" F#2 ", MiddleC::C4, 42),
+            ("C-2", MiddleC::C3, 0),
+            ("G8", MiddleC::C3, 127),
+            ("C3", MiddleC::C3, 60),
+            ("0", MiddleC::C3, 0),
+            ("127", MiddleC::C4, 127),
+        ];
+
+        for (text, middle_c, note) in cases {
+            assert_eq!(parse_note(text, middle_c).unwrap(), note, "'{text}' with middle C {middle_c:?}");
+        }
+    }
+
+    #[test]
+    fn notes_out_of_range_or_misspelt_are_errors() {
+        let cases = [
+            ("G#9", MiddleC::C4),
+            ("Cb-1", MiddleC::C4),
+            ("C-2", MiddleC::C4),
+            ("G9", MiddleC::C3),
+            ("C-3", MiddleC::C3),
+            ("128", MiddleC::C4),
+            ("-1", MiddleC::C4),
+            ("H4", MiddleC::C4),
+            ("C", MiddleC::C4),
+            ("C#", MiddleC::C4),
+            ("", MiddleC::C4),
+        ];
+
+        for (text, middle_c) in cases {
+            assert!(parse_note(text, middle_c).is_err(), "'{text}' with middle C {middle_c:?}");
+        }
+    }
+
+    #[test]
+    fn every_note_name_parses_back() {
+        for middle_c in [MiddleC::C3, MiddleC::C4] {
+            for note in 0..=127 {
+                assert_eq!(parse_note(&note_name(note, middle_c), middle_c).unwrap(), note);
+            }
+        }
+    }
+}
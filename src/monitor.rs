@@ -0,0 +1,363 @@
+// Shows every incoming MIDI message decoded into something readable,
+// e.g. "Note On    ch 1   C#4 velocity 100" instead of "[144, 61, 100]".
+// Useful for checking what a controller, DAW or the organ is actually sending.
+
+use std::io::stdin;
+use color_print::cprintln;
+use crate::errors::ProgramError;
+use crate::midi_io::{connect_midi_input, get_midi_input, get_midi_input_source, midi_input_source_name};
+use crate::midi_utils::{format_bytes, note_name, MiddleC, MidiMessage};
+use crate::organ::organ_midi::sysex_to_organ_stop;
+use crate::{return_err, MidiPortOptions};
+
+/// The types of message the monitor can be filtered to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKind {
+    Note,
+    PolyAftertouch,
+    ControlChange,
+    ProgramChange,
+    ChannelAftertouch,
+    PitchBend,
+    SysEx,
+
+    // Song position, song select, MTC quarter frames etc.
+    Common,
+
+    // Clock, start, stop etc.
+    Realtime,
+}
+
+/// Which messages the monitor shows. Empty lists show everything.
+#[derive(Clone, Debug, Default)]
+pub struct MonitorFilter {
+    pub kinds: Vec<MessageKind>,
+
+    // Channels 1-16. Messages without a channel are hidden when this is set.
+    pub channels: Vec<u8>,
+}
+
+impl MonitorFilter {
+    /// True if every message is shown
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty() && self.channels.is_empty()
+    }
+
+    pub fn matches(&self, message: &DecodedMessage) -> bool {
+        if !self.kinds.is_empty() && !self.kinds.contains(&message.kind) {
+            return false
+        }
+
+        if self.channels.is_empty() {
+            return true
+        }
+
+        match message.channel {
+            Some(channel) => self.channels.contains(&channel),
+            None => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedMessage {
+    pub kind: MessageKind,
+    pub channel: Option<u8>,
+    pub description: String,
+}
+
+/// Prints every message from the selected input until the user presses enter
+pub fn monitor_midi_input(ports: &MidiPortOptions, filter: MonitorFilter, middle_c: MiddleC) -> Result<(), ProgramError> {
+    cprintln!("\n<green>MONITORING MIDI INPUT</>");
+
+    let midi_in = get_midi_input()?;
+    let input_source = get_midi_input_source(&midi_in, ports)?;
+    let port_name = midi_input_source_name(&midi_in, &input_source);
+
+    let _conn_in = connect_midi_input(midi_in, input_source, move |stamp, message, _| {
+        let Some(decoded) = decode_midi_message(message, middle_c) else {
+            // There's no kind or channel to filter on, so these are only shown when nothing is filtered out
+            if filter.is_empty() {
+                println!("{:>10.3}  {:<20} {}", stamp as f64 / 1_000_000.0, "Unknown", format_bytes(message));
+            }
+            return
+        };
+
+        if filter.matches(&decoded) {
+            println!("{:>10.3}  {}", stamp as f64 / 1_000_000.0, decoded.description);
+        }
+    })?;
+
+    println!("Listening to '{}' (press enter to stop) ...", port_name);
+
+    let mut input = String::new();
+    match stdin().read_line(&mut input) {
+        Ok(_) => (),
+        Err(e) => return_err!(Io, format!("failed to read line from stdin: {e}"), e),
+    }
+
+    Ok(())
+}
+
+/// Decodes a message into a readable line.
+/// Returns None for messages that can't be parsed.
+pub fn decode_midi_message(message: &[u8], middle_c: MiddleC) -> Option<DecodedMessage> {
+    let parsed = MidiMessage::parse(message).ok()?;
+
+    let (kind, name, detail) = match &parsed {
+        MidiMessage::NoteOn { note, velocity, .. } => (MessageKind::Note, "Note On", format!("{:<4} velocity {}", note_name(*note, middle_c), velocity)),
+        MidiMessage::NoteOff { note, velocity, .. } => (MessageKind::Note, "Note Off", format!("{:<4} velocity {}", note_name(*note, middle_c), velocity)),
+        MidiMessage::PolyAftertouch { note, pressure, .. } => (MessageKind::PolyAftertouch, "Poly Aftertouch", format!("{:<4} pressure {}", note_name(*note, middle_c), pressure)),
+        MidiMessage::ControlChange { controller, value, .. } => (MessageKind::ControlChange, "Control Change", format!("CC {:<3} {:<22} {}", controller, control_name(*controller), value)),
+        MidiMessage::ProgramChange { program, .. } => (MessageKind::ProgramChange, "Program Change", format!("program {}", program)),
+        MidiMessage::ChannelAftertouch { pressure, .. } => (MessageKind::ChannelAftertouch, "Channel Aftertouch", format!("pressure {}", pressure)),
+        MidiMessage::PitchBend { value, .. } => (MessageKind::PitchBend, "Pitch Bend", format!("{:+}", value)),
+        MidiMessage::SysEx(data) => decode_sysex(message, data),
+        MidiMessage::MtcQuarterFrame(value) => (MessageKind::Common, "MTC Quarter Frame", format!("piece {} value {}", value >> 4, value & 0x0F)),
+        MidiMessage::SongPosition(beats) => (MessageKind::Common, "Song Position", format!("{} beats", beats)),
+        MidiMessage::SongSelect(song) => (MessageKind::Common, "Song Select", format!("song {}", song)),
+        MidiMessage::TuneRequest => (MessageKind::Common, "Tune Request", String::new()),
+        MidiMessage::Clock => (MessageKind::Realtime, "Clock", String::new()),
+        MidiMessage::Start => (MessageKind::Realtime, "Start", String::new()),
+        MidiMessage::Continue => (MessageKind::Realtime, "Continue", String::new()),
+        MidiMessage::Stop => (MessageKind::Realtime, "Stop", String::new()),
+        MidiMessage::ActiveSensing => (MessageKind::Realtime, "Active Sensing", String::new()),
+        MidiMessage::Reset => (MessageKind::Realtime, "Reset", String::new()),
+    };
+
+    let channel = parsed.channel();
+    let description = match channel {
+        Some(channel) => format!("{:<20} ch {:<3} {}", name, channel, detail),
+        None => format!("{:<20} {}", name, detail).trim_end().to_string(),
+    };
+
+    Some(DecodedMessage {
+        kind,
+        channel,
+        description,
+    })
+}
+
+fn decode_sysex(message: &[u8], data: &[u8]) -> (MessageKind, &'static str, String) {
+    if let Some((stop, on)) = sysex_to_organ_stop(message) {
+        (MessageKind::SysEx, "Organ Stop", format!("{} {}", stop, if on { "on" } else { "off" }))
+    } else if let Some(show_control) = decode_show_control(data) {
+        (MessageKind::SysEx, "MIDI Show Control", show_control)
+    } else {
+        (MessageKind::SysEx, "SysEx", format_bytes(message))
+    }
+}
+
+// MIDI Show Control is a universal real time SysEx message:
+// F0 7F <device id> 02 <command format> <command> <data> F7
+// Cue data is ASCII cue number, list and path separated by 00
+fn decode_show_control(data: &[u8]) -> Option<String> {
+    let (device_id, command_format, command, cue_data) = match data {
+        [0x7F, device_id, 0x02, command_format, command, cue_data @ ..] => (*device_id, *command_format, *command, cue_data),
+        _ => return None,
+    };
+
+    let command_name = match command {
+        0x01 => "GO",
+        0x02 => "STOP",
+        0x03 => "RESUME",
+        0x04 => "TIMED_GO",
+        0x05 => "LOAD",
+        0x06 => "SET",
+        0x07 => "FIRE",
+        0x08 => "ALL_OFF",
+        0x09 => "RESTORE",
+        0x0A => "RESET",
+        0x0B => "GO_OFF",
+        0x10 => "GO/JAM_CLOCK",
+        0x11 => "STANDBY_+",
+        0x12 => "STANDBY_-",
+        0x13 => "SEQUENCE_+",
+        0x14 => "SEQUENCE_-",
+        0x15 => "START_CLOCK",
+        0x16 => "STOP_CLOCK",
+        0x17 => "ZERO_CLOCK",
+        0x18 => "SET_CLOCK",
+        0x19 => "MTC_CHASE_ON",
+        0x1A => "MTC_CHASE_OFF",
+        0x1B => "OPEN_CUE_LIST",
+        0x1C => "CLOSE_CUE_LIST",
+        0x1D => "OPEN_CUE_PATH",
+        0x1E => "CLOSE_CUE_PATH",
+        _ => "UNKNOWN",
+    };
+
+    let format_name = match command_format {
+        0x01 => "Lighting",
+        0x02 => "Moving Lights",
+        0x10 => "Sound",
+        0x7F => "All",
+        _ => "Other",
+    };
+
+    let mut description = format!("{} ({}) device {}", command_name, format_name, device_id);
+
+    // The cue fields only mean something for the cue based commands
+    if matches!(command, 0x01..=0x05 | 0x0B | 0x1B..=0x1E) {
+        let fields = ["cue", "list", "path"];
+
+        for (field, value) in fields.iter().zip(cue_data.split(|byte| *byte == 0x00)) {
+            if !value.is_empty() {
+                description.push_str(&format!(" {} {}", field, String::from_utf8_lossy(value)));
+            }
+        }
+    }
+
+    Some(description)
+}
+
+fn control_name(controller: u8) -> &'static str {
+    match controller {
+        0 => "Bank Select",
+        1 => "Modulation",
+        2 => "Breath",
+        4 => "Foot Pedal",
+        5 => "Portamento Time",
+        6 => "Data Entry",
+        7 => "Volume",
+        8 => "Balance",
+        10 => "Pan",
+        11 => "Expression",
+        32 => "Bank Select LSB",
+        64 => "Sustain",
+        65 => "Portamento",
+        66 => "Sostenuto",
+        67 => "Soft Pedal",
+        68 => "Legato",
+        69 => "Hold 2",
+        71 => "Resonance",
+        72 => "Release Time",
+        73 => "Attack Time",
+        74 => "Cutoff",
+        84 => "Portamento Control",
+        91 => "Reverb",
+        93 => "Chorus",
+        96 => "Data Increment",
+        97 => "Data Decrement",
+        98 => "NRPN LSB",
+        99 => "NRPN MSB",
+        100 => "RPN LSB",
+        101 => "RPN MSB",
+        120 => "All Sound Off",
+        121 => "Reset All Controllers",
+        122 => "Local Control",
+        123 => "All Notes Off",
+        124 => "Omni Off",
+        125 => "Omni On",
+        126 => "Mono On",
+        127 => "Poly On",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::organ::organ_midi::organ_stop_to_sysex;
+    use crate::organ::stops_table::OrganStop;
+
+    fn show_control(command: u8, cue_data: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x7F, 0x01, 0x02, 0x01, command];
+        data.extend_from_slice(cue_data);
+        data
+    }
+
+    #[test]
+    fn channel_messages_are_decoded_with_their_channel() {
+        let decoded = decode_midi_message(&[0x90, 61, 100], MiddleC::C4).unwrap();
+        assert_eq!(decoded, DecodedMessage {
+            kind: MessageKind::Note,
+            channel: Some(1),
+            description: "Note On              ch 1   C#4  velocity 100".to_string(),
+        });
+
+        let decoded = decode_midi_message(&[0xBF, 64, 127], MiddleC::C3).unwrap();
+        assert_eq!(decoded.kind, MessageKind::ControlChange);
+        assert_eq!(decoded.channel, Some(16));
+        assert_eq!(decoded.description, "Control Change       ch 16  CC 64  Sustain                127");
+
+        let decoded = decode_midi_message(&[0xF8], MiddleC::C4).unwrap();
+        assert_eq!(decoded, DecodedMessage { kind: MessageKind::Realtime, channel: None, description: "Clock".to_string() });
+    }
+
+    #[test]
+    fn messages_that_dont_parse_arent_decoded() {
+        assert_eq!(decode_midi_message(&[], MiddleC::C4), None);
+        assert_eq!(decode_midi_message(&[0x90, 60], MiddleC::C4), None);
+        assert_eq!(decode_midi_message(&[60, 100], MiddleC::C4), None);
+    }
+
+    #[test]
+    fn show_control_go_has_its_cue_list_and_path() {
+        assert_eq!(
+            decode_show_control(&show_control(0x01, b"12.5\x003\x007")).unwrap(),
+            "GO (Lighting) device 1 cue 12.5 list 3 path 7"
+        );
+
+        // Missing or empty fields are left out
+        assert_eq!(decode_show_control(&show_control(0x01, b"4")).unwrap(), "GO (Lighting) device 1 cue 4");
+        assert_eq!(decode_show_control(&show_control(0x01, b"\x002")).unwrap(), "GO (Lighting) device 1 list 2");
+        assert_eq!(decode_show_control(&show_control(0x01, b"")).unwrap(), "GO (Lighting) device 1");
+
+        // Commands without cues ignore the data
+        assert_eq!(decode_show_control(&show_control(0x08, b"12")).unwrap(), "ALL_OFF (Lighting) device 1");
+
+        assert_eq!(decode_show_control(&[0x7F, 0x7F, 0x02, 0x10, 0x02]).unwrap(), "STOP (Sound) device 127");
+        assert_eq!(decode_show_control(&[0x7F, 0x01, 0x02, 0x01, 0x7E]).unwrap(), "UNKNOWN (Lighting) device 1");
+    }
+
+    #[test]
+    fn other_sysex_isnt_show_control() {
+        assert_eq!(decode_show_control(&[0x7E, 0x7F, 0x06, 0x01]), None);
+        assert_eq!(decode_show_control(&[0x7F, 0x01, 0x01, 0x01, 0x01]), None);
+        assert_eq!(decode_show_control(&[0x7F, 0x01, 0x02, 0x01]), None);
+    }
+
+    #[test]
+    fn sysex_is_decoded_as_show_control_organ_stops_or_bytes() {
+        let mut message = vec![0xF0];
+        message.extend(show_control(0x01, b"1\x002"));
+        message.push(0xF7);
+
+        let decoded = decode_midi_message(&message, MiddleC::C4).unwrap();
+        assert_eq!(decoded, DecodedMessage {
+            kind: MessageKind::SysEx,
+            channel: None,
+            description: "MIDI Show Control    GO (Lighting) device 1 cue 1 list 2".to_string(),
+        });
+
+        let decoded = decode_midi_message(&organ_stop_to_sysex(OrganStop::SoloTuba8, true), MiddleC::C4).unwrap();
+        assert_eq!(decoded.kind, MessageKind::SysEx);
+        assert_eq!(decoded.description, "Organ Stop           Solo Tuba 8 on");
+
+        let decoded = decode_midi_message(&organ_stop_to_sysex(OrganStop::SoloTuba8, false), MiddleC::C4).unwrap();
+        assert_eq!(decoded.description, "Organ Stop           Solo Tuba 8 off");
+
+        let decoded = decode_midi_message(&[0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], MiddleC::C4).unwrap();
+        assert_eq!(decoded.description, "SysEx                F0 7E 7F 06 01 F7");
+    }
+
+    #[test]
+    fn filters_match_on_kind_and_channel() {
+        let note = decode_midi_message(&[0x92, 60, 100], MiddleC::C4).unwrap();
+        let clock = decode_midi_message(&[0xF8], MiddleC::C4).unwrap();
+
+        let everything = MonitorFilter::default();
+        assert!(everything.is_empty());
+        assert!(everything.matches(&note) && everything.matches(&clock));
+
+        let notes = MonitorFilter { kinds: vec![MessageKind::Note], channels: Vec::new() };
+        assert!(!notes.is_empty());
+        assert!(notes.matches(&note) && !notes.matches(&clock));
+
+        // Messages without a channel are hidden once channels are filtered
+        let channel_3 = MonitorFilter { kinds: Vec::new(), channels: vec![3] };
+        assert!(channel_3.matches(&note) && !channel_3.matches(&clock));
+        assert!(!MonitorFilter { kinds: Vec::new(), channels: vec![1] }.matches(&note));
+    }
+}
@@ -0,0 +1,288 @@
+// Finds the local network interfaces, so the app IP can be picked to match the desk's network
+// instead of being hard-coded.
+
+use std::net::Ipv4Addr;
+use color_print::cprintln;
+use log::{info, warn};
+use crate::errors::ProgramError;
+use crate::{return_err, DeskRole, DeskTarget};
+
+/// An IPv4 address on one of this machine's network interfaces
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub address: Ipv4Addr,
+    pub prefix_length: u8,
+    pub broadcast: Option<Ipv4Addr>,
+    pub is_up: bool,
+}
+
+impl NetworkInterface {
+    /// True if the address is on this interface's subnet, including its broadcast address
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        let mask = match self.prefix_length {
+            0 => 0,
+            length => u32::MAX << (32 - length.min(32) as u32),
+        };
+
+        u32::from(self.address) & mask == u32::from(address) & mask
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.address.is_loopback()
+    }
+}
+
+/// Lists every IPv4 address on this machine
+pub fn list_interfaces() -> Result<Vec<NetworkInterface>, ProgramError> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(i) => i,
+        Err(e) => return_err!(NetworkBind, format!("failed to list network interfaces: {e}"), e),
+    };
+
+    Ok(interfaces
+        .into_iter()
+        .filter_map(|interface| {
+            let is_up = interface.is_oper_up();
+
+            match interface.addr {
+                if_addrs::IfAddr::V4(address) => Some(NetworkInterface {
+                    name: interface.name,
+                    address: address.ip,
+                    prefix_length: address.prefixlen,
+                    broadcast: address.broadcast,
+                    is_up,
+                }),
+                if_addrs::IfAddr::V6(_) => None,
+            }
+        })
+        .collect())
+}
+
+/// Works out which local IP to send from.
+/// A configured IP is checked to make sure it belongs to this machine,
+/// otherwise the interface on the same subnet as the desks is used.
+pub fn resolve_app_ip(configured: Option<Ipv4Addr>, desk_targets: &[DeskTarget]) -> Result<Ipv4Addr, ProgramError> {
+    let interfaces = list_interfaces()?;
+
+    match configured {
+        Some(app_ip) => {
+            validate_app_ip(app_ip, desk_targets, &interfaces)?;
+            Ok(app_ip)
+        }
+
+        None => {
+            let app_ip = select_app_ip(desk_targets, &interfaces)?;
+            info!("Automatically selected app IP {}", app_ip);
+            Ok(app_ip)
+        }
+    }
+}
+
+/// Picks the interface that shares a subnet with the desks,
+/// preferring the main desks over backups.
+/// Multicast and 255.255.255.255 aren't on any subnet, so when every desk target is one of those
+/// the only interface that isn't loopback is used, if there is just one.
+pub fn select_app_ip(desk_targets: &[DeskTarget], interfaces: &[NetworkInterface]) -> Result<Ipv4Addr, ProgramError> {
+    let mut desks: Vec<&DeskTarget> = desk_targets.iter().filter(|desk| desk.enabled).collect();
+    desks.sort_by_key(|desk| desk.role == DeskRole::Backup);
+
+    for desk in desks {
+        let interface = interfaces
+            .iter()
+            .filter(|interface| !interface.is_loopback() || desk.address.is_loopback())
+            .find(|interface| interface.is_up && interface.contains(desk.address));
+
+        if let Some(interface) = interface {
+            return Ok(interface.address)
+        }
+    }
+
+    let mut enabled = desk_targets.iter().filter(|desk| desk.enabled).peekable();
+    let only_group_targets = enabled.peek().is_some() && enabled.all(|desk| desk.address.is_multicast() || desk.address.is_broadcast());
+
+    if only_group_targets {
+        let mut candidates = interfaces.iter().filter(|interface| interface.is_up && !interface.is_loopback());
+
+        match (candidates.next(), candidates.next()) {
+            (Some(interface), None) => {
+                info!("Desk targets are all multicast or broadcast, using the only interface {} ({})", interface.name, interface.address);
+                return Ok(interface.address)
+            }
+            (Some(_), Some(_)) => return_err!(NetworkBind, format!(
+                "the desk targets ({}) are all multicast or broadcast, so they don't say which interface to send from. \
+                Set the app IP with --app-ip or in the show file.\nAvailable interfaces: {}",
+                format_desk_addresses(desk_targets),
+                format_interfaces(interfaces)
+            )),
+            (None, _) => (),
+        }
+    }
+
+    return_err!(NetworkBind, format!(
+        "none of this machine's network interfaces are on the same network as the desk ({}). \
+        Connect to the desk's network or set the app IP in the show file.\nAvailable interfaces: {}",
+        format_desk_addresses(desk_targets),
+        format_interfaces(interfaces)
+    ))
+}
+
+/// Makes sure the app IP belongs to this machine before anything tries to bind to it
+pub fn validate_app_ip(app_ip: Ipv4Addr, desk_targets: &[DeskTarget], interfaces: &[NetworkInterface]) -> Result<(), ProgramError> {
+    let interface = match interfaces.iter().find(|interface| interface.address == app_ip) {
+        Some(i) => i,
+        None => return_err!(NetworkBind, format!(
+            "app IP {app_ip} is not an address on this machine.\nAvailable interfaces: {}",
+            format_interfaces(interfaces)
+        )),
+    };
+
+    if !interface.is_up {
+        warn!("Interface {} ({}) is down", interface.name, app_ip);
+    }
+
+    // Might still work if there's a router in between, so this is only a warning
+    for desk in desk_targets.iter().filter(|desk| desk.enabled && !desk.address.is_multicast()) {
+        if !interface.contains(desk.address) {
+            warn!("Desk {} is not on the same subnet as app IP {}/{}", desk.address, app_ip, interface.prefix_length);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints every interface, marking the ones on the same network as the desks
+pub fn print_interfaces(desk_targets: &[DeskTarget]) -> Result<(), ProgramError> {
+    let interfaces = list_interfaces()?;
+
+    cprintln!("\n<yellow, bold>Network interfaces</>");
+
+    if interfaces.is_empty() {
+        println!("No IPv4 interfaces found");
+        return Ok(())
+    }
+
+    let selected = select_app_ip(desk_targets, &interfaces).ok();
+
+    for interface in &interfaces {
+        let broadcast = match interface.broadcast {
+            Some(broadcast) => format!("broadcast {broadcast}"),
+            None => String::new(),
+        };
+
+        let desks: Vec<String> = desk_targets
+            .iter()
+            .filter(|desk| interface.contains(desk.address))
+            .map(|desk| desk.address.to_string())
+            .collect();
+
+        let mut notes = Vec::new();
+        if !interface.is_up {
+            notes.push(String::from("down"));
+        }
+        if !desks.is_empty() {
+            notes.push(format!("desk {}", desks.join(", ")));
+        }
+        if selected == Some(interface.address) {
+            notes.push(String::from("selected"));
+        }
+
+        println!(
+            "{:<16} {:<20} {:<26} {}",
+            interface.name,
+            format!("{}/{}", interface.address, interface.prefix_length),
+            broadcast,
+            notes.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn format_interfaces(interfaces: &[NetworkInterface]) -> String {
+    if interfaces.is_empty() {
+        return String::from("none")
+    }
+
+    interfaces
+        .iter()
+        .map(|interface| format!("{} {}/{}", interface.name, interface.address, interface.prefix_length))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn format_desk_addresses(desk_targets: &[DeskTarget]) -> String {
+    desk_targets
+        .iter()
+        .map(|desk| desk.address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, address: Ipv4Addr, prefix_length: u8) -> NetworkInterface {
+        NetworkInterface {
+            name: String::from(name),
+            address,
+            prefix_length,
+            broadcast: None,
+            is_up: true,
+        }
+    }
+
+    #[test]
+    fn desk_subnet_picks_the_interface() {
+        let interfaces = [
+            interface("lo", Ipv4Addr::LOCALHOST, 8),
+            interface("wlan0", Ipv4Addr::new(192, 168, 1, 20), 24),
+            interface("eth0", Ipv4Addr::new(2, 0, 0, 10), 8),
+        ];
+
+        let desks = [DeskTarget::new(Ipv4Addr::new(2, 0, 0, 35))];
+        assert_eq!(select_app_ip(&desks, &interfaces).unwrap(), Ipv4Addr::new(2, 0, 0, 10));
+
+        // The subnet broadcast address is on the subnet too
+        let desks = [DeskTarget::new(Ipv4Addr::new(2, 255, 255, 255))];
+        assert_eq!(select_app_ip(&desks, &interfaces).unwrap(), Ipv4Addr::new(2, 0, 0, 10));
+    }
+
+    #[test]
+    fn broadcast_and_multicast_targets_use_the_only_interface() {
+        let interfaces = [
+            interface("lo", Ipv4Addr::LOCALHOST, 8),
+            interface("eth0", Ipv4Addr::new(2, 0, 0, 10), 8),
+        ];
+
+        let desks = [DeskTarget::new(Ipv4Addr::BROADCAST), DeskTarget::backup(Ipv4Addr::new(239, 1, 1, 1))];
+        assert_eq!(select_app_ip(&desks, &interfaces).unwrap(), Ipv4Addr::new(2, 0, 0, 10));
+    }
+
+    #[test]
+    fn broadcast_targets_with_several_interfaces_need_an_app_ip() {
+        let mut interfaces = vec![
+            interface("lo", Ipv4Addr::LOCALHOST, 8),
+            interface("eth0", Ipv4Addr::new(2, 0, 0, 10), 8),
+            interface("wlan0", Ipv4Addr::new(192, 168, 1, 20), 24),
+        ];
+
+        let desks = [DeskTarget::new(Ipv4Addr::BROADCAST)];
+        let error = select_app_ip(&desks, &interfaces).unwrap_err();
+        assert_eq!(error.kind(), crate::errors::ErrorKind::NetworkBind);
+        assert!(error.to_string().contains("--app-ip"));
+
+        // Interfaces that are down don't count
+        interfaces[2].is_up = false;
+        assert_eq!(select_app_ip(&desks, &interfaces).unwrap(), Ipv4Addr::new(2, 0, 0, 10));
+    }
+
+    #[test]
+    fn unicast_desk_off_every_subnet_is_an_error() {
+        let interfaces = [interface("eth0", Ipv4Addr::new(2, 0, 0, 10), 8)];
+        let desks = [DeskTarget::new(Ipv4Addr::new(10, 0, 0, 35))];
+
+        assert!(select_app_ip(&desks, &interfaces).is_err());
+    }
+}
@@ -28,48 +28,71 @@ F0-2B-01-01-22/23-nn-nn-F7
 nn-nn = internal stop number
 
 */
+#[cfg(feature = "midir-io")]
 use std::io::stdin;
+#[cfg(feature = "midir-io")]
 use color_print::cprintln;
+#[cfg(feature = "midir-io")]
+use log::{debug, trace};
+#[cfg(feature = "midir-io")]
 use crate::errors::ProgramError;
-use crate::midi_io::{get_midi_input, get_midi_input_port, get_midi_output};
-use crate::midi_utils::{is_on_status, status_channel};
+#[cfg(feature = "midir-io")]
+use crate::midi_io::{connect_midi_input, create_virtual_output, get_midi_input, get_midi_input_source, get_midi_output, midi_input_source_name};
+use crate::midi_utils::MidiMessage;
 use crate::organ::stops_table::{OrganStop, TOTAL_STOPS};
-use crate::return_err;
+#[cfg(feature = "midir-io")]
+use crate::recorder::RecorderHandle;
+#[cfg(feature = "midir-io")]
+use crate::{return_err, MidiPortOptions};
+
+/// Port name used in recordings for messages converted for the organ
+pub const ORGAN_OUTPUT_RECORDING_PORT: &str = "Organ output";
 
 // Some test bindings of MIDI notes
-pub fn play_organ(control_stops: bool) -> Result<(), ProgramError> {
+#[cfg(feature = "midir-io")]
+pub fn play_organ(control_stops: bool, ports: &MidiPortOptions, recorder: Option<RecorderHandle>) -> Result<(), ProgramError> {
     cprintln!("\n<green>RUNNING ORGAN MIDI CONTROL</>");
 
-    let mut conn_out = get_midi_output()?;
+    let mut conn_out = match &ports.virtual_output {
+        Some(name) => create_virtual_output(name, ports.connect_output.as_ref())?,
+        None => get_midi_output(ports.output.as_ref())?,
+    };
+
     let midi_in = get_midi_input()?;
-    let in_port = get_midi_input_port(&midi_in)?;
+    let input_source = get_midi_input_source(&midi_in, ports)?;
+    let port_name = midi_input_source_name(&midi_in, &input_source);
 
-    let _conn_in = match midi_in.connect(
-        &in_port,
-        "midir-read-input",
+    let _conn_in = connect_midi_input(
+        midi_in,
+        input_source,
         move |stamp, message, _| {
+            if let Some(recorder) = &recorder {
+                recorder.record(stamp, &port_name, message);
+            }
+
             let midi_message = midi_to_organ_note(message, control_stops);
 
-            println!("MIDI message received: {:?}", message);
+            trace!("MIDI message received: {:?}", message);
 
             if message != midi_message {
-                println!("MIDI message converted: {:?}", midi_message);
+                debug!("MIDI message converted: {:?}", midi_message);
+
+                // Converted messages (stop SysEx) get their own track when exported
+                if let Some(recorder) = &recorder {
+                    recorder.record(stamp, ORGAN_OUTPUT_RECORDING_PORT, &midi_message);
+                }
             }
 
             // Pass this midi message through to the output
             let _ = conn_out.send(&midi_message);
         },
-        (),
-    ) {
-        Ok(connection) => connection,
-        Err(e) => return_err!(&format!("failed to connect: {}", e))
-    };
+    )?;
 
     // Just wait for the user to press a key, other than the exit button to exit
     let mut input = String::new();
     match stdin().read_line(&mut input) {
         Ok(_) => (),
-        Err(e) => return_err!(format!("failed to read line from stdin: {e}")),
+        Err(e) => return_err!(Io, format!("failed to read line from stdin: {e}"), e),
     }
 
     Ok(())
@@ -78,19 +101,12 @@ pub fn play_organ(control_stops: bool) -> Result<(), ProgramError> {
 // Converts the MIDI note to an organ MIDI note
 // This translates certain MIDI info to Sysex Commands for stops,
 // And converts velocity data to expression data instead (organ ignored velocity)
-const TEMP_ORGAN_STOP_CHANNEL: u8 = 2;
-
 pub fn midi_to_organ_note(message: &[u8], control_stops: bool) -> Vec<u8> {
-    let mut converted_midi_message = message.to_vec();
-
-    let note_number = match message.get(1) {
-        Some(note) => *note,
-        None => return converted_midi_message,
-    };
-
-    let status = match message.get(0) {
-        Some(status) => *status,
-        None => return converted_midi_message,
+    // Anything that isn't a note (or can't be parsed) is passed straight through
+    let (note_number, on) = match MidiMessage::parse(message) {
+        Ok(MidiMessage::NoteOn { note, .. }) => (note, true),
+        Ok(MidiMessage::NoteOff { note, .. }) => (note, false),
+        _ => return message.to_vec(),
     };
 
     // For testing, we will arbitrarily turn certain notes into Sysex stop commands
@@ -102,74 +118,66 @@ pub fn midi_to_organ_note(message: &[u8], control_stops: bool) -> Vec<u8> {
     // Return a note converted to an organ Sysex message
     if note_number < TOTAL_STOPS && control_stops {
         // Convert the MIDI note to an organ stop number
-        let stop_number = match OrganStop::from_u8(note_number) {
-            Some(stop) => stop,
-            None => return converted_midi_message,
-        };
-
-        let on = is_on_status(status);
-
-        return organ_stop_to_sysex(stop_number, on)
+        if let Some(stop) = OrganStop::from_u8(note_number) {
+            return organ_stop_to_sysex(stop, on)
+        }
     }
 
     // Otherwise just pass the MIDI data through
     // Could do weird stuff like convert velocity to expression data for the organ,
     // but I'm sure organists would hate this.
-    converted_midi_message
+    message.to_vec()
 }
 
-pub fn sysex_to_organ_stop(sysex_message: &[u8]) -> Option<OrganStop> {
-
-    // Check if on or off
-    let on: bool = match sysex_message.get(4) {
-        Some(22) => true,
-        Some(23) => false,
+/// Reads a stop SysEx message in the format written by organ_stop_to_sysex.
+/// Returns the stop and whether it's being turned on.
+pub fn sysex_to_organ_stop(sysex_message: &[u8]) -> Option<(OrganStop, bool)> {
+    let data = match MidiMessage::parse(sysex_message) {
+        Ok(MidiMessage::SysEx(data)) => data,
         _ => return None,
     };
 
-    // Only need the second number as the first is always 0
-    match sysex_message.get(6) {
-        Some(stop_number) => OrganStop::from_u8(*stop_number),
-        None => None
-    }
+    // Manufacturer, device and model, on or off, then the stop number.
+    // The first stop number is always 0, the stop is split across the next two bytes
+    let (on, high, low) = match data.as_slice() {
+        [0x2B, 0x01, 0x01, 0x23, 0x00, high, low] => (true, *high, *low),
+        [0x2B, 0x01, 0x01, 0x22, 0x00, high, low] => (false, *high, *low),
+        _ => return None,
+    };
 
+    OrganStop::from_u8((high << 4) | (low & 0x0F)).map(|stop| (stop, on))
 }
 
 pub fn organ_stop_to_sysex(organ_stop: OrganStop, on: bool) -> Vec<u8> {
     // Provide the correct sysex command for the organ stop
-    // 22 if on is true, 23 if on is false
+    // 23 if on is true, 22 if on is false
 
-    let mut sysex_message: Vec<u8> = Vec::with_capacity(9);
-
-    // Start of Sysex Message
-    sysex_message.push(0xF0);
+    let mut sysex_data: Vec<u8> = Vec::with_capacity(7);
 
     // Manufacturer ID
-    sysex_message.push(0x2B);
+    sysex_data.push(0x2B);
 
     // Device ID
-    sysex_message.push(0x01);
+    sysex_data.push(0x01);
 
     // Model / Function Group
-    sysex_message.push(0x01);
+    sysex_data.push(0x01);
 
     // Stop On/Off
-    sysex_message.push(if on { 0x23 } else { 0x22 });
+    sysex_data.push(if on { 0x23 } else { 0x22 });
 
     // First stop number (always 0)
-    sysex_message.push(0x00);
+    sysex_data.push(0x00);
 
     // Second Stop Number
     // For this Viscount organ SysEx, you do not encode the stop number as little-endian.
     // You encode the MIDI stop number MSB first, then LSB (big-endian–style).
     let stop_number = organ_stop as u8;
-    sysex_message.push(stop_number >> 4);
+    sysex_data.push(stop_number >> 4);
 
     // Padding
-    sysex_message.push(stop_number & 0x0F);
-
-    // End of Sysex Message
-    sysex_message.push(0xF7);
+    sysex_data.push(stop_number & 0x0F);
 
-    sysex_message
+    // Start and end of SysEx are added around the data
+    MidiMessage::SysEx(sysex_data).to_bytes()
 }
\ No newline at end of file
@@ -398,6 +398,13 @@ impl OrganStop {
             _ => None, // invalid value (e.g., 2, or > 124)
         }
     }
+
+    /// The stop with this name, as written by Display (e.g. "Solo Tuba 8")
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..TOTAL_STOPS)
+            .filter_map(Self::from_u8)
+            .find(|stop| stop.to_string() == name)
+    }
 }
 
 impl Display for OrganStop {
@@ -530,4 +537,20 @@ impl Display for OrganStop {
         };
         write!(f, "{}", name)
     }
-}
\ No newline at end of file
+}
+
+// Stops are written by name, so saved stops still mean the same thing if the numbers change
+#[cfg(feature = "serde")]
+impl serde::Serialize for OrganStop {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for OrganStop {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name: String = serde::Deserialize::deserialize(deserializer)?;
+        OrganStop::from_name(&name).ok_or_else(|| serde::de::Error::custom(format!("'{name}' is not an organ stop")))
+    }
+}
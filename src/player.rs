@@ -0,0 +1,379 @@
+// Plays a Standard MIDI File in real time, so a show can run from a file with no performer.
+// The player doesn't know where messages go, it just hands each one to an output function
+// (the Chamsys runtime, or the organ output after conversion).
+// Only playing a file to those needs the cli feature, the Player itself works with any output function.
+
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use log::{debug, info};
+use crate::midi_utils::{is_off_status, is_on_status};
+use crate::smf::TimedEvent;
+#[cfg(feature = "cli")]
+use std::io::stdin;
+#[cfg(feature = "cli")]
+use std::net::Ipv4Addr;
+#[cfg(feature = "cli")]
+use std::path::Path;
+#[cfg(feature = "cli")]
+use color_print::cprintln;
+#[cfg(feature = "cli")]
+use crate::errors::ProgramError;
+#[cfg(feature = "cli")]
+use crate::midi_io::{create_virtual_output, get_midi_output};
+#[cfg(feature = "cli")]
+use crate::organ::organ_midi::midi_to_organ_note;
+#[cfg(feature = "cli")]
+use crate::smf::read_midi_file;
+#[cfg(feature = "cli")]
+use crate::{print_runtime_stats, print_simulation_summary, BankSwitching, DeskTarget, MappingBank, MidiPortOptions, MidiRuntime, OutputMode, Setlist};
+
+/// Slowest and fastest playback speeds, anything outside them is clamped
+pub const MIN_SPEED: f64 = 0.01;
+pub const MAX_SPEED: f64 = 100.0;
+
+/// Where the messages from a MIDI file are sent
+#[cfg(feature = "cli")]
+pub enum PlaybackTarget {
+    // Through the Chamsys translation, as if played on the input port
+    Chamsys { desk_targets: Vec<DeskTarget>, app_ip: Ipv4Addr, output_mode: OutputMode, banks: Vec<MappingBank>, bank_switching: BankSwitching, setlist: Setlist },
+
+    // Through the organ conversion to a MIDI output port
+    Organ { control_stops: bool },
+}
+
+#[derive(Clone, Debug)]
+pub struct PlaybackOptions {
+    // Where in the file to start playing from
+    pub start_offset: Duration,
+
+    // Go back to the start offset when the end of the file is reached
+    pub looping: bool,
+
+    // 1.0 is the speed written in the file, 2.0 is twice as fast, between MIN_SPEED and MAX_SPEED
+    pub speed: f64,
+}
+
+impl Default for PlaybackOptions {
+    fn default() -> Self {
+        Self {
+            start_offset: Duration::ZERO,
+            looping: false,
+            speed: 1.0,
+        }
+    }
+}
+
+/// Plays a MIDI file to the target until it ends or the user presses enter
+#[cfg(feature = "cli")]
+pub fn play_midi_file(path: &Path, target: PlaybackTarget, options: PlaybackOptions, ports: &MidiPortOptions) -> Result<(), ProgramError> {
+    cprintln!("\n<green>PLAYING MIDI FILE</>");
+
+    let midi_file = read_midi_file(path)?;
+    let events = midi_file.timed_events();
+
+    info!(
+        "Loaded '{}': format {}, {} tracks, {} events, {:.1}s long",
+        path.display(),
+        midi_file.format,
+        midi_file.tracks.len(),
+        events.len(),
+        midi_file.duration_us() as f64 / 1_000_000.0
+    );
+
+    // The runtime has to stay alive while the file plays
+    let (player, runtime) = match target {
+        PlaybackTarget::Chamsys { desk_targets, app_ip, output_mode, banks, bank_switching, setlist } => {
+            let runtime = MidiRuntime::create_without_input(desk_targets, app_ip, output_mode)?;
+            runtime.set_banks(banks, bank_switching);
+            runtime.set_setlist(setlist);
+            (Player::start(events, options, runtime.midi_feed()), Some(runtime))
+        }
+
+        PlaybackTarget::Organ { control_stops } => {
+            let mut conn_out = match &ports.virtual_output {
+                Some(name) => create_virtual_output(name, ports.connect_output.as_ref())?,
+                None => get_midi_output(ports.output.as_ref())?,
+            };
+
+            let player = Player::start(events, options, move |message| {
+                let _ = conn_out.send(&midi_to_organ_note(message, control_stops));
+            });
+
+            (player, None)
+        }
+    };
+
+    println!("Playing, press enter to stop ...");
+
+    // Stdin is read on its own thread so playback can also end by reaching the end of the file
+    let (enter_tx, enter_rx) = mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        let mut input = String::new();
+        let _ = stdin().read_line(&mut input);
+        let _ = enter_tx.send(());
+    });
+
+    while !player.is_finished() {
+        if enter_rx.recv_timeout(Duration::from_millis(100)).is_ok() {
+            break;
+        }
+    }
+
+    player.stop();
+
+    if let Some(runtime) = runtime {
+        print_simulation_summary(&runtime);
+        print_runtime_stats(&runtime);
+        runtime.stop();
+    }
+
+    Ok(())
+}
+
+pub struct Player {
+    stop_tx: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Player {
+    /// Starts playing the events on a new thread.
+    /// Events must be sorted by time, as returned by MidiFile::timed_events.
+    pub fn start<F>(events: Vec<TimedEvent>, options: PlaybackOptions, output: F) -> Player
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+        let thread = std::thread::spawn(move || {
+            play_events(&events, &options, output, stop_rx);
+        });
+
+        Player {
+            stop_tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// True once the end of the file has been reached (never, when looping)
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|thread| thread.is_finished())
+    }
+
+    /// Blocks until the player reaches the end of the file
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Stops playback, releasing any notes that are still held
+    pub fn stop(mut self) {
+        let _ = self.stop_tx.send(());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        let _ = self.stop_tx.send(());
+    }
+}
+
+fn play_events<F: FnMut(&[u8])>(
+    events: &[TimedEvent],
+    options: &PlaybackOptions,
+    mut output: F,
+    stop_rx: mpsc::Receiver<()>,
+) {
+    let speed = if options.speed.is_finite() && options.speed > 0.0 { options.speed.clamp(MIN_SPEED, MAX_SPEED) } else { 1.0 };
+    let start_offset_us = options.start_offset.as_micros() as u64;
+
+    // (channel, note) for every note that has been turned on but not off yet
+    let mut held_notes: HashSet<(u8, u8)> = HashSet::new();
+
+    loop {
+        info!("Playing MIDI file from {:.1}s at {}x speed", options.start_offset.as_secs_f64(), speed);
+
+        // Anything before the start offset that isn't a note is sent straight away,
+        // so stops, program changes and controllers are where they should be when playback starts
+        for event in events.iter().take_while(|event| event.time_us < start_offset_us) {
+            if !is_note_message(&event.message) {
+                output(&event.message);
+            }
+        }
+
+        // Nothing left to play after the start offset
+        if events.last().is_none_or(|event| event.time_us < start_offset_us) {
+            return
+        }
+
+        let started = Instant::now();
+
+        for event in events.iter().skip_while(|event| event.time_us < start_offset_us) {
+            // So far in the future it can't be waited for, nothing after it can be played either
+            let file_time = (event.time_us - start_offset_us) as f64 / 1_000_000.0;
+            let Some(play_at) = Duration::try_from_secs_f64(file_time / speed).ok().and_then(|wait| started.checked_add(wait)) else {
+                break
+            };
+
+            let now = Instant::now();
+            if play_at > now {
+                match stop_rx.recv_timeout(play_at - now) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => (),
+
+                    // Stop was asked for, or the player was dropped
+                    _ => {
+                        release_held_notes(&mut held_notes, &mut output);
+                        debug!("MIDI file playback stopped");
+                        return
+                    }
+                }
+            }
+
+            track_held_note(&event.message, &mut held_notes);
+            output(&event.message);
+        }
+
+        release_held_notes(&mut held_notes, &mut output);
+
+        if !options.looping {
+            info!("Reached the end of the MIDI file");
+            return
+        }
+
+        if stop_rx.try_recv().is_ok() {
+            return
+        }
+    }
+}
+
+fn is_note_message(message: &[u8]) -> bool {
+    message.first().is_some_and(|status| is_on_status(*status) || is_off_status(*status))
+}
+
+fn track_held_note(message: &[u8], held_notes: &mut HashSet<(u8, u8)>) {
+    let (status, note, velocity) = match message {
+        [status, note, velocity, ..] => (*status, *note, *velocity),
+        _ => return,
+    };
+
+    let channel = status & 0x0F;
+
+    if is_on_status(status) && velocity > 0 {
+        held_notes.insert((channel, note));
+    } else if is_on_status(status) || is_off_status(status) {
+        held_notes.remove(&(channel, note));
+    }
+}
+
+fn release_held_notes<F: FnMut(&[u8])>(held_notes: &mut HashSet<(u8, u8)>, output: &mut F) {
+    for (channel, note) in held_notes.drain() {
+        output(&[0x80 | channel, note, 0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(time_ms: u64, message: &[u8]) -> TimedEvent {
+        TimedEvent { time_us: time_ms * 1000, message: message.to_vec() }
+    }
+
+    // Plays to the end, returning every message with how long after starting it was sent
+    fn play(events: Vec<TimedEvent>, options: PlaybackOptions) -> Vec<(Duration, Vec<u8>)> {
+        let (tx, rx) = mpsc::channel();
+        let started = Instant::now();
+
+        Player::start(events, options, move |message| {
+            let _ = tx.send((started.elapsed(), message.to_vec()));
+        })
+        .wait();
+
+        rx.into_iter().collect()
+    }
+
+    fn messages(played: &[(Duration, Vec<u8>)]) -> Vec<Vec<u8>> {
+        played.iter().map(|(_, message)| message.clone()).collect()
+    }
+
+    #[test]
+    fn start_offset_skips_notes_but_not_controllers() {
+        let events = vec![
+            event(0, &[0xB0, 7, 100]),
+            event(0, &[0x90, 60, 100]),
+            event(50, &[0xC0, 5]),
+            event(100, &[0x90, 62, 100]),
+            event(150, &[0x80, 62, 0]),
+        ];
+
+        let options = PlaybackOptions { start_offset: Duration::from_millis(100), ..PlaybackOptions::default() };
+        let played = play(events, options);
+
+        assert_eq!(messages(&played), vec![vec![0xB0, 7, 100], vec![0xC0, 5], vec![0x90, 62, 100], vec![0x80, 62, 0]]);
+
+        // The first note is played straight away, times count from the start offset
+        assert!(played[2].0 < Duration::from_millis(40));
+        assert!(played[3].0 >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn start_offset_past_the_end_plays_nothing() {
+        let events = vec![event(0, &[0xB0, 7, 100]), event(10, &[0x90, 60, 100])];
+        let options = PlaybackOptions { start_offset: Duration::from_secs(60), ..PlaybackOptions::default() };
+
+        assert_eq!(messages(&play(events, options)), vec![vec![0xB0, 7, 100]]);
+    }
+
+    #[test]
+    fn speed_scales_the_time_between_events() {
+        let events = vec![event(0, &[0x90, 60, 100]), event(400, &[0x80, 60, 0])];
+        let options = PlaybackOptions { speed: 4.0, ..PlaybackOptions::default() };
+
+        let played = play(events, options);
+        let gap = played[1].0 - played[0].0;
+
+        assert!(gap >= Duration::from_millis(100), "{gap:?}");
+        assert!(gap < Duration::from_millis(300), "{gap:?}");
+    }
+
+    #[test]
+    fn stopping_releases_held_notes() {
+        let events = vec![event(0, &[0x91, 60, 100]), event(0, &[0x91, 64, 100]), event(0, &[0x81, 64, 0]), event(10_000, &[0x81, 60, 0])];
+        let (tx, rx) = mpsc::channel();
+
+        let player = Player::start(events, PlaybackOptions::default(), move |message| {
+            let _ = tx.send(message.to_vec());
+        });
+
+        for _ in 0..3 {
+            rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        player.stop();
+
+        // Only the note still held, long before the file would have let it go
+        assert_eq!(rx.into_iter().collect::<Vec<_>>(), vec![vec![0x81, 60, 0]]);
+    }
+
+    #[test]
+    fn extreme_speeds_and_times_dont_panic() {
+        let events = vec![event(0, &[0x90, 60, 100]), TimedEvent { time_us: u64::MAX / 2, message: vec![0x80, 60, 0] }];
+
+        for speed in [1e-300, 0.0, -1.0, f64::NAN, 1e300] {
+            let (stop_tx, stop_rx) = mpsc::channel();
+            stop_tx.send(()).unwrap();
+
+            let mut sent = Vec::new();
+            let options = PlaybackOptions { speed, ..PlaybackOptions::default() };
+            play_events(&events, &options, |message| sent.push(message.to_vec()), stop_rx);
+
+            assert_eq!(sent, vec![vec![0x90, 60, 100], vec![0x80, 60, 0]], "speed {speed}");
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Picks a MIDI port from what's available without asking the user,
+// so the program can be started from a script or as a service.
+
+// Selectors are written as:
+// 2                 -> the port at this index in the port list
+// Launchkey MIDI 1  -> a port with exactly this name, or else the only port with this in its name (ignoring case)
+// /Launch.*MIDI/    -> the only port with a name matching this regex
+
+use std::fmt;
+use regex::Regex;
+use crate::errors::ProgramError;
+use crate::return_err;
+
+#[derive(Clone, Debug)]
+pub enum PortSelector {
+    Index(usize),
+    Name(String),
+    Pattern(Regex),
+}
+
+impl PortSelector {
+    pub fn parse(selector: &str) -> Result<PortSelector, ProgramError> {
+        let selector = selector.trim();
+
+        if selector.is_empty() {
+            return_err!(Config, "port selector is empty")
+        }
+
+        if let Ok(index) = selector.parse::<usize>() {
+            return Ok(PortSelector::Index(index))
+        }
+
+        if let Some(pattern) = selector.strip_prefix('/').and_then(|s| s.strip_suffix('/')) {
+            return match Regex::new(pattern) {
+                Ok(regex) => Ok(PortSelector::Pattern(regex)),
+                Err(e) => return_err!(Config, format!("invalid port pattern '{selector}': {e}"), e),
+            }
+        }
+
+        Ok(PortSelector::Name(selector.to_owned()))
+    }
+
+    /// Finds the index of the selected port in the list of port names.
+    /// The error lists every available port, so the user can see what to choose instead.
+    pub fn select(&self, port_names: &[String]) -> Result<usize, ProgramError> {
+        let matches: Vec<usize> = match self {
+            PortSelector::Index(index) => {
+                if *index < port_names.len() {
+                    vec![*index]
+                } else {
+                    Vec::new()
+                }
+            }
+
+            PortSelector::Name(name) => {
+                match port_names.iter().position(|port_name| port_name == name) {
+                    Some(index) => vec![index],
+                    None => {
+                        let name = name.to_lowercase();
+                        port_names
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, port_name)| port_name.to_lowercase().contains(&name))
+                            .map(|(index, _)| index)
+                            .collect()
+                    }
+                }
+            }
+
+            PortSelector::Pattern(regex) => port_names
+                .iter()
+                .enumerate()
+                .filter(|(_, port_name)| regex.is_match(port_name))
+                .map(|(index, _)| index)
+                .collect(),
+        };
+
+        match matches.as_slice() {
+            [index] => Ok(*index),
+            [] => {
+                let all: Vec<usize> = (0..port_names.len()).collect();
+                return_err!(PortSelection, format!("no MIDI port matches '{}'\n{}", self, list_ports(port_names, &all)))
+            }
+            _ => return_err!(PortSelection, format!(
+                "more than one MIDI port matches '{}', be more specific\n{}",
+                self,
+                list_ports(port_names, &matches)
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortSelector::Index(index) => write!(f, "{}", index),
+            PortSelector::Name(name) => write!(f, "{}", name),
+            PortSelector::Pattern(regex) => write!(f, "/{}/", regex.as_str()),
+        }
+    }
+}
+
+// Written the same way as on the command line and in show files, e.g. "/Launch.*MIDI/"
+#[cfg(feature = "serde")]
+impl serde::Serialize for PortSelector {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PortSelector {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text: String = serde::Deserialize::deserialize(deserializer)?;
+        PortSelector::parse(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+fn list_ports(port_names: &[String], indices: &[usize]) -> String {
+    if indices.is_empty() {
+        return String::from("No MIDI ports are available")
+    }
+
+    let mut list = String::from("Available ports:");
+    for index in indices {
+        list.push_str(&format!("\n  {}: {}", index, port_names[*index]));
+    }
+
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    fn ports() -> Vec<String> {
+        ["Midi Through Port-0", "Launchkey MIDI 1", "Launchkey MIDI 2", "Launchkey"]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    fn select(selector: &str) -> Result<usize, ProgramError> {
+        PortSelector::parse(selector).unwrap().select(&ports())
+    }
+
+    #[test]
+    fn selectors_are_parsed_by_their_form() {
+        assert!(matches!(PortSelector::parse(" 2 ").unwrap(), PortSelector::Index(2)));
+        assert!(matches!(PortSelector::parse("Launchkey").unwrap(), PortSelector::Name(name) if name == "Launchkey"));
+        assert!(matches!(PortSelector::parse("/Launch.*/").unwrap(), PortSelector::Pattern(regex) if regex.as_str() == "Launch.*"));
+
+        assert_eq!(PortSelector::parse("  ").unwrap_err().kind(), ErrorKind::Config);
+        assert_eq!(PortSelector::parse("/(/").unwrap_err().kind(), ErrorKind::Config);
+    }
+
+    #[test]
+    fn index() {
+        assert_eq!(select("3").unwrap(), 3);
+        assert_eq!(select("4").unwrap_err().kind(), ErrorKind::PortSelection);
+        assert_eq!(PortSelector::Index(0).select(&[]).unwrap_err().kind(), ErrorKind::PortSelection);
+    }
+
+    #[test]
+    fn an_exact_name_wins_over_names_containing_it() {
+        assert_eq!(select("Launchkey").unwrap(), 3);
+        assert_eq!(select("Launchkey MIDI 2").unwrap(), 2);
+    }
+
+    #[test]
+    fn part_of_a_name_ignores_case() {
+        assert_eq!(select("through").unwrap(), 0);
+        assert_eq!(select("midi 1").unwrap(), 1);
+
+        // Launchkey MIDI 1 and 2
+        assert_eq!(select("launchkey midi").unwrap_err().kind(), ErrorKind::PortSelection);
+    }
+
+    #[test]
+    fn pattern() {
+        assert_eq!(select("/MIDI 2$/").unwrap(), 2);
+        assert_eq!(select("/^Launchkey$/").unwrap(), 3);
+
+        // Case sensitive unless the pattern says otherwise
+        assert!(select("/^launchkey$/").is_err());
+        assert_eq!(select("/(?i)^launchkey$/").unwrap(), 3);
+    }
+
+    #[test]
+    fn errors_list_the_ports() {
+        let e = select("Keystation").unwrap_err();
+        assert_eq!(e.to_string(), concat!(
+            "no MIDI port matches 'Keystation'\n",
+            "Available ports:\n",
+            "  0: Midi Through Port-0\n",
+            "  1: Launchkey MIDI 1\n",
+            "  2: Launchkey MIDI 2\n",
+            "  3: Launchkey",
+        ));
+
+        // Only the ports that matched, so it's clear what to narrow down
+        let e = select("/MIDI \\d/").unwrap_err();
+        assert_eq!(e.to_string(), concat!(
+            "more than one MIDI port matches '/MIDI \\d/', be more specific\n",
+            "Available ports:\n",
+            "  1: Launchkey MIDI 1\n",
+            "  2: Launchkey MIDI 2",
+        ));
+
+        let e = PortSelector::Index(0).select(&[]).unwrap_err();
+        assert_eq!(e.to_string(), "no MIDI port matches '0'\nNo MIDI ports are available");
+    }
+}
@@ -0,0 +1,300 @@
+// Records incoming MIDI to a file so a rehearsal can be reproduced later without the band.
+// Messages are handed to a writer thread so the MIDI callback never waits on the disk.
+// Recordings can be exported as Standard MIDI Files to be opened and edited in a DAW.
+// Only recording from a port needs midir-io, writing, reading and exporting recordings work without it.
+
+// Recording file format (plain text, one message per line):
+// # midilx recording
+// # started <local time>
+// <timestamp in microseconds>\t<source port name>\t<message bytes in hex>
+// e.g. "1520331\tLaunchkey MIDI\t90 30 7F"
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use chrono::Local;
+use log::{error, info, warn};
+use crate::errors::ProgramError;
+use crate::midi_utils::format_bytes;
+use crate::return_err;
+use crate::smf::{tempo_meta_data, write_midi_file, Division, MidiFile, TrackEvent, TrackEventKind, META_TEMPO, META_TRACK_NAME};
+#[cfg(feature = "midir-io")]
+use std::io::stdin;
+#[cfg(feature = "midir-io")]
+use color_print::cprintln;
+#[cfg(feature = "midir-io")]
+use crate::midi_io::{connect_error_source, get_midi_input, get_midi_input_port, INPUT_PORT_NAME};
+#[cfg(feature = "midir-io")]
+use crate::MidiPortOptions;
+
+const RECORDING_HEADER: &str = "# midilx recording";
+
+/// Ticks per quarter note used for exported MIDI files
+const EXPORT_TICKS_PER_QUARTER: u16 = 480;
+
+/// Records everything from the selected input port until the user presses enter
+#[cfg(feature = "midir-io")]
+pub fn record_midi_input(path: &Path, ports: &MidiPortOptions) -> Result<(), ProgramError> {
+    cprintln!("\n<green>RECORDING MIDI INPUT</>");
+
+    let recorder = Recorder::start(path)?;
+    let recorder_handle = recorder.handle();
+
+    let midi_in = get_midi_input()?;
+    let in_port = get_midi_input_port(&midi_in, ports.input.as_ref())?;
+    let port_name = midi_in.port_name(&in_port).unwrap_or_default();
+
+    let conn_in = match midi_in.connect(
+        &in_port,
+        INPUT_PORT_NAME,
+        move |stamp, message, _| {
+            recorder_handle.record(stamp, &port_name, message);
+        },
+        (),
+    ) {
+        Ok(connection) => connection,
+        Err(e) => return_err!(Connection, format!("failed to connect: {}", e), connect_error_source(&e))
+    };
+
+    println!("Recording, press enter to stop ...");
+
+    let mut input = String::new();
+    match stdin().read_line(&mut input) {
+        Ok(_) => (),
+        Err(e) => return_err!(Io, format!("failed to read line from stdin: {e}"), e),
+    }
+
+    // Closing the connection drops the callback's handle so the recording can finish
+    conn_in.close();
+    recorder.finish();
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordedMessage {
+    // Microseconds, as reported by the MIDI backend for the port
+    pub stamp: u64,
+    pub port: String,
+    pub message: Vec<u8>,
+}
+
+pub struct Recorder {
+    tx: mpsc::Sender<RecordedMessage>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Creates the recording file and starts the writer thread
+    pub fn start(path: &Path) -> Result<Recorder, ProgramError> {
+        let file = match File::create(path) {
+            Ok(f) => f,
+            Err(e) => return_err!(Io, format!("failed to create recording file '{}': {e}", path.display()), e),
+        };
+
+        let mut writer = BufWriter::new(file);
+
+        let header = format!("{}\n# started {}\n", RECORDING_HEADER, Local::now().to_rfc3339());
+        match writer.write_all(header.as_bytes()) {
+            Ok(_) => (),
+            Err(e) => return_err!(Io, format!("failed to write recording header: {e}"), e),
+        }
+
+        let (tx, rx) = mpsc::channel::<RecordedMessage>();
+        let path_name = path.display().to_string();
+
+        let writer_thread = std::thread::spawn(move || {
+            let mut count: usize = 0;
+
+            // Runs until every sender has been dropped
+            for recorded in rx {
+                if let Err(e) = writeln!(writer, "{}", format_recorded_message(&recorded)) {
+                    error!("Failed to write to recording '{}': {}", path_name, e);
+                    break;
+                }
+
+                count += 1;
+            }
+
+            if let Err(e) = writer.flush() {
+                error!("Failed to flush recording '{}': {}", path_name, e);
+            }
+
+            info!("Recording '{}' closed with {} messages", path_name, count);
+        });
+
+        info!("Recording MIDI input to '{}'", path.display());
+
+        Ok(Recorder {
+            tx,
+            writer_thread: Some(writer_thread),
+        })
+    }
+
+    /// Returns a handle that can be moved into a MIDI input callback
+    pub fn handle(&self) -> RecorderHandle {
+        RecorderHandle { tx: self.tx.clone() }
+    }
+
+    /// Stops recording and waits for everything to be written to the file.
+    /// Handles given out by this recorder must be dropped first (closing their MIDI connections does this).
+    pub fn finish(mut self) {
+        self.close();
+    }
+
+    fn close(&mut self) {
+        // Replace our sender with a dead one so the writer thread sees the channel close
+        let (dead_tx, _) = mpsc::channel();
+        self.tx = dead_tx;
+
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[derive(Clone)]
+pub struct RecorderHandle {
+    tx: mpsc::Sender<RecordedMessage>,
+}
+
+impl RecorderHandle {
+    pub fn record(&self, stamp: u64, port: &str, message: &[u8]) {
+        let _ = self.tx.send(RecordedMessage {
+            stamp,
+            port: port.to_owned(),
+            message: message.to_vec(),
+        });
+    }
+}
+
+fn format_recorded_message(recorded: &RecordedMessage) -> String {
+    format!("{}\t{}\t{}", recorded.stamp, recorded.port, format_bytes(&recorded.message))
+}
+
+/// Reads every message back out of a recording file
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedMessage>, ProgramError> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return_err!(Io, format!("failed to open recording '{}': {e}", path.display()), e),
+    };
+
+    let mut messages = Vec::new();
+
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => return_err!(Io, format!("failed to read recording '{}': {e}", path.display()), e),
+        };
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_recorded_message(&line) {
+            Some(recorded) => messages.push(recorded),
+            None => warn!("Skipping unreadable line {} in recording '{}'", line_number + 1, path.display()),
+        }
+    }
+
+    Ok(messages)
+}
+
+fn parse_recorded_message(line: &str) -> Option<RecordedMessage> {
+    let mut fields = line.splitn(3, '\t');
+
+    let stamp = fields.next()?.parse::<u64>().ok()?;
+    let port = fields.next()?.to_owned();
+
+    let mut message = Vec::new();
+    for byte in fields.next()?.split_whitespace() {
+        message.push(u8::from_str_radix(byte, 16).ok()?);
+    }
+
+    Some(RecordedMessage { stamp, port, message })
+}
+
+/// Exports a recording file as a Standard MIDI File at the given tempo
+pub fn export_recording(recording_path: &Path, midi_path: &Path, bpm: f64) -> Result<(), ProgramError> {
+    let messages = read_recording(recording_path)?;
+
+    if messages.is_empty() {
+        return_err!(Parse, format!("recording '{}' has no messages to export", recording_path.display()))
+    }
+
+    let midi_file = recording_to_midi_file(&messages, bpm);
+    write_midi_file(midi_path, &midi_file)?;
+
+    info!(
+        "Exported {} messages from '{}' to '{}'",
+        messages.len(),
+        recording_path.display(),
+        midi_path.display()
+    );
+
+    Ok(())
+}
+
+/// Turns recorded messages into a format 1 MIDI file.
+/// The first track holds the tempo, then every port in the recording gets its own track named after it.
+pub fn recording_to_midi_file(messages: &[RecordedMessage], bpm: f64) -> MidiFile {
+    let bpm = if bpm > 0.0 { bpm } else { 120.0 };
+    let first_stamp = messages.iter().map(|recorded| recorded.stamp).min().unwrap_or(0);
+    let ticks_per_micro = EXPORT_TICKS_PER_QUARTER as f64 * bpm / 60_000_000.0;
+
+    let tempo_track = vec![
+        TrackEvent { delta: 0, kind: TrackEventKind::Meta(META_TEMPO, tempo_meta_data(bpm)) },
+    ];
+
+    let mut tracks = vec![tempo_track];
+
+    // Ports in the order they first appear in the recording
+    let mut ports: Vec<&str> = Vec::new();
+    for recorded in messages {
+        if !ports.contains(&recorded.port.as_str()) {
+            ports.push(&recorded.port);
+        }
+    }
+
+    for port in ports {
+        let mut track = vec![
+            TrackEvent { delta: 0, kind: TrackEventKind::Meta(META_TRACK_NAME, port.as_bytes().to_vec()) },
+        ];
+
+        // Absolute ticks are rounded rather than each delta, so timing doesn't drift over a long take
+        let mut previous_tick: u64 = 0;
+
+        for recorded in messages.iter().filter(|recorded| recorded.port == port) {
+            let kind = match recorded.message.first() {
+                Some(0x80..=0xEF) => TrackEventKind::Midi(recorded.message.clone()),
+                Some(0xF0) => TrackEventKind::SysEx(recorded.message.clone()),
+
+                // System common and realtime messages can only be stored as escaped bytes
+                Some(_) => TrackEventKind::Escape(recorded.message.clone()),
+                None => continue,
+            };
+
+            let tick = ((recorded.stamp - first_stamp) as f64 * ticks_per_micro).round() as u64;
+            let delta = tick.saturating_sub(previous_tick) as u32;
+            previous_tick = previous_tick.max(tick);
+
+            track.push(TrackEvent { delta, kind });
+        }
+
+        tracks.push(track);
+    }
+
+    MidiFile {
+        format: 1,
+        division: Division::TicksPerQuarter(EXPORT_TICKS_PER_QUARTER),
+        tracks,
+    }
+}
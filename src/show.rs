@@ -0,0 +1,597 @@
+// The show file holds everything needed to run a show, so it can be started without any questions.
+// It's a TOML file:
+//
+// [midi]
+// in_port = "Launchkey"     # port index, exact name, part of the name or /regex/
+// out_port = 1
+// middle_c = "C3"           # optional, what note 60 is called (C3 or C4, default C4)
+// client_name = "lights"    # optional, the MIDI client ports are opened from, e.g. "lights:in" in JACK
+// connect_out = "fluidsynth:.*"  # optional, regex for the ports a virtual output is connected to (JACK only)
+//
+// [lx]
+// first_playback_note = "C3"  # note that controls PB1, a name or a note number
+//
+// [[bank]]                  # optional, banks of mappings to switch between, e.g. one for each song
+// name = "Song 1"
+// program = 0               # program change that switches to it, defaults to its position (from 0), no two banks can share one
+// first_playback_note = "C3"  # defaults to lx.first_playback_note
+// first_playback = 11       # the playback first_playback_note controls, defaults to 1
+//
+// [bank.mappings]
+// "C4" = "activate"         # note name or number = activate, deactivate or intensity, for the note's own playback
+//                           # activate and deactivate latch, intensity sets the level from the velocity
+//
+// [bank_switching]
+// channel = 16              # optional, only switch banks from this channel
+// next_note = "C-1"         # optional notes that step through the banks
+// previous_note = "C#-1"
+// select_cc = 20            # optional, the CC value picks the bank by position (from 0)
+// release_on_switch = true  # release what the old bank activated when switching
+//
+// [[song]]                  # optional, the setlist in the order it's played
+// name = "Opener"
+// bank = "Song 1"           # optional, bank switched to when the song starts
+// tempo = 128               # optional, BPM shown while it's playing
+// commands = ["1G", "2,100L"]  # optional, MagicQ commands sent when the song starts
+//
+// [setlist]
+// channel = 16              # optional, only step through the setlist from this channel
+// next_note = "D-1"         # optional notes that step through the songs
+// previous_note = "D#-1"
+// next_cc = 64              # optional, a footswitch CC that starts the next song when pressed
+//
+// [network]
+// app_ip = "2.0.0.1"        # optional, picked from the interface on the desk's network if left out
+//
+// [[desk]]
+// address = "2.0.0.35"      # a desk, subnet broadcast address or multicast group
+//
+// [[desk]]
+// address = "2.0.0.36"
+// backup = true             # only used while no other desk can be reached
+// enabled = true
+
+use std::net::Ipv4Addr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use regex::Regex;
+use toml::{Table, Value};
+use crate::errors::ProgramError;
+use crate::magicq::MagicqCommand;
+use crate::midi_utils::{parse_note, MiddleC};
+use crate::port_selector::PortSelector;
+use crate::{return_err, BankSwitching, DeskRole, DeskTarget, LxCommand, MappingBank, Setlist, Song};
+
+#[derive(Clone, Debug, Default)]
+pub struct ShowFile {
+    pub in_port: Option<PortSelector>,
+    pub out_port: Option<PortSelector>,
+    pub client_name: Option<String>,
+    pub connect_output: Option<Regex>,
+
+    // Empty if the show file doesn't list any desks
+    pub desks: Vec<DeskTarget>,
+
+    pub app_ip: Option<Ipv4Addr>,
+
+    // Note names in the show file are read with this middle C
+    pub middle_c: Option<MiddleC>,
+    pub first_playback_note: Option<u8>,
+
+    // Empty if the show file doesn't have any banks
+    pub banks: Vec<MappingBank>,
+    pub bank_switching: BankSwitching,
+
+    // Empty if the show file doesn't have any songs
+    pub setlist: Setlist,
+}
+
+pub fn load_show_file(path: &Path) -> Result<ShowFile, ProgramError> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => return_err!(Config, format!("failed to read show file '{}': {e}", path.display()), e),
+    };
+
+    match parse_show_file(&text) {
+        Ok(show) => Ok(show),
+        Err(e) => return_err!(Config, format!("invalid show file '{}': {e}", path.display()), e),
+    }
+}
+
+pub fn parse_show_file(text: &str) -> Result<ShowFile, ProgramError> {
+    let table = match text.parse::<Table>() {
+        Ok(t) => t,
+        Err(e) => return_err!(Config, format!("{e}"), e),
+    };
+
+    let mut show = ShowFile::default();
+
+    if let Some(midi) = get_table(&table, "midi")? {
+        show.in_port = get_port_selector(midi, "midi.in_port")?;
+        show.out_port = get_port_selector(midi, "midi.out_port")?;
+
+        show.middle_c = match midi.get("middle_c") {
+            None => None,
+            Some(Value::String(middle_c)) => Some(MiddleC::parse(middle_c)?),
+            Some(_) => return_err!(Config, "'midi.middle_c' should be \"C3\" or \"C4\""),
+        };
+
+        show.client_name = match midi.get("client_name") {
+            None => None,
+            Some(Value::String(client_name)) if !client_name.is_empty() => Some(client_name.to_owned()),
+            Some(_) => return_err!(Config, "'midi.client_name' should be a name"),
+        };
+
+        show.connect_output = match midi.get("connect_out") {
+            None => None,
+            Some(Value::String(pattern)) => match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(e) => return_err!(Config, format!("invalid pattern '{pattern}' for 'midi.connect_out': {e}"), e),
+            },
+            Some(_) => return_err!(Config, "'midi.connect_out' should be a regex for port names"),
+        };
+    }
+
+    if let Some(lx) = get_table(&table, "lx")? {
+        show.first_playback_note = get_note(lx, "lx.first_playback_note", show.middle_c.unwrap_or_default())?;
+    }
+
+    if let Some(network) = get_table(&table, "network")? {
+        show.app_ip = match network.get("app_ip") {
+            None => None,
+            Some(Value::String(app_ip)) => match app_ip.parse::<Ipv4Addr>() {
+                Ok(app_ip) => Some(app_ip),
+                Err(_) => return_err!(Config, format!("'{app_ip}' is not a valid app IP")),
+            },
+            Some(_) => return_err!(Config, "'network.app_ip' should be an IP address"),
+        };
+    }
+
+    let middle_c = show.middle_c.unwrap_or_default();
+
+    match table.get("bank") {
+        None => (),
+        Some(Value::Array(banks)) => {
+            for (position, bank) in banks.iter().enumerate() {
+                match bank {
+                    Value::Table(bank) => show.banks.push(get_mapping_bank(bank, position, show.first_playback_note, middle_c)?),
+                    _ => return_err!(Config, "each 'bank' should be a table"),
+                }
+            }
+        }
+        Some(_) => return_err!(Config, "'bank' should be written as [[bank]] tables"),
+    }
+
+    // Otherwise a program change could only ever reach the first of them
+    for (position, bank) in show.banks.iter().enumerate() {
+        if let Some(program) = bank.program
+            && let Some(other) = show.banks[..position].iter().find(|other| other.program == Some(program))
+        {
+            return_err!(Config, format!(
+                "banks '{}' and '{}' both switch on program {program}, banks without a program use their position (from 0)",
+                other.name,
+                bank.name
+            ))
+        }
+    }
+
+    if let Some(switching) = get_table(&table, "bank_switching")? {
+        show.bank_switching = BankSwitching {
+            channel: get_integer(switching, "bank_switching.channel", 1..=16)?,
+            next_note: get_note(switching, "bank_switching.next_note", middle_c)?,
+            previous_note: get_note(switching, "bank_switching.previous_note", middle_c)?,
+            select_cc: get_integer(switching, "bank_switching.select_cc", 0..=127)?,
+            release_on_switch: get_bool(switching, "bank_switching.release_on_switch")?.unwrap_or(false),
+        };
+    }
+
+    match table.get("song") {
+        None => (),
+        Some(Value::Array(songs)) => {
+            for song in songs {
+                match song {
+                    Value::Table(song) => show.setlist.songs.push(get_song(song, &show.banks)?),
+                    _ => return_err!(Config, "each 'song' should be a table"),
+                }
+            }
+        }
+        Some(_) => return_err!(Config, "'song' should be written as [[song]] tables"),
+    }
+
+    if let Some(setlist) = get_table(&table, "setlist")? {
+        show.setlist.channel = get_integer(setlist, "setlist.channel", 1..=16)?;
+        show.setlist.next_note = get_note(setlist, "setlist.next_note", middle_c)?;
+        show.setlist.previous_note = get_note(setlist, "setlist.previous_note", middle_c)?;
+        show.setlist.next_cc = get_integer(setlist, "setlist.next_cc", 0..=127)?;
+    }
+
+    match table.get("desk") {
+        None => (),
+        Some(Value::Array(desks)) => {
+            for desk in desks {
+                match desk {
+                    Value::Table(desk) => show.desks.push(get_desk_target(desk)?),
+                    _ => return_err!(Config, "each 'desk' should be a table"),
+                }
+            }
+        }
+        Some(_) => return_err!(Config, "'desk' should be written as [[desk]] tables"),
+    }
+
+    Ok(show)
+}
+
+fn get_table<'a>(table: &'a Table, key: &str) -> Result<Option<&'a Table>, ProgramError> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Table(t)) => Ok(Some(t)),
+        Some(_) => return_err!(Config, format!("'{key}' should be a table")),
+    }
+}
+
+// Port selectors can be written as a number (index) or a string (name or /regex/)
+fn get_port_selector(table: &Table, full_key: &str) -> Result<Option<PortSelector>, ProgramError> {
+    let key = full_key.rsplit('.').next().unwrap_or(full_key);
+
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(selector)) => Ok(Some(PortSelector::parse(selector)?)),
+        Some(Value::Integer(index)) if *index >= 0 => Ok(Some(PortSelector::Index(*index as usize))),
+        Some(_) => return_err!(Config, format!("'{full_key}' should be a port name, /pattern/ or index")),
+    }
+}
+
+// Notes can be written as a name ("C3", "F#4") or a note number
+fn get_note(table: &Table, full_key: &str, middle_c: MiddleC) -> Result<Option<u8>, ProgramError> {
+    let key = full_key.rsplit('.').next().unwrap_or(full_key);
+
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(note)) => Ok(Some(parse_note(note, middle_c)?)),
+        Some(Value::Integer(note)) if (0..=127).contains(note) => Ok(Some(*note as u8)),
+        Some(_) => return_err!(Config, format!("'{full_key}' should be a note name like \"C3\" or a note number 0-127")),
+    }
+}
+
+fn get_mapping_bank(bank: &Table, position: usize, first_playback_note: Option<u8>, middle_c: MiddleC) -> Result<MappingBank, ProgramError> {
+    let mut mapping_bank = match bank.get("name") {
+        Some(Value::String(name)) => MappingBank::new(name),
+        Some(_) => return_err!(Config, "'bank.name' should be a string"),
+        None => return_err!(Config, "every bank needs a name"),
+    };
+
+    mapping_bank.program = match get_integer(bank, "bank.program", 0..=127)? {
+        Some(program) => Some(program),
+        None => u8::try_from(position).ok().filter(|program| *program <= 127),
+    };
+
+    if let Some(note) = get_note(bank, "bank.first_playback_note", middle_c)?.or(first_playback_note) {
+        mapping_bank.first_playback_note = note;
+    }
+
+    if let Some(playback) = get_integer(bank, "bank.first_playback", 1..=255)? {
+        mapping_bank.first_playback = playback;
+    }
+
+    match bank.get("mappings") {
+        None => (),
+        Some(Value::Table(mappings)) => {
+            for (note, command) in mappings {
+                let command = match command {
+                    Value::String(command) => LxCommand::parse(command)?,
+                    _ => return_err!(Config, format!("the mapping for '{note}' should be a command name")),
+                };
+
+                mapping_bank.mappings.insert(parse_note(note, middle_c)? as usize, command);
+            }
+        }
+        Some(_) => return_err!(Config, "'bank.mappings' should be a table"),
+    }
+
+    Ok(mapping_bank)
+}
+
+// Songs can only use banks from the same show file
+fn get_song(song: &Table, banks: &[MappingBank]) -> Result<Song, ProgramError> {
+    let mut setlist_song = match song.get("name") {
+        Some(Value::String(name)) => Song::new(name),
+        Some(_) => return_err!(Config, "'song.name' should be a string"),
+        None => return_err!(Config, "every song needs a name"),
+    };
+
+    setlist_song.bank = match song.get("bank") {
+        None => None,
+        Some(Value::String(bank)) if banks.iter().any(|b| b.name == *bank) => Some(bank.to_owned()),
+        Some(Value::String(bank)) => return_err!(Config, format!("song '{}' uses bank '{bank}', but there's no bank with that name", setlist_song.name)),
+        Some(_) => return_err!(Config, "'song.bank' should be a bank name"),
+    };
+
+    setlist_song.tempo = match song.get("tempo") {
+        None => None,
+        Some(Value::Integer(tempo)) if *tempo > 0 => Some(*tempo as f64),
+        Some(Value::Float(tempo)) if *tempo > 0.0 && tempo.is_finite() => Some(*tempo),
+        Some(_) => return_err!(Config, "'song.tempo' should be a number of beats per minute"),
+    };
+
+    match song.get("commands") {
+        None => (),
+        Some(Value::Array(commands)) => {
+            for command in commands {
+                match command {
+                    Value::String(command) => setlist_song.commands.push(MagicqCommand::parse(command)?),
+                    _ => return_err!(Config, format!("the commands for '{}' should be MagicQ commands like \"1G\"", setlist_song.name)),
+                }
+            }
+        }
+        Some(_) => return_err!(Config, "'song.commands' should be a list of commands"),
+    }
+
+    Ok(setlist_song)
+}
+
+fn get_integer(table: &Table, full_key: &str, range: RangeInclusive<u8>) -> Result<Option<u8>, ProgramError> {
+    let key = full_key.rsplit('.').next().unwrap_or(full_key);
+
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(value)) if (*range.start() as i64..=*range.end() as i64).contains(value) => Ok(Some(*value as u8)),
+        Some(_) => return_err!(Config, format!("'{full_key}' should be a number from {} to {}", range.start(), range.end())),
+    }
+}
+
+fn get_desk_target(desk: &Table) -> Result<DeskTarget, ProgramError> {
+    let address = match desk.get("address") {
+        Some(Value::String(address)) => match address.parse::<Ipv4Addr>() {
+            Ok(address) => address,
+            Err(_) => return_err!(Config, format!("'{address}' is not a valid desk address")),
+        },
+        Some(_) => return_err!(Config, "'desk.address' should be an IP address"),
+        None => return_err!(Config, "every desk needs an address"),
+    };
+
+    let mut target = DeskTarget::new(address);
+
+    if get_bool(desk, "desk.backup")?.unwrap_or(false) {
+        target.role = DeskRole::Backup;
+    }
+
+    target.enabled = get_bool(desk, "desk.enabled")?.unwrap_or(true);
+
+    Ok(target)
+}
+
+fn get_bool(table: &Table, full_key: &str) -> Result<Option<bool>, ProgramError> {
+    let key = full_key.rsplit('.').next().unwrap_or(full_key);
+
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Boolean(value)) => Ok(Some(*value)),
+        Some(_) => return_err!(Config, format!("'{full_key}' should be true or false")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    const SHOW: &str = r#"
+        [midi]
+        in_port = "Launchkey"
+        out_port = 1
+        middle_c = "C3"
+        client_name = "lights"
+        connect_out = "fluidsynth:.*"
+
+        [lx]
+        first_playback_note = "C3"
+
+        [[bank]]
+        name = "Default"
+
+        [[bank]]
+        name = "Song 1"
+        program = 5
+        first_playback_note = 48
+        first_playback = 11
+
+        [bank.mappings]
+        "C4" = "intensity"
+        "D4" = "deactivate"
+
+        [bank_switching]
+        channel = 16
+        next_note = "C-1"
+        previous_note = "C#-1"
+        select_cc = 20
+        release_on_switch = true
+
+        [[song]]
+        name = "Opener"
+        bank = "Song 1"
+        tempo = 128
+        commands = ["1G", "2,100L"]
+
+        [[song]]
+        name = "Encore"
+        tempo = 96.5
+
+        [setlist]
+        channel = 15
+        next_note = "D-1"
+        previous_note = "D#-1"
+        next_cc = 64
+
+        [network]
+        app_ip = "2.0.0.1"
+
+        [[desk]]
+        address = "2.0.0.35"
+
+        [[desk]]
+        address = "2.0.0.36"
+        backup = true
+
+        [[desk]]
+        address = "2.255.255.255"
+        enabled = false
+    "#;
+
+    fn error_kind(text: &str) -> ErrorKind {
+        match parse_show_file(text) {
+            Ok(_) => panic!("show file was accepted:\n{text}"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn ports_and_network() {
+        let show = parse_show_file(SHOW).unwrap();
+
+        assert!(matches!(&show.in_port, Some(PortSelector::Name(name)) if name == "Launchkey"));
+        assert!(matches!(show.out_port, Some(PortSelector::Index(1))));
+        assert!(matches!(
+            parse_show_file("[midi]\nin_port = \"/Launch.*/\"").unwrap().in_port,
+            Some(PortSelector::Pattern(_))
+        ));
+        assert_eq!(show.client_name.as_deref(), Some("lights"));
+        assert_eq!(show.connect_output.as_ref().map(Regex::as_str), Some("fluidsynth:.*"));
+        assert_eq!(show.app_ip, Some(Ipv4Addr::new(2, 0, 0, 1)));
+    }
+
+    #[test]
+    fn note_names_use_the_show_files_middle_c() {
+        let show = parse_show_file(SHOW).unwrap();
+
+        assert_eq!(show.middle_c, Some(MiddleC::C3));
+        assert_eq!(show.first_playback_note, Some(60));
+
+        let show = parse_show_file("[lx]\nfirst_playback_note = \"C3\"").unwrap();
+        assert_eq!(show.middle_c, None);
+        assert_eq!(show.first_playback_note, Some(48));
+    }
+
+    #[test]
+    fn desks() {
+        let show = parse_show_file(SHOW).unwrap();
+
+        assert_eq!(show.desks, vec![
+            DeskTarget::new(Ipv4Addr::new(2, 0, 0, 35)),
+            DeskTarget { address: Ipv4Addr::new(2, 0, 0, 36), enabled: true, role: DeskRole::Backup },
+            DeskTarget { address: Ipv4Addr::new(2, 255, 255, 255), enabled: false, role: DeskRole::Main },
+        ]);
+    }
+
+    #[test]
+    fn banks() {
+        let show = parse_show_file(SHOW).unwrap();
+
+        // A bank without its own first playback note uses the one from [lx]
+        let mut default = MappingBank::new("Default");
+        default.program = Some(0);
+        default.first_playback_note = 60;
+
+        let mut song = MappingBank::new("Song 1");
+        song.program = Some(5);
+        song.first_playback_note = 48;
+        song.first_playback = 11;
+        song.mappings.insert(72, LxCommand::Intensity);
+        song.mappings.insert(74, LxCommand::Deactivate);
+
+        assert_eq!(show.banks, vec![default, song]);
+    }
+
+    #[test]
+    fn banks_cant_share_a_program() {
+        // The second bank defaults to program 1, which the third asks for
+        let text = "[[bank]]\nname = \"A\"\n[[bank]]\nname = \"B\"\n[[bank]]\nname = \"C\"\nprogram = 1";
+        let e = parse_show_file(text).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::Config);
+        assert!(e.to_string().starts_with("banks 'B' and 'C' both switch on program 1"), "{e}");
+
+        let text = "[[bank]]\nname = \"A\"\nprogram = 1\n[[bank]]\nname = \"B\"\nprogram = 0";
+        let programs: Vec<_> = parse_show_file(text).unwrap().banks.iter().map(|bank| bank.program).collect();
+        assert_eq!(programs, vec![Some(1), Some(0)]);
+    }
+
+    #[test]
+    fn bank_switching() {
+        let show = parse_show_file(SHOW).unwrap();
+
+        assert_eq!(show.bank_switching, BankSwitching {
+            channel: Some(16),
+            next_note: Some(12),
+            previous_note: Some(13),
+            select_cc: Some(20),
+            release_on_switch: true,
+        });
+        assert_eq!(parse_show_file("").unwrap().bank_switching, BankSwitching::default());
+    }
+
+    #[test]
+    fn setlist() {
+        let show = parse_show_file(SHOW).unwrap();
+
+        let mut opener = Song::new("Opener");
+        opener.bank = Some(String::from("Song 1"));
+        opener.tempo = Some(128.0);
+        opener.commands = vec![MagicqCommand::Go(1), MagicqCommand::Level(2, 100)];
+
+        let mut encore = Song::new("Encore");
+        encore.tempo = Some(96.5);
+
+        assert_eq!(show.setlist, Setlist {
+            songs: vec![opener, encore],
+            channel: Some(15),
+            next_note: Some(14),
+            previous_note: Some(15),
+            next_cc: Some(64),
+        });
+    }
+
+    #[test]
+    fn an_empty_show_file_sets_nothing() {
+        let show = parse_show_file("").unwrap();
+
+        assert!(show.in_port.is_none() && show.out_port.is_none() && show.client_name.is_none() && show.connect_output.is_none());
+        assert!(show.desks.is_empty() && show.banks.is_empty());
+        assert_eq!(show.setlist, Setlist::default());
+        assert_eq!((show.app_ip, show.middle_c, show.first_playback_note), (None, None, None));
+    }
+
+    #[test]
+    fn malformed_show_files_are_errors() {
+        for (text, kind) in [
+            ("[midi", ErrorKind::Config),
+            ("midi = 1", ErrorKind::Config),
+            ("[midi]\nin_port = -1", ErrorKind::Config),
+            ("[midi]\nin_port = \"/(/\"", ErrorKind::Config),
+            ("[midi]\nclient_name = \"\"", ErrorKind::Config),
+            ("[midi]\nconnect_out = \"(\"", ErrorKind::Config),
+            ("[midi]\nmiddle_c = \"C5\"", ErrorKind::Parse),
+            ("[lx]\nfirst_playback_note = 128", ErrorKind::Config),
+            ("[lx]\nfirst_playback_note = \"H3\"", ErrorKind::Parse),
+            ("[network]\napp_ip = \"2.0.0\"", ErrorKind::Config),
+            ("[bank]\nname = \"Song 1\"", ErrorKind::Config),
+            ("[[bank]]\nprogram = 1", ErrorKind::Config),
+            ("[[bank]]\nname = \"Song 1\"\nprogram = 128", ErrorKind::Config),
+            ("[[bank]]\nname = \"Song 1\"\nfirst_playback = 0", ErrorKind::Config),
+            ("[[bank]]\nname = \"Song 1\"\n[bank.mappings]\nC4 = \"explode\"", ErrorKind::Parse),
+            ("[[bank]]\nname = \"Song 1\"\n[bank.mappings]\nC4 = 1", ErrorKind::Config),
+            ("[[bank]]\nname = \"Song 1\"\nprogram = 3\n[[bank]]\nname = \"Song 2\"\nprogram = 3", ErrorKind::Config),
+            ("[bank_switching]\nchannel = 17", ErrorKind::Config),
+            ("[bank_switching]\nrelease_on_switch = \"yes\"", ErrorKind::Config),
+            ("[[song]]\nname = \"Opener\"\nbank = \"Song 1\"", ErrorKind::Config),
+            ("[[song]]\nname = \"Opener\"\ntempo = 0", ErrorKind::Config),
+            ("[[song]]\nname = \"Opener\"\ncommands = [\"1X\"]", ErrorKind::Parse),
+            ("[[song]]\ntempo = 120", ErrorKind::Config),
+            ("[setlist]\nnext_cc = 128", ErrorKind::Config),
+            ("[[desk]]\nbackup = true", ErrorKind::Config),
+            ("[[desk]]\naddress = \"desk\"", ErrorKind::Config),
+            ("[[desk]]\naddress = \"2.0.0.35\"\nenabled = 1", ErrorKind::Config),
+        ] {
+            assert_eq!(error_kind(text), kind, "{text}");
+        }
+    }
+}
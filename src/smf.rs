@@ -0,0 +1,619 @@
+// Standard MIDI File (SMF) reading and writing.
+// Supports format 0 and format 1 files, both PPQ and SMPTE timing,
+// and turns the file into a single list of messages with real times using the file's tempo map.
+// Files are written without running status, which every DAW can read.
+
+use std::path::Path;
+use crate::errors::ProgramError;
+use crate::return_err;
+
+/// Tempo used until the file sets one (120 BPM)
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
+// Meta event types this program cares about
+pub const META_END_OF_TRACK: u8 = 0x2F;
+pub const META_TEMPO: u8 = 0x51;
+pub const META_TRACK_NAME: u8 = 0x03;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Division {
+    // Ticks per quarter note, tempo changes how long a tick is
+    TicksPerQuarter(u16),
+
+    // Fixed length ticks, tempo is ignored
+    Smpte { frames_per_second: u8, ticks_per_frame: u8 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackEventKind {
+    // Channel voice messages, including the status byte
+    Midi(Vec<u8>),
+
+    // Complete SysEx messages, starting with F0 and (normally) ending with F7
+    SysEx(Vec<u8>),
+
+    // Raw bytes from an F7 escape event, sent exactly as they are
+    Escape(Vec<u8>),
+
+    Meta(u8, Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackEvent {
+    // Ticks since the previous event in the same track
+    pub delta: u32,
+    pub kind: TrackEventKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiFile {
+    pub format: u16,
+    pub division: Division,
+    pub tracks: Vec<Vec<TrackEvent>>,
+}
+
+/// A message from the file with the time it should be played at, from the start of the file
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedEvent {
+    pub time_us: u64,
+    pub message: Vec<u8>,
+}
+
+pub fn read_midi_file(path: &Path) -> Result<MidiFile, ProgramError> {
+    let bytes = match std::fs::read(path) {
+        Ok(b) => b,
+        Err(e) => return_err!(Io, format!("failed to read MIDI file '{}': {e}", path.display()), e),
+    };
+
+    parse_midi_file(&bytes)
+}
+
+pub fn parse_midi_file(bytes: &[u8]) -> Result<MidiFile, ProgramError> {
+    let mut reader = ByteReader::new(bytes);
+
+    let (chunk_type, header) = reader.read_chunk()?;
+    if chunk_type != *b"MThd" || header.len() < 6 {
+        return_err!(Parse, "not a Standard MIDI File (missing MThd header)")
+    }
+
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let track_count = u16::from_be_bytes([header[2], header[3]]);
+    let raw_division = u16::from_be_bytes([header[4], header[5]]);
+
+    if format > 1 {
+        return_err!(Parse, format!("MIDI file format {format} is not supported (only format 0 and 1)"))
+    }
+
+    let division = if raw_division & 0x8000 == 0 {
+        if raw_division == 0 {
+            return_err!(Parse, "MIDI file has a time division of 0 ticks per quarter note")
+        }
+        Division::TicksPerQuarter(raw_division)
+    } else {
+        // Upper byte is the negative frame rate (two's complement)
+        let frames_per_second = ((raw_division >> 8) as i8).wrapping_neg() as u8;
+        let ticks_per_frame = (raw_division & 0xFF) as u8;
+        if frames_per_second == 0 || ticks_per_frame == 0 {
+            return_err!(Parse, "MIDI file has an invalid SMPTE time division")
+        }
+        Division::Smpte { frames_per_second, ticks_per_frame }
+    };
+
+    let mut tracks = Vec::with_capacity(track_count as usize);
+
+    while tracks.len() < track_count as usize && !reader.is_empty() {
+        let (chunk_type, data) = reader.read_chunk()?;
+
+        // Unknown chunks are allowed by the spec and should just be skipped
+        if chunk_type != *b"MTrk" {
+            continue;
+        }
+
+        tracks.push(parse_track(data)?);
+    }
+
+    if tracks.len() < track_count as usize {
+        return_err!(Parse, format!("MIDI file says it has {track_count} tracks but only {} were found", tracks.len()))
+    }
+
+    Ok(MidiFile { format, division, tracks })
+}
+
+fn parse_track(data: &[u8]) -> Result<Vec<TrackEvent>, ProgramError> {
+    let mut reader = ByteReader::new(data);
+    let mut events = Vec::new();
+    let mut running_status: Option<u8> = None;
+
+    while !reader.is_empty() {
+        let delta = reader.read_variable_length()?;
+        let first = reader.read_u8()?;
+
+        let kind = match first {
+            0xFF => {
+                let meta_type = reader.read_u8()?;
+                let length = reader.read_variable_length()?;
+                let meta_data = reader.read_bytes(length as usize)?.to_vec();
+
+                if meta_type == META_END_OF_TRACK {
+                    events.push(TrackEvent { delta, kind: TrackEventKind::Meta(meta_type, meta_data) });
+                    break;
+                }
+
+                TrackEventKind::Meta(meta_type, meta_data)
+            }
+
+            0xF0 => {
+                let length = reader.read_variable_length()?;
+                let mut sysex = Vec::with_capacity(length as usize + 1);
+                sysex.push(0xF0);
+                sysex.extend_from_slice(reader.read_bytes(length as usize)?);
+                running_status = None;
+                TrackEventKind::SysEx(sysex)
+            }
+
+            0xF7 => {
+                let length = reader.read_variable_length()?;
+                running_status = None;
+                TrackEventKind::Escape(reader.read_bytes(length as usize)?.to_vec())
+            }
+
+            _ => {
+                // Data byte first means the status from the previous event is reused
+                let (status, first_data) = if first & 0x80 != 0 {
+                    running_status = Some(first);
+                    (first, None)
+                } else {
+                    match running_status {
+                        Some(status) => (status, Some(first)),
+                        None => return_err!(Parse, "MIDI file track uses running status before any status byte"),
+                    }
+                };
+
+                let data_length = match status & 0xF0 {
+                    0xC0 | 0xD0 => 1,
+                    0x80..=0xE0 => 2,
+                    _ => return_err!(Parse, format!("unexpected status byte {status:#04X} in MIDI file track")),
+                };
+
+                let mut message = Vec::with_capacity(3);
+                message.push(status);
+
+                if let Some(first_data) = first_data {
+                    message.push(first_data);
+                }
+
+                while message.len() < data_length + 1 {
+                    message.push(reader.read_u8()?);
+                }
+
+                TrackEventKind::Midi(message)
+            }
+        };
+
+        events.push(TrackEvent { delta, kind });
+    }
+
+    Ok(events)
+}
+
+pub fn write_midi_file(path: &Path, midi_file: &MidiFile) -> Result<(), ProgramError> {
+    match std::fs::write(path, midi_file.to_bytes()) {
+        Ok(_) => Ok(()),
+        Err(e) => return_err!(Io, format!("failed to write MIDI file '{}': {e}", path.display()), e),
+    }
+}
+
+impl MidiFile {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let raw_division = match self.division {
+            Division::TicksPerQuarter(ticks_per_quarter) => ticks_per_quarter & 0x7FFF,
+            Division::Smpte { frames_per_second, ticks_per_frame } => {
+                ((frames_per_second as i8).wrapping_neg() as u8 as u16) << 8 | ticks_per_frame as u16
+            }
+        };
+
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&self.format.to_be_bytes());
+        bytes.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&raw_division.to_be_bytes());
+
+        for track in &self.tracks {
+            let track_data = track_to_bytes(track);
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track_data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&track_data);
+        }
+
+        bytes
+    }
+
+    /// Every playable message in the file (channel messages and SysEx) from all tracks,
+    /// sorted by the time in microseconds they should be played at.
+    pub fn timed_events(&self) -> Vec<TimedEvent> {
+        let (events, _) = self.absolute_events();
+        let tempo_map = TempoMap::new(self.division, &events);
+
+        events
+            .into_iter()
+            .filter_map(|(tick, _, kind)| {
+                let message = match kind {
+                    TrackEventKind::Midi(message)
+                    | TrackEventKind::SysEx(message)
+                    | TrackEventKind::Escape(message) => message.clone(),
+                    TrackEventKind::Meta(..) => return None,
+                };
+
+                Some(TimedEvent { time_us: tempo_map.tick_to_micros(tick), message })
+            })
+            .collect()
+    }
+
+    /// Length of the file in microseconds, up to the last event of the longest track
+    pub fn duration_us(&self) -> u64 {
+        let (events, last_tick) = self.absolute_events();
+        TempoMap::new(self.division, &events).tick_to_micros(last_tick)
+    }
+
+    // Every event from every track with its absolute tick, sorted by tick then track,
+    // along with the tick of the last event in the file
+    fn absolute_events(&self) -> (Vec<(u64, usize, &TrackEventKind)>, u64) {
+        let mut events: Vec<(u64, usize, &TrackEventKind)> = Vec::new();
+        let mut last_tick: u64 = 0;
+
+        for (track_index, track) in self.tracks.iter().enumerate() {
+            let mut tick: u64 = 0;
+            for event in track {
+                tick += event.delta as u64;
+                events.push((tick, track_index, &event.kind));
+            }
+            last_tick = last_tick.max(tick);
+        }
+
+        // Stable sort, so events at the same tick stay in file order
+        events.sort_by_key(|(tick, track_index, _)| (*tick, *track_index));
+
+        (events, last_tick)
+    }
+}
+
+fn track_to_bytes(track: &[TrackEvent]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut has_end_of_track = false;
+
+    for event in track {
+        write_variable_length(&mut bytes, event.delta);
+
+        match &event.kind {
+            TrackEventKind::Midi(message) => bytes.extend_from_slice(message),
+
+            // Stored as F0, length, then everything after the F0
+            TrackEventKind::SysEx(message) => {
+                let data = message.get(1..).unwrap_or_default();
+                bytes.push(0xF0);
+                write_variable_length(&mut bytes, data.len() as u32);
+                bytes.extend_from_slice(data);
+            }
+
+            TrackEventKind::Escape(data) => {
+                bytes.push(0xF7);
+                write_variable_length(&mut bytes, data.len() as u32);
+                bytes.extend_from_slice(data);
+            }
+
+            TrackEventKind::Meta(meta_type, data) => {
+                bytes.push(0xFF);
+                bytes.push(*meta_type);
+                write_variable_length(&mut bytes, data.len() as u32);
+                bytes.extend_from_slice(data);
+
+                if *meta_type == META_END_OF_TRACK {
+                    has_end_of_track = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    // Every track has to finish with an end of track event
+    if !has_end_of_track {
+        write_variable_length(&mut bytes, 0);
+        bytes.extend_from_slice(&[0xFF, META_END_OF_TRACK, 0x00]);
+    }
+
+    bytes
+}
+
+fn write_variable_length(bytes: &mut Vec<u8>, value: u32) {
+    // Only 28 bits fit in a variable length value
+    let value = value & 0x0FFF_FFFF;
+
+    let mut groups = [0u8; 4];
+    let mut count = 0;
+    let mut remaining = value;
+
+    loop {
+        groups[count] = (remaining & 0x7F) as u8;
+        count += 1;
+        remaining >>= 7;
+
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    for i in (0..count).rev() {
+        let continues = if i > 0 { 0x80 } else { 0x00 };
+        bytes.push(groups[i] | continues);
+    }
+}
+
+/// Tempo meta event data for a tempo in beats per minute
+pub fn tempo_meta_data(bpm: f64) -> Vec<u8> {
+    let micros_per_quarter = (60_000_000.0 / bpm).round().clamp(1.0, 0xFF_FFFF as f64) as u32;
+    micros_per_quarter.to_be_bytes()[1..].to_vec()
+}
+
+// Converts ticks to microseconds, taking every tempo change into account
+struct TempoMap {
+    division: Division,
+
+    // (tick the tempo starts at, microseconds at that tick, microseconds per quarter note)
+    segments: Vec<(u64, u64, u32)>,
+}
+
+impl TempoMap {
+    fn new(division: Division, sorted_events: &[(u64, usize, &TrackEventKind)]) -> Self {
+        let mut map = Self {
+            division,
+            segments: vec![(0, 0, DEFAULT_TEMPO)],
+        };
+
+        if let Division::TicksPerQuarter(_) = division {
+            for (tick, _, kind) in sorted_events {
+                if let TrackEventKind::Meta(META_TEMPO, data) = kind
+                    && data.len() >= 3
+                {
+                    let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                    let start_micros = map.tick_to_micros(*tick);
+                    map.segments.push((*tick, start_micros, tempo));
+                }
+            }
+        }
+
+        map
+    }
+
+    fn tick_to_micros(&self, tick: u64) -> u64 {
+        match self.division {
+            Division::TicksPerQuarter(ticks_per_quarter) => {
+                // Last tempo change at or before this tick
+                let (start_tick, start_micros, tempo) = self.segments
+                    .iter()
+                    .rev()
+                    .find(|(start_tick, _, _)| *start_tick <= tick)
+                    .copied()
+                    .unwrap_or((0, 0, DEFAULT_TEMPO));
+
+                // Ticks times tempo can overflow a u64 in a long file with a slow tempo.
+                // A division of 0 is refused when parsing, but a MidiFile can be built by hand.
+                let micros = (tick - start_tick) as u128 * tempo as u128 / ticks_per_quarter.max(1) as u128;
+                start_micros.saturating_add(u64::try_from(micros).unwrap_or(u64::MAX))
+            }
+
+            Division::Smpte { frames_per_second, ticks_per_frame } => {
+                // 29 means 29.97 drop frame, close enough to treat as 30 for playback
+                let frames_per_second = if frames_per_second == 29 { 30 } else { frames_per_second };
+                let micros = tick as u128 * 1_000_000 / (frames_per_second as u128 * ticks_per_frame as u128).max(1);
+                u64::try_from(micros).unwrap_or(u64::MAX)
+            }
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ProgramError> {
+        match self.bytes.get(self.position) {
+            Some(b) => {
+                self.position += 1;
+                Ok(*b)
+            }
+            None => return_err!(Parse, "unexpected end of MIDI file"),
+        }
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], ProgramError> {
+        match self.bytes.get(self.position..self.position + length) {
+            Some(b) => {
+                self.position += length;
+                Ok(b)
+            }
+            None => return_err!(Parse, "unexpected end of MIDI file"),
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<([u8; 4], &'a [u8]), ProgramError> {
+        let chunk_type = self.read_bytes(4)?;
+        let length = self.read_bytes(4)?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]);
+
+        let data = self.read_bytes(length as usize)?;
+
+        Ok(([chunk_type[0], chunk_type[1], chunk_type[2], chunk_type[3]], data))
+    }
+
+    // Variable length quantities use 7 bits per byte, with the top bit set on every byte but the last
+    fn read_variable_length(&mut self) -> Result<u32, ProgramError> {
+        let mut value: u32 = 0;
+
+        for _ in 0..4 {
+            let byte = self.read_u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+
+            if byte & 0x80 == 0 {
+                return Ok(value)
+            }
+        }
+
+        return_err!(Parse, "invalid variable length value in MIDI file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn end_of_track() -> TrackEvent {
+        TrackEvent { delta: 0, kind: TrackEventKind::Meta(META_END_OF_TRACK, Vec::new()) }
+    }
+
+    // A file from its header fields and the raw data of each track
+    fn file_bytes(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&format.to_be_bytes());
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn format_0_with_running_status() {
+        let track = [
+            0x00, 0x90, 0x3C, 0x64,
+            // Running status, 480 ticks later
+            0x83, 0x60, 0x3E, 0x64,
+            0x00, 0x3C, 0x00,
+            0x00, 0xC1, 0x05,
+            0x00, 0x06,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+
+        let midi_file = parse_midi_file(&file_bytes(0, 480, &[&track])).unwrap();
+
+        assert_eq!(midi_file.format, 0);
+        assert_eq!(midi_file.division, Division::TicksPerQuarter(480));
+        assert_eq!(midi_file.tracks.len(), 1);
+
+        let messages: Vec<_> = midi_file.timed_events().into_iter().map(|event| (event.time_us, event.message)).collect();
+        assert_eq!(messages, vec![
+            (0, vec![0x90, 0x3C, 0x64]),
+            (500_000, vec![0x90, 0x3E, 0x64]),
+            (500_000, vec![0x90, 0x3C, 0x00]),
+            (500_000, vec![0xC1, 0x05]),
+            (500_000, vec![0xC1, 0x06]),
+        ]);
+    }
+
+    #[test]
+    fn running_status_needs_a_status_first() {
+        let track = [0x00, 0x3C, 0x64, 0x00, 0xFF, 0x2F, 0x00];
+        assert!(parse_midi_file(&file_bytes(0, 480, &[&track])).is_err());
+    }
+
+    #[test]
+    fn format_1_tracks_are_merged_by_time() {
+        let tempo_track = [0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x00, 0xFF, 0x2F, 0x00];
+        let first = [0x00, 0x90, 0x3C, 0x64, 0x60, 0x80, 0x3C, 0x40, 0x00, 0xFF, 0x2F, 0x00];
+        let second = [0x30, 0x91, 0x40, 0x64, 0x00, 0xFF, 0x2F, 0x00];
+
+        let midi_file = parse_midi_file(&file_bytes(1, 96, &[&tempo_track, &first, &second])).unwrap();
+
+        assert_eq!(midi_file.format, 1);
+        assert_eq!(midi_file.tracks.len(), 3);
+
+        let messages: Vec<_> = midi_file.timed_events().into_iter().map(|event| (event.time_us, event.message)).collect();
+        assert_eq!(messages, vec![
+            (0, vec![0x90, 0x3C, 0x64]),
+            (250_000, vec![0x91, 0x40, 0x64]),
+            (500_000, vec![0x80, 0x3C, 0x40]),
+        ]);
+    }
+
+    #[test]
+    fn tempo_changes_in_the_middle_of_the_file() {
+        // 120 BPM, then 240 BPM from the second beat
+        let tempo_track = [
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x83, 0x60, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let notes = [0x00, 0x90, 0x3C, 0x64, 0x87, 0x40, 0x80, 0x3C, 0x40, 0x00, 0xFF, 0x2F, 0x00];
+
+        let midi_file = parse_midi_file(&file_bytes(1, 480, &[&tempo_track, &notes])).unwrap();
+        let times: Vec<_> = midi_file.timed_events().into_iter().map(|event| event.time_us).collect();
+
+        // A beat at 120 BPM then a beat at 240 BPM
+        assert_eq!(times, vec![0, 750_000]);
+        assert_eq!(midi_file.duration_us(), 750_000);
+    }
+
+    #[test]
+    fn to_bytes_round_trips() {
+        for division in [Division::TicksPerQuarter(480), Division::Smpte { frames_per_second: 25, ticks_per_frame: 40 }] {
+            let midi_file = MidiFile {
+                format: 1,
+                division,
+                tracks: vec![
+                    vec![
+                        TrackEvent { delta: 0, kind: TrackEventKind::Meta(META_TRACK_NAME, b"Tempo".to_vec()) },
+                        TrackEvent { delta: 0, kind: TrackEventKind::Meta(META_TEMPO, tempo_meta_data(128.0)) },
+                        end_of_track(),
+                    ],
+                    vec![
+                        TrackEvent { delta: 0, kind: TrackEventKind::Midi(vec![0x90, 0x3C, 0x64]) },
+                        TrackEvent { delta: 0x0FFF_FFFF, kind: TrackEventKind::Midi(vec![0x80, 0x3C, 0x00]) },
+                        TrackEvent { delta: 127, kind: TrackEventKind::Midi(vec![0xC0, 0x07]) },
+                        TrackEvent { delta: 128, kind: TrackEventKind::SysEx(vec![0xF0, 0x2B, 0x01, 0x01, 0x23, 0x00, 0x00, 0x09, 0xF7]) },
+                        TrackEvent { delta: 0, kind: TrackEventKind::Escape(vec![0xF8]) },
+                        end_of_track(),
+                    ],
+                ],
+            };
+
+            assert_eq!(parse_midi_file(&midi_file.to_bytes()).unwrap(), midi_file);
+        }
+    }
+
+    #[test]
+    fn long_files_with_slow_tempos_dont_overflow() {
+        let mut track = vec![TrackEvent { delta: 0, kind: TrackEventKind::Meta(META_TEMPO, vec![0xFF, 0xFF, 0xFF]) }];
+        track.extend((0..5000).map(|_| TrackEvent { delta: 0x0FFF_FFFF, kind: TrackEventKind::Midi(vec![0xF8]) }));
+
+        let mut midi_file = MidiFile {
+            format: 0,
+            division: Division::TicksPerQuarter(96),
+            tracks: vec![track],
+        };
+
+        let ticks = 5000 * 0x0FFF_FFFF_u128;
+        assert_eq!(midi_file.duration_us() as u128, ticks * 0xFF_FFFF / 96);
+
+        // Too long to fit in microseconds at all
+        midi_file.division = Division::TicksPerQuarter(1);
+        assert_eq!(midi_file.duration_us(), u64::MAX);
+    }
+}
@@ -0,0 +1,295 @@
+// Counters and latency histograms for the path from a MIDI message arriving to the command leaving for the desk.
+// Kept by the runtime's event loop, sent to subscribers every few seconds and printed when the runtime stops.
+//
+// A message goes through three stages, each timed separately:
+// queue      from the MIDI callback to the event loop taking it off the queue
+// translate  parsing the message and working out the command
+// send       sending the command to every active desk (send_to)
+// total is the whole path, for messages that sent a command.
+
+use std::time::Duration;
+use color_print::cprintln;
+
+/// Upper bounds of the histogram buckets, in microseconds. Anything slower goes in one last bucket.
+const BUCKET_BOUNDS_US: [u64; 15] = [5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000];
+
+const BUCKET_COUNT: usize = BUCKET_BOUNDS_US.len() + 1;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencyHistogram {
+    buckets: [u64; BUCKET_COUNT],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        // Compared in nanoseconds, whole microseconds would put 5.9µs in the bucket up to 5µs
+        let nanos = latency.as_nanos();
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|bound| nanos <= *bound as u128 * 1_000)
+            .unwrap_or(BUCKET_COUNT - 1);
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count == 0 {
+            return None
+        }
+
+        // Duration can only be divided by a u32, which a long running runtime can count past.
+        // The mean is never more than the max, so it fits back in a u64 of nanoseconds.
+        Some(Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// The latency this fraction (0 to 1) of measurements were at or under.
+    /// Only as precise as the buckets, so it's the upper bound of the bucket it falls in (or the max if that's lower).
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None
+        }
+
+        let target = ((self.count as f64 * fraction).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+
+            if seen >= target {
+                return match BUCKET_BOUNDS_US.get(bucket) {
+                    Some(bound) => Some(Duration::from_micros(*bound).min(self.max)),
+                    None => Some(self.max),
+                }
+            }
+        }
+
+        Some(self.max)
+    }
+
+    /// Every bucket with its upper bound (None for the last one) and how many measurements are in it
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(bucket, count)| {
+            (BUCKET_BOUNDS_US.get(bucket).map(|bound| Duration::from_micros(*bound)), *count)
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RuntimeStats {
+    // How long the runtime has been running for
+    pub running_for: Duration,
+
+    pub messages_in: u64,
+
+    // Parsed, but nothing is set to happen for them
+    pub messages_ignored: u64,
+
+    // Couldn't be parsed, or arrived while the queue to the event loop was full
+    pub messages_dropped: u64,
+
+    pub commands_out: u64,
+
+    // One for every desk a command couldn't be sent to
+    pub send_errors: u64,
+
+    pub queue_latency: LatencyHistogram,
+    pub translate_latency: LatencyHistogram,
+    pub send_latency: LatencyHistogram,
+    pub total_latency: LatencyHistogram,
+}
+
+impl RuntimeStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages_per_second(&self) -> f64 {
+        per_second(self.messages_in, self.running_for)
+    }
+
+    pub fn commands_per_second(&self) -> f64 {
+        per_second(self.commands_out, self.running_for)
+    }
+}
+
+fn per_second(count: u64, running_for: Duration) -> f64 {
+    if running_for.is_zero() {
+        return 0.0
+    }
+
+    count as f64 / running_for.as_secs_f64()
+}
+
+/// Prints the stats as a table, for when the runtime stops
+pub fn print_stats(stats: &RuntimeStats) {
+    cprintln!("\n<bold>Stats</> (ran for {})", format_running_time(stats.running_for));
+    println!(
+        "MIDI in       {} ({:.1}/s), {} ignored, {} dropped",
+        stats.messages_in,
+        stats.messages_per_second(),
+        stats.messages_ignored,
+        stats.messages_dropped
+    );
+    println!("Commands out  {} ({:.1}/s), {} send errors", stats.commands_out, stats.commands_per_second(), stats.send_errors);
+
+    println!("\n{:<12} {:>8} {:>10} {:>10} {:>10} {:>10}", "Latency", "count", "mean", "p50", "p99", "max");
+
+    let histograms = [
+        ("queue", &stats.queue_latency),
+        ("translate", &stats.translate_latency),
+        ("send", &stats.send_latency),
+        ("total", &stats.total_latency),
+    ];
+
+    for (name, histogram) in histograms {
+        println!(
+            "{:<12} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            histogram.count(),
+            format_latency(histogram.mean()),
+            format_latency(histogram.percentile(0.5)),
+            format_latency(histogram.percentile(0.99)),
+            format_latency(histogram.max()),
+        );
+    }
+}
+
+/// e.g. "0.42 ms", or "-" if nothing has been measured
+pub fn format_latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.2} ms", latency.as_secs_f64() * 1000.0),
+        None => String::from("-"),
+    }
+}
+
+fn format_running_time(running_for: Duration) -> String {
+    let seconds = running_for.as_secs();
+
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn micros(micros: u64) -> Duration {
+        Duration::from_micros(micros)
+    }
+
+    fn bucket_counts(histogram: &LatencyHistogram) -> Vec<u64> {
+        histogram.buckets().map(|(_, count)| count).collect()
+    }
+
+    #[test]
+    fn bucket_bounds_are_inclusive() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(Duration::ZERO);
+        histogram.record(micros(5));
+        histogram.record(micros(5) + Duration::from_nanos(1));
+        histogram.record(micros(10));
+        histogram.record(micros(250_000));
+        histogram.record(micros(250_000) + Duration::from_nanos(1));
+        histogram.record(Duration::from_secs(10));
+
+        let mut expected = vec![0; BUCKET_COUNT];
+        expected[0] = 2;
+        expected[1] = 2;
+        expected[BUCKET_COUNT - 2] = 1;
+        expected[BUCKET_COUNT - 1] = 2;
+
+        assert_eq!(bucket_counts(&histogram), expected);
+        assert_eq!(histogram.count(), 7);
+
+        let bounds: Vec<Option<Duration>> = histogram.buckets().map(|(bound, _)| bound).collect();
+        assert_eq!(bounds[0], Some(micros(5)));
+        assert_eq!(bounds[BUCKET_COUNT - 2], Some(micros(250_000)));
+        assert_eq!(bounds[BUCKET_COUNT - 1], None);
+    }
+
+    #[test]
+    fn nothing_recorded_has_no_latency() {
+        let histogram = LatencyHistogram::new();
+
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.max(), None);
+        assert_eq!(histogram.percentile(0.5), None);
+    }
+
+    #[test]
+    fn percentiles_are_bucket_bounds_clamped_to_the_max() {
+        let mut histogram = LatencyHistogram::new();
+        for _ in 0..98 {
+            histogram.record(micros(3));
+        }
+        histogram.record(micros(30));
+        histogram.record(micros(400));
+
+        // 3µs goes in the bucket up to 5µs, but nothing was slower than the max
+        assert_eq!(histogram.percentile(0.5), Some(micros(5)));
+        assert_eq!(histogram.percentile(0.98), Some(micros(5)));
+        assert_eq!(histogram.percentile(0.99), Some(micros(50)));
+        assert_eq!(histogram.percentile(1.0), Some(micros(400)));
+
+        // Fractions outside 0 to 1 are the fastest and slowest buckets
+        assert_eq!(histogram.percentile(0.0), Some(micros(5)));
+        assert_eq!(histogram.percentile(2.0), Some(micros(400)));
+
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(micros(3));
+        assert_eq!(histogram.percentile(0.5), Some(micros(3)));
+
+        // Past the last bound, the max is all there is
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(Duration::from_secs(2));
+        histogram.record(Duration::from_secs(3));
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn mean_and_max() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(micros(10));
+        histogram.record(micros(20));
+        histogram.record(micros(60));
+
+        assert_eq!(histogram.mean(), Some(micros(30)));
+        assert_eq!(histogram.max(), Some(micros(60)));
+    }
+
+    #[test]
+    fn mean_works_past_u32_max_measurements() {
+        let count = u32::MAX as u64 + 5;
+        let histogram = LatencyHistogram {
+            count,
+            total: Duration::from_nanos(2_000 * count),
+            max: micros(3),
+            ..LatencyHistogram::default()
+        };
+
+        assert_eq!(histogram.mean(), Some(micros(2)));
+    }
+}
@@ -0,0 +1,238 @@
+// Watches the MIDI input port while the runtime is running.
+// If the device disappears (e.g. a USB controller gets bumped loose mid-show) the connection is closed,
+// and as soon as a device with the same name shows up again it is reconnected.
+// The handler for incoming messages is shared between connections,
+// so everything it holds (MIDI through, recorder) carries on where it left off.
+// Only the parts of it that need to be mutable (MIDI through) are behind a lock,
+// feeding the runtime and the recorder never wait on one.
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use log::{debug, info, warn};
+use midir::{MidiInput, MidiInputConnection};
+use crate::midi_io::{client_name, get_midi_input, INPUT_PORT_NAME};
+use crate::RuntimeEvent;
+
+/// How often the port list is checked
+const SCAN_INTERVAL: Duration = Duration::from_millis(1000);
+
+type MessageFeed = Arc<dyn Fn(u64, &[u8]) + Send + Sync>;
+type ExclusiveHandler = Arc<Mutex<dyn FnMut(u64, &[u8]) + Send>>;
+
+/// What every connection to the input port calls with each message
+#[derive(Clone)]
+pub struct MidiHandler {
+    feed: MessageFeed,
+    exclusive: Option<ExclusiveHandler>,
+}
+
+impl MidiHandler {
+    /// Calls feed with every message, from whichever thread the connection delivers on
+    pub fn new<F>(feed: F) -> Self
+    where
+        F: Fn(u64, &[u8]) + Send + Sync + 'static,
+    {
+        MidiHandler {
+            feed: Arc::new(feed),
+            exclusive: None,
+        }
+    }
+
+    /// Also calls handler with every message after feed, for things that need to be mutable.
+    /// It's behind a lock, so only what has to be should go in it.
+    pub fn with_exclusive<H>(mut self, handler: H) -> Self
+    where
+        H: FnMut(u64, &[u8]) + Send + 'static,
+    {
+        self.exclusive = Some(Arc::new(Mutex::new(handler)));
+        self
+    }
+
+    fn handle(&self, stamp: u64, message: &[u8]) {
+        (self.feed)(stamp, message);
+
+        if let Some(exclusive) = &self.exclusive
+            && let Ok(mut handler) = exclusive.lock()
+        {
+            handler(stamp, message);
+        }
+    }
+}
+
+pub struct PortSupervisor {
+    stop_tx: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PortSupervisor {
+    /// Takes over an open connection to the named port and keeps it connected.
+    /// Status changes are passed to on_status.
+    pub fn start<S>(
+        port_name: String,
+        connection: MidiInputConnection<()>,
+        handler: MidiHandler,
+        on_status: S,
+    ) -> PortSupervisor
+    where
+        S: Fn(RuntimeEvent) + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+        let thread = std::thread::spawn(move || {
+            supervise(port_name, connection, handler, on_status, stop_rx);
+        });
+
+        PortSupervisor {
+            stop_tx,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for PortSupervisor {
+    fn drop(&mut self) {
+        let _ = self.stop_tx.send(());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Wraps the shared handler in a callback for a single connection
+pub fn handler_callback(handler: &MidiHandler) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    let handler = handler.clone();
+
+    move |stamp, message, _| handler.handle(stamp, message)
+}
+
+fn supervise<S: Fn(RuntimeEvent)>(
+    port_name: String,
+    connection: MidiInputConnection<()>,
+    handler: MidiHandler,
+    on_status: S,
+    stop_rx: mpsc::Receiver<()>,
+) {
+    // A separate client just for listing ports
+    let scanner = match MidiInput::new(&format!("{} scanner", client_name())) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Can't watch '{}' for disconnects: {}", port_name, e);
+
+            // Keep the connection open until the runtime stops
+            let _ = stop_rx.recv();
+            connection.close();
+            return
+        }
+    };
+
+    let mut connection = Some(connection);
+
+    // Scans until the runtime stops (or drops the supervisor)
+    while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(SCAN_INTERVAL) {
+        let present = scanner
+            .ports()
+            .iter()
+            .any(|port| scanner.port_name(port).is_ok_and(|name| same_device(&name, &port_name)));
+
+        match (connection.is_some(), present) {
+            (true, false) => {
+                warn!("MIDI input '{}' disappeared, waiting for it to come back", port_name);
+
+                if let Some(connection) = connection.take() {
+                    connection.close();
+                }
+
+                on_status(RuntimeEvent::MidiInputDisconnected(port_name.clone()));
+            }
+
+            (false, true) => match reconnect(&port_name, &handler) {
+                Some(new_connection) => {
+                    info!("MIDI input '{}' reconnected", port_name);
+                    connection = Some(new_connection);
+                    on_status(RuntimeEvent::MidiInputReconnected(port_name.clone()));
+                }
+                None => debug!("MIDI input '{}' is back but couldn't be connected yet", port_name),
+            },
+
+            _ => (),
+        }
+    }
+
+    if let Some(connection) = connection {
+        connection.close();
+    }
+}
+
+fn reconnect(port_name: &str, handler: &MidiHandler) -> Option<MidiInputConnection<()>> {
+    let midi_in = get_midi_input().ok()?;
+
+    let port = midi_in
+        .ports()
+        .into_iter()
+        .find(|port| midi_in.port_name(port).is_ok_and(|name| same_device(&name, port_name)))?;
+
+    midi_in.connect(&port, INPUT_PORT_NAME, handler_callback(handler), ()).ok()
+}
+
+// ALSA port names end in the client and port numbers (e.g. "Launchkey:Launchkey MIDI 1 24:0"),
+// which can change when the device is plugged back in, so they are ignored when comparing names.
+// JACK port names (e.g. "system:midi_capture_1") don't have them, so they're compared as they are.
+fn same_device(a: &str, b: &str) -> bool {
+    strip_port_address(a) == strip_port_address(b)
+}
+
+fn strip_port_address(name: &str) -> &str {
+    match name.rsplit_once(' ') {
+        Some((device, address))
+            if address.split_once(':').is_some_and(|(client, port)| {
+                !client.is_empty()
+                    && !port.is_empty()
+                    && client.chars().all(|c| c.is_ascii_digit())
+                    && port.chars().all(|c| c.is_ascii_digit())
+            }) => device,
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alsa_client_and_port_numbers_are_stripped() {
+        assert_eq!(strip_port_address("Launchkey:Launchkey MIDI 1 24:0"), "Launchkey:Launchkey MIDI 1");
+        assert_eq!(strip_port_address("Midi Through:Midi Through Port-0 14:0"), "Midi Through:Midi Through Port-0");
+        assert_eq!(strip_port_address("nanoKONTROL2 128:15"), "nanoKONTROL2");
+    }
+
+    #[test]
+    fn names_with_colons_but_no_address_are_kept() {
+        // JACK and CoreMIDI names, and ALSA names whose last word only looks like an address
+        assert_eq!(strip_port_address("system:midi_capture_1"), "system:midi_capture_1");
+        assert_eq!(strip_port_address("a2j:Launchkey [24] (capture): Launchkey MIDI 1"), "a2j:Launchkey [24] (capture): Launchkey MIDI 1");
+        assert_eq!(strip_port_address("Synth Out 1:A"), "Synth Out 1:A");
+        assert_eq!(strip_port_address("Synth Out :0"), "Synth Out :0");
+        assert_eq!(strip_port_address("Synth Out 24:"), "Synth Out 24:");
+        assert_eq!(strip_port_address("24:0"), "24:0");
+        assert_eq!(strip_port_address(""), "");
+    }
+
+    #[test]
+    fn a_device_is_the_same_after_it_gets_new_numbers() {
+        assert!(same_device("Launchkey:Launchkey MIDI 1 24:0", "Launchkey:Launchkey MIDI 1 28:0"));
+        assert!(same_device("Launchkey:Launchkey MIDI 1 24:0", "Launchkey:Launchkey MIDI 1"));
+        assert!(same_device("system:midi_capture_1", "system:midi_capture_1"));
+
+        assert!(!same_device("Launchkey:Launchkey MIDI 1 24:0", "Launchkey:Launchkey MIDI 2 24:1"));
+        assert!(!same_device("system:midi_capture_1", "system:midi_capture_2"));
+        assert!(!same_device("Launchkey:Launchkey MIDI 1 24:0", "launchkey:launchkey midi 1 24:0"));
+    }
+
+    #[test]
+    fn identical_devices_only_differ_by_their_numbers() {
+        // Two of the same controller can't be told apart by name, so whichever is found first is reconnected
+        assert!(same_device("Launchkey:Launchkey MIDI 1 24:0", "Launchkey:Launchkey MIDI 1 32:0"));
+    }
+}
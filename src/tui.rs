@@ -0,0 +1,441 @@
+// Terminal dashboard for running a show.
+// Shows everything about the running Chamsys runtime at a glance instead of a scrolling log:
+// the MIDI input, whether each desk can be reached, the playbacks and what was last sent.
+// Log records still go to the session log file, but not to the terminal while the dashboard is drawn.
+
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::sync::mpsc;
+use std::time::Duration;
+use chrono::Local;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use crate::desk_state::DeskState;
+use crate::stats::{format_latency, print_stats, RuntimeStats};
+use crate::errors::ProgramError;
+use crate::{return_err, BankSwitching, DeskRole, DeskTarget, MappingBank, MidiRuntime, OutputMode, RuntimeEvent, Setlist, Song, DEFAULT_BANK_NAME};
+
+/// How often the screen is redrawn when nothing is pressed
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many events and errors are kept for the event list
+const EVENT_HISTORY: usize = 50;
+
+/// Playbacks shown in each row of the playback grid
+const PLAYBACKS_PER_ROW: u8 = 10;
+
+/// The smallest number of playbacks shown, more rows are added as higher playbacks are used
+const MIN_PLAYBACKS_SHOWN: u8 = 20;
+
+/// What can change when the show file is reloaded while the dashboard is running
+pub struct ShowReload {
+    pub desk_targets: Vec<DeskTarget>,
+    pub banks: Vec<MappingBank>,
+    pub bank_switching: BankSwitching,
+    pub setlist: Setlist,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Reachability {
+    Checking,
+
+    // Nothing has failed, but the desk hasn't answered either
+    Unknown,
+    Reachable,
+    Unreachable,
+}
+
+struct DeskStatus {
+    target: DeskTarget,
+    reachability: Reachability,
+    active: bool,
+}
+
+struct Dashboard {
+    input_port: String,
+    input_connected: bool,
+    output_mode: OutputMode,
+    desks: Vec<DeskStatus>,
+    desk_state: DeskState,
+
+    // Name of the active mapping bank
+    bank: String,
+
+    // The song playing and its position in the setlist, None before the first song
+    song: Option<(usize, Song)>,
+    song_count: usize,
+
+    // The last stats the runtime sent
+    stats: Option<RuntimeStats>,
+
+    // Newest last, with the time they happened
+    events: VecDeque<(String, EventLevel)>,
+}
+
+#[derive(Clone, Copy)]
+enum EventLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+/// Draws the dashboard until the user stops it.
+/// q or Esc stops, b releases every active playback, n and p step through the setlist and r reloads the show file.
+pub fn run_dashboard<R>(
+    runtime: MidiRuntime,
+    input_port: String,
+    desk_targets: Vec<DeskTarget>,
+    bank: String,
+    song_count: usize,
+    output_mode: OutputMode,
+    mut reload: R,
+) -> Result<(), ProgramError>
+where
+    R: FnMut() -> Result<ShowReload, ProgramError>,
+{
+    let events = runtime.subscribe();
+
+    let mut dashboard = Dashboard {
+        input_port,
+        input_connected: true,
+        output_mode,
+        desks: Vec::new(),
+        desk_state: DeskState::new(),
+        bank,
+        song: None,
+        song_count,
+        stats: None,
+        events: VecDeque::new(),
+    };
+    dashboard.set_desk_targets(desk_targets);
+    dashboard.push_event(String::from("Running"), EventLevel::Info);
+
+    let mut terminal = ratatui::init();
+    let result = run_loop(&mut terminal, &mut dashboard, &runtime, &events, &mut reload);
+    ratatui::restore();
+
+    // The runtime may already have been stopped from the control API, so the last stats sent will have to do
+    if let Some(stats) = runtime.stats().or(dashboard.stats) {
+        print_stats(&stats);
+    }
+
+    runtime.stop();
+    result
+}
+
+fn run_loop<R>(
+    terminal: &mut DefaultTerminal,
+    dashboard: &mut Dashboard,
+    runtime: &MidiRuntime,
+    events: &mpsc::Receiver<RuntimeEvent>,
+    reload: &mut R,
+) -> Result<(), ProgramError>
+where
+    R: FnMut() -> Result<ShowReload, ProgramError>,
+{
+    loop {
+        while let Ok(event) = events.try_recv() {
+            dashboard.apply_event(event);
+        }
+
+        match runtime.desk_state() {
+            Some(desk_state) => dashboard.desk_state = desk_state,
+            // Stopped from somewhere else, e.g. the control API
+            None => return Ok(()),
+        }
+
+        if let Err(e) = terminal.draw(|frame| dashboard.draw(frame)) {
+            return_err!(Io, format!("failed to draw the dashboard: {e}"), e)
+        }
+
+        let key = match event::poll(REFRESH_INTERVAL) {
+            Ok(true) => match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => key.code,
+                Ok(_) => continue,
+                Err(e) => return_err!(Io, format!("failed to read from the terminal: {e}"), e),
+            },
+            Ok(false) => continue,
+            Err(e) => return_err!(Io, format!("failed to read from the terminal: {e}"), e),
+        };
+
+        match key {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+
+            KeyCode::Char('b') => {
+                runtime.blackout();
+                dashboard.push_event(String::from("Blackout, released every active playback"), EventLevel::Warning);
+            }
+
+            KeyCode::Char('n') => runtime.next_song(),
+            KeyCode::Char('p') => runtime.previous_song(),
+
+            KeyCode::Char('r') => match reload() {
+                Ok(show) => {
+                    runtime.set_desk_targets(show.desk_targets.clone());
+                    dashboard.bank = show.banks.first().map(|bank| bank.name.clone()).unwrap_or_else(|| String::from(DEFAULT_BANK_NAME));
+                    runtime.set_banks(show.banks, show.bank_switching);
+
+                    // The runtime stays on the same song if it's still in the setlist
+                    dashboard.set_setlist(&show.setlist);
+                    runtime.set_setlist(show.setlist);
+
+                    dashboard.set_desk_targets(show.desk_targets);
+                    dashboard.push_event(String::from("Reloaded the show file"), EventLevel::Info);
+                }
+                Err(e) => dashboard.push_event(format!("Failed to reload the show file: {e}"), EventLevel::Error),
+            },
+
+            _ => (),
+        }
+    }
+}
+
+impl Dashboard {
+    fn set_desk_targets(&mut self, desk_targets: Vec<DeskTarget>) {
+        // Reachability isn't known again until the runtime checks the new desks
+        self.desks = desk_targets
+            .into_iter()
+            .map(|target| DeskStatus {
+                active: target.enabled && target.role == DeskRole::Main,
+                target,
+                reachability: Reachability::Checking,
+            })
+            .collect();
+    }
+
+    fn set_setlist(&mut self, setlist: &Setlist) {
+        self.song_count = setlist.songs.len();
+
+        self.song = self.song.take().and_then(|(_, song)| {
+            let position = setlist.songs.iter().position(|new_song| new_song.name == song.name)?;
+            Some((position, setlist.songs[position].clone()))
+        });
+    }
+
+    fn push_event(&mut self, message: String, level: EventLevel) {
+        if self.events.len() == EVENT_HISTORY {
+            self.events.pop_front();
+        }
+
+        self.events.push_back((format!("{} {}", Local::now().format("%H:%M:%S"), message), level));
+    }
+
+    fn apply_event(&mut self, event: RuntimeEvent) {
+        match event {
+            RuntimeEvent::MidiInputDisconnected(port) => {
+                self.input_connected = false;
+                self.push_event(format!("MIDI input '{}' disconnected", port), EventLevel::Error);
+            }
+            RuntimeEvent::MidiInputReconnected(port) => {
+                self.input_connected = true;
+                self.push_event(format!("MIDI input '{}' reconnected", port), EventLevel::Info);
+            }
+            RuntimeEvent::DeskReachable(address) => {
+                self.set_reachability(address, Reachability::Reachable);
+                self.push_event(format!("Desk {} is reachable", address), EventLevel::Info);
+            }
+            RuntimeEvent::DeskReachabilityUnknown(address) => {
+                self.set_reachability(address, Reachability::Unknown);
+                self.push_event(format!("Desk {} isn't answering, it may not be there", address), EventLevel::Warning);
+            }
+            RuntimeEvent::DeskUnreachable(address, reason) => {
+                self.set_reachability(address, Reachability::Unreachable);
+                self.push_event(format!("Desk {} is unreachable: {}", address, reason), EventLevel::Error);
+            }
+            RuntimeEvent::ActiveDesksChanged(active_desks) => {
+                for desk in &mut self.desks {
+                    desk.active = active_desks.contains(&desk.target.address);
+                }
+
+                let names: Vec<String> = active_desks.iter().map(Ipv4Addr::to_string).collect();
+                self.push_event(format!("Sending to: {}", names.join(", ")), EventLevel::Warning);
+            }
+            RuntimeEvent::BankChanged(name) => {
+                self.push_event(format!("Switched to bank '{}'", name), EventLevel::Info);
+                self.bank = name;
+            }
+            RuntimeEvent::SongChanged(position, song) => {
+                self.push_event(format!("Started song {}: '{}'", position + 1, song.name), EventLevel::Info);
+                self.song = Some((position, song));
+            }
+            // Shown from the desk state instead, which also has commands sent before the dashboard started
+            RuntimeEvent::CommandSent(..) => (),
+            RuntimeEvent::CommandFailed(address, reason) => {
+                self.push_event(format!("Failed to send to {}: {}", address, reason), EventLevel::Error);
+            }
+            RuntimeEvent::Stats(stats) => self.stats = Some(*stats),
+        }
+    }
+
+    fn set_reachability(&mut self, address: Ipv4Addr, reachability: Reachability) {
+        for desk in self.desks.iter_mut().filter(|desk| desk.target.address == address) {
+            desk.reachability = reachability;
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        // Input and bank lines, a song line if there's a setlist, every desk and the border
+        let status_lines = 2 + usize::from(self.song_count > 0) + self.desks.len();
+
+        let [status_area, playback_area, bottom_area, help_area] = Layout::vertical([
+            Constraint::Length(status_lines as u16 + 2),
+            Constraint::Length(self.playback_rows() as u16 + 2),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let [commands_area, events_area] = Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)]).areas(bottom_area);
+
+        self.draw_status(frame, status_area);
+        self.draw_playbacks(frame, playback_area);
+        self.draw_commands(frame, commands_area);
+        self.draw_events(frame, events_area);
+
+        frame.render_widget(
+            Paragraph::new(Line::from(" q stop   b blackout   n/p next/previous song   r reload show file").style(Style::new().add_modifier(Modifier::DIM))),
+            help_area,
+        );
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = Vec::new();
+
+        let input_status = if self.input_connected {
+            Span::styled("connected", Style::new().fg(Color::Green))
+        } else {
+            Span::styled("disconnected", Style::new().fg(Color::Red).add_modifier(Modifier::BOLD))
+        };
+        lines.push(Line::from(vec![Span::raw(format!("MIDI in  {:<30} ", self.input_port)), input_status]));
+        lines.push(Line::from(vec![Span::raw("Bank     "), Span::styled(self.bank.as_str(), Style::new().add_modifier(Modifier::BOLD))]));
+
+        if self.song_count > 0 {
+            let song = match &self.song {
+                Some((position, song)) => {
+                    let tempo = song.tempo.map(|tempo| format!("  {} BPM", tempo)).unwrap_or_default();
+                    Span::styled(format!("{}/{} {}{}", position + 1, self.song_count, song.name, tempo), Style::new().add_modifier(Modifier::BOLD))
+                }
+                None => Span::styled(format!("not started ({} songs)", self.song_count), Style::new().add_modifier(Modifier::DIM)),
+            };
+
+            lines.push(Line::from(vec![Span::raw("Song     "), song]));
+        }
+
+        for desk in &self.desks {
+            let role = match desk.target.role {
+                DeskRole::Main => "main",
+                DeskRole::Backup => "backup",
+            };
+
+            let reachability = match (self.output_mode, desk.reachability) {
+                (OutputMode::Simulation, _) => Span::styled("simulated", Style::new().fg(Color::Yellow)),
+                (_, Reachability::Checking) => Span::styled("checking", Style::new().add_modifier(Modifier::DIM)),
+                (_, Reachability::Unknown) => Span::styled("unknown", Style::new().fg(Color::Yellow)),
+                (_, Reachability::Reachable) => Span::styled("reachable", Style::new().fg(Color::Green)),
+                (_, Reachability::Unreachable) => Span::styled("unreachable", Style::new().fg(Color::Red).add_modifier(Modifier::BOLD)),
+            };
+
+            let sending = match (desk.target.enabled, desk.active) {
+                (false, _) => "disabled",
+                (true, true) => "sending",
+                (true, false) => "standby",
+            };
+
+            lines.push(Line::from(vec![
+                Span::raw(format!("Desk     {:<15} {:<7} {:<9} ", desk.target.address, role, sending)),
+                reachability,
+            ]));
+        }
+
+        let title = match self.output_mode {
+            OutputMode::Network => " midi_lx ",
+            OutputMode::Simulation => " midi_lx (SIMULATION) ",
+        };
+
+        frame.render_widget(Paragraph::new(lines).block(Block::new().borders(Borders::ALL).title(title)), area);
+    }
+
+    // Enough rows for the highest playback used so far
+    fn playback_rows(&self) -> u8 {
+        let highest = self.desk_state.playbacks().map(|(playback, _)| playback).max().unwrap_or(0);
+        highest.max(MIN_PLAYBACKS_SHOWN).div_ceil(PLAYBACKS_PER_ROW)
+    }
+
+    fn draw_playbacks(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = Vec::new();
+
+        for row in 0..self.playback_rows() {
+            let mut spans = Vec::new();
+
+            for column in 1..=PLAYBACKS_PER_ROW {
+                // The last row runs past PB255, the highest playback there is
+                let Ok(playback) = u8::try_from(u16::from(row) * u16::from(PLAYBACKS_PER_ROW) + u16::from(column)) else {
+                    break
+                };
+                let state = self.desk_state.playback(playback).copied().unwrap_or_default();
+
+                let level = match state.level {
+                    Some(level) => format!("{:>3}", level),
+                    None => String::from("  -"),
+                };
+
+                let style = if state.active {
+                    Style::new().fg(Color::Black).bg(Color::Green)
+                } else {
+                    Style::new().add_modifier(Modifier::DIM)
+                };
+
+                spans.push(Span::styled(format!(" PB{:<3} {} ", playback, level), style));
+                spans.push(Span::raw(" "));
+            }
+
+            lines.push(Line::from(spans));
+        }
+
+        let title = format!(" Playbacks ({}) ", self.desk_state.summary());
+        frame.render_widget(Paragraph::new(lines).block(Block::new().borders(Borders::ALL).title(title)), area);
+    }
+
+    fn draw_commands(&self, frame: &mut Frame, area: Rect) {
+        // Newest at the top, as many as fit
+        let shown = area.height.saturating_sub(2) as usize;
+        let commands: Vec<_> = self.desk_state.recent_commands().collect();
+        let lines: Vec<Line> = commands.iter().rev().take(shown).map(|command| Line::from(format!("{:<10} {}", command, command.description()))).collect();
+
+        let title = match &self.stats {
+            Some(stats) => format!(
+                " Commands ({} sent, latency p99 {}) ",
+                self.desk_state.total_commands(),
+                format_latency(stats.total_latency.percentile(0.99))
+            ),
+            None => format!(" Commands ({} sent) ", self.desk_state.total_commands()),
+        };
+        frame.render_widget(Paragraph::new(lines).block(Block::new().borders(Borders::ALL).title(title)), area);
+    }
+
+    fn draw_events(&self, frame: &mut Frame, area: Rect) {
+        let shown = area.height.saturating_sub(2) as usize;
+
+        let lines: Vec<Line> = self
+            .events
+            .iter()
+            .rev()
+            .take(shown)
+            .map(|(message, level)| {
+                let style = match level {
+                    EventLevel::Info => Style::new(),
+                    EventLevel::Warning => Style::new().fg(Color::Yellow),
+                    EventLevel::Error => Style::new().fg(Color::Red),
+                };
+
+                Line::styled(message.as_str(), style)
+            })
+            .collect();
+
+        frame.render_widget(Paragraph::new(lines).block(Block::new().borders(Borders::ALL).title(" Events and errors ")), area);
+    }
+}
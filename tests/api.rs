@@ -0,0 +1,146 @@
+// Serves the control API on a free local port in front of a simulated runtime,
+// and talks to it over plain HTTP and a WebSocket like a front end would.
+// Run with: cargo test --no-default-features --features api
+
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::time::Duration;
+use serde_json::Value;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::WebSocket;
+use midilx::api::ControlApi;
+use midilx::magicq::MagicqCommand;
+use midilx::midi_utils::MiddleC;
+use midilx::{MidiRuntime, OutputMode, RuntimeEvent, DEFAULT_FIRST_PLAYBACK_NOTE};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct TestApi {
+    runtime: MidiRuntime,
+    api: ControlApi,
+    stops: mpsc::Receiver<()>,
+}
+
+fn start_api() -> TestApi {
+    let runtime = MidiRuntime::create_without_input(Vec::new(), Ipv4Addr::UNSPECIFIED, OutputMode::Simulation).expect("failed to start the runtime");
+    let (stop_tx, stops) = mpsc::channel();
+
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let api = ControlApi::start(address, runtime.handle(), MiddleC::C4, move || {
+        let _ = stop_tx.send(());
+    })
+    .expect("failed to start the API");
+
+    TestApi { runtime, api, stops }
+}
+
+// Sends a request and returns the status and body, the connection is closed after each one
+fn request(api: &ControlApi, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(api.address()).expect("failed to connect to the API");
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).expect("failed to read the response");
+
+    let status = response.split(' ').nth(1).and_then(|status| status.parse().ok()).expect("no status in the response");
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_owned()).unwrap_or_default();
+
+    (status, body)
+}
+
+// The subscription is made while the upgrade is handled,
+// so once a later request is answered the runtime is sending this socket its events
+fn open_events(api: &ControlApi) -> WebSocket<MaybeTlsStream<TcpStream>> {
+    let (socket, _) = tungstenite::connect(format!("ws://{}/api/events", api.address())).expect("failed to open the event stream");
+
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    }
+
+    assert_eq!(request(api, "GET", "/api/state", "").0, 200);
+    socket
+}
+
+// Reads events until one matches, skipping anything else (e.g. stats)
+fn next_event<F: Fn(&RuntimeEvent) -> bool>(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, wanted: F) -> RuntimeEvent {
+    loop {
+        let message = socket.read().expect("no event before the timeout");
+        let text = message.to_text().expect("events are sent as text");
+        let event: RuntimeEvent = serde_json::from_str(text).expect("event isn't a RuntimeEvent");
+
+        if wanted(&event) {
+            return event
+        }
+    }
+}
+
+#[test]
+fn mappings_change_the_command_sent() {
+    let test = start_api();
+    let mut events = open_events(&test.api);
+
+    let body = format!("{{\"{}\": \"intensity\"}}", DEFAULT_FIRST_PLAYBACK_NOTE);
+    assert_eq!(request(&test.api, "PUT", "/api/mappings", &body).0, 204);
+
+    // Answered after the mappings were replaced
+    assert_eq!(request(&test.api, "GET", "/api/state", "").0, 200);
+
+    test.runtime.send_midi(&[0x90, DEFAULT_FIRST_PLAYBACK_NOTE, 64]);
+
+    let event = next_event(&mut events, |event| matches!(event, RuntimeEvent::CommandSent(_)));
+    assert_eq!(event, RuntimeEvent::CommandSent(MagicqCommand::Level(1, 64)));
+
+    let (status, state) = request(&test.api, "GET", "/api/state", "");
+    let state: Value = serde_json::from_str(&state).unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(state["playbacks"][0]["playback"], 1);
+    assert_eq!(state["playbacks"][0]["level"], 64);
+}
+
+#[test]
+fn desk_ip_changes_where_commands_go() {
+    let test = start_api();
+    let mut events = open_events(&test.api);
+
+    assert_eq!(request(&test.api, "PUT", "/api/desk-ip", r#"{"ip": "127.0.0.1"}"#).0, 204);
+
+    let event = next_event(&mut events, |event| matches!(event, RuntimeEvent::ActiveDesksChanged(_)));
+    assert_eq!(event, RuntimeEvent::ActiveDesksChanged(vec![Ipv4Addr::LOCALHOST]));
+}
+
+#[test]
+fn bad_bodies_are_refused() {
+    let test = start_api();
+
+    for (method, path, body) in [
+        ("PUT", "/api/mappings", "not json"),
+        ("PUT", "/api/mappings", r#"{"C4": "explode"}"#),
+        ("PUT", "/api/mappings", r#"["C4"]"#),
+        ("PUT", "/api/desk-ip", r#"{"ip": "2.0.0"}"#),
+        ("POST", "/api/bank", "{}"),
+    ] {
+        let (status, body) = request(&test.api, method, path, body);
+        let body: Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(status, 400, "{method} {path}");
+        assert!(body["error"].is_string(), "{method} {path}");
+    }
+
+    assert_eq!(request(&test.api, "GET", "/api/nothing", "").0, 404);
+}
+
+#[test]
+fn stop_calls_back() {
+    let test = start_api();
+
+    assert_eq!(request(&test.api, "POST", "/api/stop", "").0, 204);
+    assert_eq!(test.stops.recv_timeout(TIMEOUT), Ok(()));
+}
@@ -0,0 +1,66 @@
+// Exports recordings as MIDI files and reads them back, checking every port ends up on its own track.
+// Run with: cargo test --no-default-features --features organ
+
+use std::path::PathBuf;
+use midilx::organ::organ_midi::{organ_stop_to_sysex, ORGAN_OUTPUT_RECORDING_PORT};
+use midilx::organ::stops_table::OrganStop;
+use midilx::recorder::export_recording;
+use midilx::smf::{parse_midi_file, TrackEvent, TrackEventKind, META_TRACK_NAME};
+
+// A path in the temp directory that's only used by this test
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("midilx-{}-{}", std::process::id(), name))
+}
+
+fn format_bytes(message: &[u8]) -> String {
+    message.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(" ")
+}
+
+fn track_name(track: &[TrackEvent]) -> Option<&[u8]> {
+    track.iter().find_map(|event| match &event.kind {
+        TrackEventKind::Meta(META_TRACK_NAME, name) => Some(name.as_slice()),
+        _ => None,
+    })
+}
+
+#[test]
+fn organ_stop_sysex_is_exported_on_the_organ_track() {
+    let sysex = organ_stop_to_sysex(OrganStop::SoloTuba8, true);
+
+    let recording = format!(
+        "# midilx recording\n# started 2026-10-19T20:00:00+01:00\n1000000\tLaunchkey MIDI\t90 30 7F\n1000250\t{}\t{}\n1500000\tLaunchkey MIDI\t80 30 00\n",
+        ORGAN_OUTPUT_RECORDING_PORT,
+        format_bytes(&sysex),
+    );
+
+    let recording_path = temp_path("organ.rec");
+    let midi_path = temp_path("organ.mid");
+    std::fs::write(&recording_path, recording).unwrap();
+
+    let exported = export_recording(&recording_path, &midi_path, 120.0);
+    let bytes = std::fs::read(&midi_path);
+    let _ = std::fs::remove_file(&recording_path);
+    let _ = std::fs::remove_file(&midi_path);
+
+    exported.unwrap();
+    let midi_file = parse_midi_file(&bytes.unwrap()).unwrap();
+
+    // The tempo track, then a track for each port in the order they first appear
+    assert_eq!(midi_file.format, 1);
+    assert_eq!(midi_file.tracks.len(), 3);
+    assert_eq!(track_name(&midi_file.tracks[1]), Some(b"Launchkey MIDI".as_slice()));
+    assert_eq!(track_name(&midi_file.tracks[2]), Some(ORGAN_OUTPUT_RECORDING_PORT.as_bytes()));
+
+    let organ_sysex: Vec<_> = midi_file.tracks[2]
+        .iter()
+        .filter_map(|event| match &event.kind {
+            TrackEventKind::SysEx(message) => Some(message.clone()),
+            _ => None,
+        })
+        .collect();
+
+    assert_eq!(organ_sysex, vec![sysex]);
+
+    // Nothing from the organ ends up on the input's track
+    assert!(midi_file.tracks[1].iter().all(|event| !matches!(event.kind, TrackEventKind::SysEx(_))));
+}
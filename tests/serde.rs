@@ -0,0 +1,189 @@
+// Round trips the public types through JSON, and checks the types written as text keep their names.
+// Run with: cargo test --no-default-features --features serde,chamsys,organ
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use midilx::desk_state::DeskState;
+use midilx::magicq::MagicqCommand;
+use midilx::midi_utils::{MiddleC, MidiMessage};
+use midilx::organ::stops_table::{OrganStop, TOTAL_STOPS};
+use midilx::port_selector::PortSelector;
+use midilx::stats::RuntimeStats;
+use midilx::{BankSwitching, DeskTarget, LxCommand, MappingBank, OutputMode, RuntimeEvent, Setlist, Song};
+
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> T {
+    let json = serde_json::to_string(value).expect("failed to serialize");
+    serde_json::from_str(&json).expect("failed to deserialize")
+}
+
+fn all_stops() -> impl Iterator<Item = OrganStop> {
+    (0..TOTAL_STOPS).filter_map(OrganStop::from_u8)
+}
+
+#[test]
+fn lx_commands_use_the_show_file_names() {
+    for name in ["activate", "deactivate", "intensity"] {
+        let command = LxCommand::parse(name).unwrap();
+
+        assert_eq!(serde_json::to_string(&command).unwrap(), format!("\"{name}\""));
+        assert_eq!(round_trip(&command), command);
+    }
+}
+
+#[test]
+fn organ_stops_are_written_by_their_display_name() {
+    for stop in all_stops() {
+        assert_eq!(serde_json::to_string(&stop).unwrap(), format!("\"{stop}\""));
+        assert_eq!(round_trip(&stop), stop);
+    }
+
+    assert_eq!(serde_json::to_string(&OrganStop::SoloTuba8).unwrap(), "\"Solo Tuba 8\"");
+}
+
+#[test]
+fn unknown_organ_stop_is_an_error() {
+    assert!(serde_json::from_str::<OrganStop>("\"Solo Kazoo 8\"").is_err());
+    assert!(serde_json::from_str::<OrganStop>("\"SoloTuba8\"").is_err());
+}
+
+#[test]
+fn magicq_commands_are_written_as_sent() {
+    let commands = [
+        MagicqCommand::Activate(3),
+        MagicqCommand::Release(3),
+        MagicqCommand::Level(3, 100),
+        MagicqCommand::Go(1),
+        MagicqCommand::Back(1),
+        MagicqCommand::Stop(1),
+        MagicqCommand::Jump(255, 65535),
+    ];
+
+    for command in commands {
+        assert_eq!(serde_json::to_string(&command).unwrap(), format!("\"{}\"", command.encode().as_str()));
+        assert_eq!(round_trip(&command), command);
+    }
+
+    assert!(serde_json::from_str::<MagicqCommand>("\"3X\"").is_err());
+}
+
+#[test]
+fn mapping_bank_round_trips() {
+    let bank = MappingBank {
+        program: Some(4),
+        first_playback: 11,
+        mappings: HashMap::from([(60, LxCommand::Activate), (62, LxCommand::Intensity)]),
+        ..MappingBank::new("Song 1")
+    };
+
+    assert_eq!(round_trip(&bank), bank);
+
+    let switching = BankSwitching {
+        channel: Some(16),
+        next_note: Some(0),
+        previous_note: Some(1),
+        select_cc: Some(20),
+        release_on_switch: true,
+    };
+
+    assert_eq!(round_trip(&switching), switching);
+}
+
+#[test]
+fn setlist_round_trips() {
+    let setlist = Setlist {
+        songs: vec![
+            Song {
+                bank: Some(String::from("Song 1")),
+                tempo: Some(128.0),
+                commands: vec![MagicqCommand::Go(1), MagicqCommand::Level(2, 100)],
+                ..Song::new("Opener")
+            },
+            Song::new("Encore"),
+        ],
+        channel: Some(16),
+        next_note: Some(2),
+        previous_note: None,
+        next_cc: Some(64),
+    };
+
+    assert_eq!(round_trip(&setlist), setlist);
+}
+
+#[test]
+fn desk_targets_round_trip() {
+    let mut disabled = DeskTarget::new(Ipv4Addr::new(2, 255, 255, 255));
+    disabled.enabled = false;
+
+    let targets = vec![DeskTarget::new(Ipv4Addr::new(2, 0, 0, 35)), DeskTarget::backup(Ipv4Addr::new(2, 0, 0, 36)), disabled];
+
+    assert_eq!(round_trip(&targets), targets);
+    assert_eq!(round_trip(&OutputMode::Simulation), OutputMode::Simulation);
+    assert_eq!(round_trip(&MiddleC::C3), MiddleC::C3);
+}
+
+#[test]
+fn port_selectors_are_written_as_on_the_command_line() {
+    for text in ["2", "Launchkey MIDI 1", "/Launch.*MIDI/"] {
+        let selector = PortSelector::parse(text).unwrap();
+
+        assert_eq!(serde_json::to_string(&selector).unwrap(), format!("\"{text}\""));
+        assert_eq!(round_trip(&selector).to_string(), text);
+    }
+
+    assert!(serde_json::from_str::<PortSelector>("\"/(/\"").is_err());
+}
+
+#[test]
+fn midi_messages_round_trip() {
+    let messages = [
+        MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 },
+        MidiMessage::ControlChange { channel: 16, controller: 64, value: 127 },
+        MidiMessage::PitchBend { channel: 2, value: -8192 },
+        MidiMessage::SysEx(vec![0x2B, 0x01, 0x01, 0x23, 0x00, 0x00, 0x09]),
+        MidiMessage::Clock,
+    ];
+
+    for message in messages {
+        assert_eq!(round_trip(&message), message);
+    }
+}
+
+#[test]
+fn runtime_events_round_trip() {
+    let mut stats = RuntimeStats {
+        running_for: Duration::from_secs(90),
+        messages_in: 12,
+        commands_out: 10,
+        ..RuntimeStats::new()
+    };
+    stats.total_latency.record(Duration::from_micros(420));
+
+    let events = [
+        RuntimeEvent::MidiInputDisconnected(String::from("Launchkey MIDI 1")),
+        RuntimeEvent::DeskUnreachable(Ipv4Addr::new(2, 0, 0, 35), String::from("no reply")),
+        RuntimeEvent::ActiveDesksChanged(vec![Ipv4Addr::new(2, 0, 0, 36)]),
+        RuntimeEvent::SongChanged(0, Song::new("Opener")),
+        RuntimeEvent::CommandSent(MagicqCommand::Activate(1)),
+        RuntimeEvent::Stats(Box::new(stats)),
+    ];
+
+    for event in events {
+        assert_eq!(round_trip(&event), event);
+    }
+}
+
+#[test]
+fn desk_state_round_trips() {
+    let mut desk_state = DeskState::new();
+    desk_state.apply_command(MagicqCommand::Activate(1));
+    desk_state.apply_command(MagicqCommand::Level(2, 50));
+
+    let copy = round_trip(&desk_state);
+
+    assert_eq!(copy.total_commands(), 2);
+    assert_eq!(copy.playbacks().collect::<Vec<_>>(), desk_state.playbacks().collect::<Vec<_>>());
+    assert_eq!(copy.recent_commands().collect::<Vec<_>>(), desk_state.recent_commands().collect::<Vec<_>>());
+}